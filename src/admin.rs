@@ -0,0 +1,126 @@
+use crate::broker::Broker;
+use actix_web::{error, web, Error, HttpResponse};
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Структура для создания снимка брокера
+#[derive(Deserialize)]
+pub struct SnapshotRequest {
+    // Файл снимка относительно каталога MEM_BROKER_SNAPSHOT_DIR
+    path: PathBuf,
+}
+
+// Ответ на создание снимка
+#[derive(Serialize)]
+pub struct SnapshotResponse {
+    path: PathBuf,
+    topics: usize,
+    created_at: u64,
+}
+
+// Использование памяти брокера
+#[derive(Serialize)]
+pub struct MemoryResponse {
+    // Байт сообщений в памяти всех топиков
+    used: u64,
+    // Лимит, null - без ограничения
+    limit: Option<u64>,
+}
+
+// Функция для создания снимка всех топиков в файл
+pub async fn snapshot(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    req: web::Json<SnapshotRequest>,
+) -> Result<HttpResponse, Error> {
+    let broker = broker.lock().await;
+    let path = broker
+        .snapshot_path(&req.path)
+        .map_err(error::ErrorBadRequest)?;
+    let snapshot = broker
+        .snapshot()
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    snapshot
+        .save(&path)
+        .map_err(error::ErrorInternalServerError)?;
+    println!("Снимок брокера записан в {}", path.display());
+
+    Ok(HttpResponse::Ok().json(SnapshotResponse {
+        path,
+        topics: snapshot.topics.len(),
+        created_at: snapshot.created_at,
+    }))
+}
+
+// Функция для получения использования памяти
+pub async fn memory(broker: web::Data<Arc<Mutex<Broker>>>) -> HttpResponse {
+    let broker = broker.lock().await;
+    let budget = broker.memory_budget();
+    HttpResponse::Ok().json(MemoryResponse {
+        used: budget.used(),
+        limit: budget.limit(),
+    })
+}
+
+// Функция для получения списка топиков
+pub async fn list_topics(broker: web::Data<Arc<Mutex<Broker>>>) -> HttpResponse {
+    HttpResponse::Ok().json(broker.lock().await.topic_names())
+}
+
+// Функция для получения описания топика
+pub async fn describe_topic(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let description = broker
+        .lock()
+        .await
+        .describe_topic(&name)
+        .await
+        .map_err(error::ErrorNotFound)?;
+    Ok(HttpResponse::Ok().json(description))
+}
+
+// Функция для изменения настроек топика, в теле - только изменяемые поля
+pub async fn alter_topic(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    name: web::Path<String>,
+    changes: web::Json<serde_json::Value>,
+) -> Result<HttpResponse, Error> {
+    let config = broker
+        .lock()
+        .await
+        .alter_topic(&name, changes.into_inner())
+        .await
+        .map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(config))
+}
+
+// Функция для удаления топика
+pub async fn delete_topic(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    broker
+        .lock()
+        .await
+        .delete_topic(&name)
+        .await
+        .map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().finish())
+}
+
+// Маршруты администрирования
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/admin/snapshot").route(web::post().to(snapshot)))
+        .service(web::resource("/admin/memory").route(web::get().to(memory)))
+        .service(web::resource("/admin/topics").route(web::get().to(list_topics)))
+        .service(
+            web::resource("/admin/topics/{name}")
+                .route(web::get().to(describe_topic))
+                .route(web::patch().to(alter_topic))
+                .route(web::delete().to(delete_topic)),
+        );
+}
@@ -1,79 +1,499 @@
-use crate::topic::{Acknowledge, PublishMessage, Subscribe, Topic, Unsubscribe};
+use crate::buffer::SubscriberBuffer;
+use crate::message::now_millis;
+use crate::snapshot::BrokerSnapshot;
+use crate::storage::{FileStorage, MemoryBudget, MemoryStorage, StorageKind, TopicStorage};
+use crate::topic::{
+    Acknowledge, AlterConfig, DeleteTopic, Describe, Fetch, GetKey, GetMessage, GetTable, KeyPage,
+    ListKeys, Nack, NackAction, PublishMessage, RestoreSnapshot, Subscribe, TableSnapshot,
+    TakeSnapshot, Topic, TopicConfig, TopicDescription, Unsubscribe, Watch,
+};
+use crate::wal::{self, TopicLog, WalOptions};
 use actix::prelude::*;
 use actix_web::Error;
 use actix_web::{error, web, HttpResponse};
 use futures::lock::Mutex;
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
 
 // Хранение топиков (каждый топик будет актором)
 pub struct Broker {
     topics: HashMap<String, Addr<Topic>>,
+    // Настройки журналов на диске, None - топики живут только в памяти
+    wal: Option<WalOptions>,
+    // Общий для всех топиков лимит памяти под сообщения
+    budget: Arc<MemoryBudget>,
+    // Dead-letter топик каждого топика, у которого он задан
+    dead_letters: HashMap<String, String>,
+    // Каталог для снимков через /admin/snapshot, None - снимки по HTTP выключены
+    snapshot_dir: Option<PathBuf>,
 }
 
 // Структура для создания топика
 #[derive(Deserialize)]
 pub struct CreateTopicRequest {
     pub name: String,
-    // Время после которого сообщения удаляются
-    pub retention: Option<u64>, // Время в секундах
-    pub compaction: bool,
+    // Настройки хранения и доставки (retention, compaction, повторная доставка)
+    #[serde(flatten)]
+    pub config: TopicConfig,
+}
+
+impl Default for Broker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Broker {
     pub fn new() -> Broker {
         Broker {
             topics: HashMap::new(),
+            wal: None,
+            budget: Arc::default(),
+            dead_letters: HashMap::new(),
+            snapshot_dir: None,
+        }
+    }
+
+    // Брокер с журналами на диске, все сохраненные топики восстанавливаются
+    // вместе с настройками, offset'ами и состоянием компакции
+    pub fn open(options: WalOptions) -> io::Result<Broker> {
+        let stored: HashMap<String, TopicConfig> = wal::load_topics(&options)?
+            .into_iter()
+            .map(|topic| (topic.name, topic.config))
+            .collect();
+        let mut broker = Broker {
+            topics: HashMap::new(),
+            wal: Some(options),
+            budget: Arc::default(),
+            dead_letters: HashMap::new(),
+            snapshot_dir: None,
+        };
+
+        let mut names: Vec<&String> = stored.keys().collect();
+        names.sort();
+        for name in names {
+            println!("Топик восстановлен - {}", name);
+        }
+        broker.start_topics(stored).map_err(io::Error::other)?;
+        Ok(broker)
+    }
+
+    // Лимит памяти под сообщения всех топиков, None - без ограничения
+    pub fn set_memory_limit(&self, limit: Option<u64>) {
+        self.budget.set_limit(limit);
+    }
+
+    // Учет памяти брокера
+    pub fn memory_budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+
+    // Каталог, в который /admin/snapshot записывает снимки
+    pub fn set_snapshot_dir(&mut self, dir: Option<PathBuf>) {
+        self.snapshot_dir = dir;
+    }
+
+    // Путь к файлу снимка внутри каталога снимков. Допускается только
+    // относительный путь без "..", чтобы запрос не мог писать за пределы каталога
+    pub fn snapshot_path(&self, name: &Path) -> Result<PathBuf, String> {
+        let dir = self
+            .snapshot_dir
+            .as_ref()
+            .ok_or("Каталог для снимков не задан")?;
+        let plain = name
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if !plain || name.as_os_str().is_empty() {
+            return Err(format!("Недопустимое имя снимка: {}", name.display()));
         }
+        Ok(dir.join(name))
+    }
+
+    // Снимок всех топиков. Каждый топик снимается атомарно, но топики
+    // снимаются по очереди, а публикации идут в топики мимо блокировки брокера,
+    // поэтому между топиками снимок не согласован
+    pub async fn snapshot(&self) -> Result<BrokerSnapshot, String> {
+        let mut names: Vec<&String> = self.topics.keys().collect();
+        names.sort();
+
+        let mut topics = Vec::new();
+        for name in names {
+            let snapshot = self.topics[name]
+                .send(TakeSnapshot)
+                .await
+                .map_err(|e| e.to_string())?;
+            topics.push(snapshot);
+        }
+        Ok(BrokerSnapshot {
+            created_at: now_millis(),
+            topics,
+        })
+    }
+
+    // Восстановление топиков из снимка, топики с такими именами не должны существовать
+    pub async fn restore_snapshot(&mut self, snapshot: BrokerSnapshot) -> Result<(), String> {
+        if let Some(topic) = snapshot
+            .topics
+            .iter()
+            .find(|topic| self.topics.contains_key(&topic.name))
+        {
+            return Err(format!("Топик {} уже существует", topic.name));
+        }
+
+        let configs: HashMap<String, TopicConfig> = snapshot
+            .topics
+            .iter()
+            .map(|topic| (topic.name.clone(), topic.config.clone()))
+            .collect();
+        self.create_topics(configs, false)?;
+
+        for topic in snapshot.topics {
+            let addr = self.topics[&topic.name].clone();
+            addr.send(RestoreSnapshot(topic))
+                .await
+                .map_err(|e| e.to_string())??;
+        }
+        Ok(())
     }
 
     // Создание нового топика
-    pub fn create_topic(
+    pub fn create_topic(&mut self, name: String, config: TopicConfig) -> Result<(), String> {
+        self.create_topics(HashMap::from([(name, config)]), true)
+    }
+
+    // Создание нескольких новых топиков, которые могут ссылаться друг на друга.
+    // При восстановлении снимка циклы dead-letter топиков не проверяются:
+    // снимок мог быть сделан до того, как такие цепочки стали запрещены
+    fn create_topics(
         &mut self,
-        name: String,
-        retention: Option<Duration>,
-        compaction: bool,
+        configs: HashMap<String, TopicConfig>,
+        check_chains: bool,
     ) -> Result<(), String> {
-        if self.topics.contains_key(&name) {
-            Err("Топик уже существует".into())
-        } else {
+        let mut links = self.dead_letters.clone();
+        for (name, config) in &configs {
+            if self.topics.contains_key(name) {
+                return Err("Топик уже существует".into());
+            }
+            if config.dead_letter_topic.as_ref() == Some(name) {
+                return Err("Топик не может быть dead-letter топиком для самого себя".into());
+            }
+            if config.storage == StorageKind::File && self.wal.is_none() {
+                return Err(
+                    "Для хранения на диске брокер должен быть запущен с каталогом данных".into(),
+                );
+            }
+            if let Some(dead_letter) = &config.dead_letter_topic {
+                links.insert(name.clone(), dead_letter.clone());
+            }
+        }
+        if check_chains {
+            for name in configs.keys() {
+                check_dead_letter_chain(&links, name)?;
+            }
+        }
+
+        // Описание топика сохраняем на диск, чтобы восстановить его после перезапуска
+        let mut names: Vec<&String> = configs.keys().collect();
+        names.sort();
+        for name in names {
+            if let Some(options) = &self.wal {
+                wal::save_topic(&options.topic_dir(name), name, &configs[name])
+                    .map_err(|e| e.to_string())?;
+            }
             println!("Топик создан - {}", name);
-            // Создаем новый топик и переводим в актор
-            self.topics
-                .insert(name.clone(), Topic::new(retention, compaction).start());
-            Ok(())
         }
+        self.start_topics(configs)
     }
 
-    // Отправка сообщения в топик
+    // Запуск акторов топиков, журналы на диске подхватываются вместе с сообщениями.
+    // Сначала у всех топиков появляются адреса, затем каждый получает адрес своего
+    // dead-letter топика, поэтому топики могут ссылаться друг на друга в любом порядке
+    fn start_topics(&mut self, configs: HashMap<String, TopicConfig>) -> Result<(), String> {
+        let mut topics = Vec::new();
+        for (name, config) in configs {
+            // Хранилище выбирается по настройкам топика, журнал на диске
+            // подхватывается вместе с уже сохраненными сообщениями
+            let storage: Box<dyn TopicStorage> = match &self.wal {
+                Some(options) => {
+                    let (log, messages) = TopicLog::open(options.topic_dir(&name), options)
+                        .map_err(|e| e.to_string())?;
+                    match config.storage {
+                        StorageKind::Memory => Box::new(MemoryStorage::durable(log, messages)),
+                        StorageKind::File => Box::new(FileStorage::open(log, messages)),
+                    }
+                }
+                None => Box::new(MemoryStorage::new()),
+            };
+            let dead_letter = config.dead_letter_topic.clone();
+            let topic = Topic::new(name.clone(), config)
+                .with_storage(storage)
+                .with_budget(self.budget.clone());
+            topics.push((name, topic, dead_letter));
+        }
+
+        let mut pending = Vec::new();
+        for (name, topic, dead_letter) in topics {
+            if let Some(dead_letter) = &dead_letter {
+                self.dead_letters.insert(name.clone(), dead_letter.clone());
+            }
+            let ctx = Context::new();
+            self.topics.insert(name, ctx.address());
+            pending.push((topic, ctx, dead_letter));
+        }
+
+        for (mut topic, ctx, dead_letter) in pending {
+            // Dead-letter топик создается с настройками по умолчанию, если его еще нет
+            if let Some(dead_letter) = dead_letter {
+                if !self.topics.contains_key(&dead_letter) {
+                    self.create_topic(dead_letter.clone(), TopicConfig::default())?;
+                }
+                topic = topic.with_dead_letter(self.topics[&dead_letter].clone());
+            }
+            ctx.run(topic);
+        }
+        Ok(())
+    }
+
+    pub fn has_topic(&self, name: &str) -> bool {
+        self.topics.contains_key(name)
+    }
+
+    // Имена всех топиков
+    pub fn topic_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.topics.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    // Описание топика: настройки, количество сообщений, подписчиков и ожидающих подтверждения
+    pub async fn describe_topic(&self, topic_name: &str) -> Result<TopicDescription, String> {
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic.send(Describe).await.map_err(|e| e.to_string())
+        } else {
+            Err("Топик не найден".into())
+        }
+    }
+
+    // Изменение настроек живого топика. changes - JSON-объект с полями TopicConfig,
+    // поля, которых в нем нет, остаются прежними. Возвращает новые настройки
+    pub async fn alter_topic(
+        &mut self,
+        topic_name: &str,
+        changes: serde_json::Value,
+    ) -> Result<TopicConfig, String> {
+        let current = self.describe_topic(topic_name).await?.config;
+        let serde_json::Value::Object(changes) = changes else {
+            return Err("Ожидается JSON-объект с настройками".into());
+        };
+        let mut merged = serde_json::to_value(&current).map_err(|e| e.to_string())?;
+        if let Some(fields) = merged.as_object_mut() {
+            fields.extend(changes);
+        }
+        let config: TopicConfig =
+            serde_json::from_value(merged).map_err(|e| format!("Неверные настройки: {}", e))?;
+
+        if config.storage != current.storage {
+            return Err("Хранилище топика нельзя изменить".into());
+        }
+        if config.dead_letter_topic.as_deref() == Some(topic_name) {
+            return Err("Топик не может быть dead-letter топиком для самого себя".into());
+        }
+        let mut links = self.dead_letters.clone();
+        links.remove(topic_name);
+        if let Some(dead_letter) = &config.dead_letter_topic {
+            links.insert(topic_name.to_string(), dead_letter.clone());
+        }
+        check_dead_letter_chain(&links, topic_name)?;
+        let dead_letter = match &config.dead_letter_topic {
+            Some(dead_letter) => {
+                if !self.topics.contains_key(dead_letter) {
+                    self.create_topic(dead_letter.clone(), TopicConfig::default())?;
+                }
+                self.topics.get(dead_letter).cloned()
+            }
+            None => None,
+        };
+
+        if let Some(options) = &self.wal {
+            wal::save_topic(&options.topic_dir(topic_name), topic_name, &config)
+                .map_err(|e| e.to_string())?;
+        }
+        self.topics[topic_name]
+            .send(AlterConfig {
+                config: config.clone(),
+                dead_letter,
+            })
+            .await
+            .map_err(|e| e.to_string())?;
+        self.dead_letters = links;
+        Ok(config)
+    }
+
+    // Удаление топика вместе с данными на диске, подписчики отключаются
+    pub async fn delete_topic(&mut self, topic_name: &str) -> Result<(), String> {
+        let Some(topic) = self.topics.get(topic_name) else {
+            return Err("Топик не найден".into());
+        };
+
+        // Топик, в который другие топики переносят сообщения, удалять нельзя
+        for other in self.topics.keys().filter(|name| *name != topic_name) {
+            let description = self.describe_topic(other).await?;
+            if description.config.dead_letter_topic.as_deref() == Some(topic_name) {
+                return Err(format!(
+                    "Топик используется как dead-letter топик для {}",
+                    other
+                ));
+            }
+        }
+
+        topic.send(DeleteTopic).await.map_err(|e| e.to_string())?;
+        self.topics.remove(topic_name);
+        self.dead_letters.remove(topic_name);
+        if let Some(options) = &self.wal {
+            let dir = options.topic_dir(topic_name);
+            if dir.exists() {
+                std::fs::remove_dir_all(dir).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Отправка сообщения в топик, возвращает назначенный сообщению offset.
+    // Возвращаемая публикация не держит брокер: при политике Block издатель
+    // может долго ждать места, а брокер в это время нужен остальным
     pub fn publish_message(
         &self,
         topic_name: &str,
         message: crate::message::Message,
-    ) -> Result<(), String> {
-        if let Some(topic) = self.topics.get(topic_name) {
+    ) -> impl Future<Output = Result<u64, String>> {
+        let topic = self.topics.get(topic_name).cloned();
+        async move {
+            let Some(topic) = topic else {
+                return Err("Топик не найден".into());
+            };
             println!("ID сообщения: {}", message.id);
-            topic.do_send(PublishMessage(message));
-            println!("Сообщение отправлено");
-            Ok(())
+            let offset = topic
+                .send(PublishMessage(message))
+                .await
+                .map_err(|e| e.to_string())??;
+            println!("Сообщение отправлено, offset: {}", offset);
+            Ok(offset)
+        }
+    }
+
+    // Чтение сообщений по запросу, брокер не блокируется на время ожидания
+    pub fn fetch(
+        &self,
+        topic_name: &str,
+        fetch: Fetch,
+    ) -> impl Future<Output = Result<Vec<crate::message::Message>, String>> {
+        let topic = self.topics.get(topic_name).cloned();
+        async move {
+            let Some(topic) = topic else {
+                return Err("Топик не найден".into());
+            };
+            topic.send(fetch).await.map_err(|e| e.to_string())?
+        }
+    }
+
+    // Получение сообщения по offset
+    pub async fn get_message(
+        &self,
+        topic_name: &str,
+        offset: u64,
+    ) -> Result<Option<crate::message::Message>, String> {
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic
+                .send(GetMessage { offset })
+                .await
+                .map_err(|e| e.to_string())
+        } else {
+            Err("Топик не найден".into())
+        }
+    }
+
+    // Текущее значение ключа компактного топика
+    pub async fn get_key(
+        &self,
+        topic_name: &str,
+        key: String,
+    ) -> Result<Option<crate::message::Message>, String> {
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic
+                .send(GetKey { key })
+                .await
+                .map_err(|e| e.to_string())?
+        } else {
+            Err("Топик не найден".into())
+        }
+    }
+
+    // Страница ключей компактного топика
+    pub async fn list_keys(
+        &self,
+        topic_name: &str,
+        prefix: Option<String>,
+        after: Option<String>,
+        limit: usize,
+    ) -> Result<KeyPage, String> {
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic
+                .send(ListKeys {
+                    prefix,
+                    after,
+                    limit,
+                })
+                .await
+                .map_err(|e| e.to_string())?
+        } else {
+            Err("Топик не найден".into())
+        }
+    }
+
+    // Все текущие значения компактного топика
+    pub async fn get_table(&self, topic_name: &str) -> Result<TableSnapshot, String> {
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic.send(GetTable).await.map_err(|e| e.to_string())?
         } else {
             Err("Топик не найден".into())
         }
     }
 
-    pub fn subscribe(
+    // Подписка на топик
+    pub async fn subscribe(&self, topic_name: &str, subscription: Subscribe) -> Result<(), String> {
+        // Если топик существует, отправляем сообщение, что клиент подписался
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic.send(subscription).await.map_err(|e| e.to_string())?
+        } else {
+            Err("Топик не найден".into())
+        }
+    }
+
+    // Подписка на изменения ключей компактного топика
+    pub async fn watch(
         &self,
         topic_name: &str,
         client_id: String,
-        // Recipient - это адресат сообщения
         addr: Recipient<crate::topic::DeliverMessage>,
+        keys: HashSet<String>,
+        prefix: Option<String>,
+        buffer: Option<Arc<SubscriberBuffer>>,
     ) -> Result<(), String> {
-        // Если топик существует, отправляем сообщение, что клиент подписался
         if let Some(topic) = self.topics.get(topic_name) {
-            topic.do_send(Subscribe { client_id, addr });
-            Ok(())
+            topic
+                .send(Watch {
+                    client_id,
+                    addr,
+                    keys,
+                    prefix,
+                    buffer,
+                })
+                .await
+                .map_err(|e| e.to_string())?
         } else {
             Err("Топик не найден".into())
         }
@@ -110,6 +530,49 @@ impl Broker {
             Err("Топик не найден".into())
         }
     }
+
+    // Отрицательное подтверждение: повторная доставка или перенос в dead-letter топик
+    pub fn nack(
+        &self,
+        topic_name: &str,
+        client_id: String,
+        message_id: String,
+        action: NackAction,
+        reason: Option<String>,
+    ) -> Result<(), String> {
+        if let Some(topic) = self.topics.get(topic_name) {
+            topic.do_send(Nack {
+                client_id,
+                message_id,
+                action,
+                reason,
+            });
+            println!("Сообщение отклонено");
+            Ok(())
+        } else {
+            Err("Топик не найден".into())
+        }
+    }
+}
+
+// Цепочка dead-letter топиков, начатая с name, не должна к нему возвращаться
+fn check_dead_letter_chain(links: &HashMap<String, String>, name: &str) -> Result<(), String> {
+    let mut next = links.get(name);
+    let mut visited = HashSet::new();
+    while let Some(current) = next {
+        if current == name {
+            return Err(format!(
+                "Цепочка dead-letter топиков возвращается к топику {}",
+                name
+            ));
+        }
+        // Цикл, в который name не входит, проверяется для топиков этого цикла
+        if !visited.insert(current) {
+            break;
+        }
+        next = links.get(current);
+    }
+    Ok(())
 }
 
 // Обработчик создания топика
@@ -119,11 +582,7 @@ pub async fn create_topic_handler(
 ) -> Result<HttpResponse, Error> {
     let mut broker = broker.lock().await;
     broker
-        .create_topic(
-            req.name.clone(),
-            req.retention.map(Duration::from_secs),
-            req.compaction,
-        )
+        .create_topic(req.name.clone(), req.config.clone())
         .map_err(error::ErrorBadRequest)?;
     Ok(HttpResponse::Ok().finish())
 }
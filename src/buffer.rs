@@ -0,0 +1,115 @@
+use crate::topic::DeliverMessage;
+use futures::Stream;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+// Буфер подписчика между сессией клиента и потоком ответа. Топик отмечает каждое
+// отправленное сообщение, поэтому без ожидания ответа знает, сколько сообщений
+// подписчик еще не прочитал, и может применить политику медленного потребителя
+#[derive(Debug, Default)]
+pub struct SubscriberBuffer {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<DeliverMessage>,
+    // Отправлено топиком и еще не прочитано: в очереди и в пути к сессии
+    queued: usize,
+    // Сколько сообщений, еще не дошедших до очереди, нужно пропустить
+    skip: usize,
+    waker: Option<Waker>,
+    // Клиент отключился и больше не читает
+    closed: bool,
+    // Сессия остановлена, новых сообщений не будет
+    finished: bool,
+}
+
+impl SubscriberBuffer {
+    pub fn new() -> Arc<Self> {
+        Arc::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // Сколько сообщений подписчик еще не прочитал
+    pub fn queued(&self) -> usize {
+        self.state().queued
+    }
+
+    // Топик отправил сообщение
+    pub fn sent(&self) {
+        self.state().queued += 1;
+    }
+
+    // Топик вытесняет самое старое непрочитанное сообщение
+    pub fn drop_oldest(&self) {
+        let mut state = self.state();
+        if state.queue.pop_front().is_none() {
+            // Все непрочитанные еще в пути, пропустим первое из них
+            state.skip += 1;
+        }
+        state.queued = state.queued.saturating_sub(1);
+    }
+
+    // Сессия кладет доставленное сообщение в очередь
+    pub fn push(&self, message: DeliverMessage) {
+        let mut state = self.state();
+        if state.skip > 0 {
+            state.skip -= 1;
+            return;
+        }
+        state.queue.push_back(message);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    // Сессия остановлена: поток дочитывает очередь и завершается
+    pub fn finish(&self) {
+        let mut state = self.state();
+        state.finished = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state().closed
+    }
+
+    // Поток сообщений для ответа клиенту
+    pub fn stream(self: &Arc<Self>) -> BufferStream {
+        BufferStream(self.clone())
+    }
+}
+
+// Читающая сторона буфера, при удалении буфер считается закрытым
+pub struct BufferStream(Arc<SubscriberBuffer>);
+
+impl Stream for BufferStream {
+    type Item = DeliverMessage;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DeliverMessage>> {
+        let mut state = self.0.state();
+        if let Some(message) = state.queue.pop_front() {
+            state.queued = state.queued.saturating_sub(1);
+            return Poll::Ready(Some(message));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for BufferStream {
+    fn drop(&mut self) {
+        self.0.state().closed = true;
+    }
+}
@@ -1,18 +1,40 @@
 use crate::{
+    admin,
     broker::{Broker, CreateTopicRequest},
+    buffer::{BufferStream, SubscriberBuffer},
+    kv,
     message::Message,
-    topic::DeliverMessage,
+    topic::{
+        DeliverMessage, Fetch, FetchFrom, NackAction, StartPosition, Subscribe,
+        DISCONNECT_CHECK_INTERVAL,
+    },
+    ws,
 };
 use actix::prelude::*;
 use actix_web::{error, web, Error, HttpRequest, HttpResponse};
-use futures::{channel::mpsc, lock::Mutex, StreamExt};
-use serde::Deserialize;
-use std::{sync::Arc, time::Duration};
+use futures::{
+    channel::mpsc,
+    lock::Mutex,
+    stream::{self, SelectAll},
+    Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
 use uuid::Uuid;
 
-// Структура для хранения сессии клиента, хранит отправителя сообщений
-struct ClientSession {
-    tx: mpsc::UnboundedSender<Message>,
+// Сессия соединения клиента, складывает сообщения всех его подписок в буфер
+pub struct ClientSession {
+    buffer: Arc<SubscriberBuffer>,
+}
+
+impl ClientSession {
+    pub fn new(buffer: Arc<SubscriberBuffer>) -> Self {
+        ClientSession { buffer }
+    }
 }
 
 // Структура для публикации сообщения
@@ -20,14 +42,66 @@ struct ClientSession {
 pub struct PublishRequest {
     topic: String,
     key: Option<String>,
-    payload: String,
+    // null - tombstone, удаляет ключ в компактном топике
+    payload: Option<String>,
     require_ack: bool,
 }
 
+// Ответ на публикацию сообщения
+#[derive(Serialize)]
+pub struct PublishResponse {
+    id: String,
+    offset: u64,
+}
+
+// Структура для получения сообщения по offset
+#[derive(Deserialize)]
+pub struct GetMessageRequest {
+    topic: String,
+    offset: u64,
+}
+
+// Структура для чтения сообщений по запросу: указывается offset или группа
+#[derive(Deserialize)]
+pub struct FetchRequest {
+    topic: String,
+    // Читать с этого offset
+    offset: Option<u64>,
+    // Читать с позиции группы, позиция сдвигается на выданные сообщения
+    group: Option<String>,
+    // Максимум сообщений в ответе
+    max: Option<usize>,
+    // Сколько ждать, если сообщений нет, в миллисекундах
+    timeout_ms: Option<u64>,
+    // Id клиента для подтверждений, по умолчанию создается новый
+    client_id: Option<String>,
+}
+
 // Структура для подписки на топик
 #[derive(Deserialize)]
 pub struct SubscribeRequest {
     topic: String,
+    // Позиция начала чтения: earliest, latest, time:<мс> или id сообщения
+    from: Option<String>,
+    // Группа потребителей: внутри группы каждое сообщение получает один участник
+    group: Option<String>,
+    // Постоянный id клиента, по умолчанию создается новый
+    client_id: Option<String>,
+    // Имя постоянной подписки, оно же id клиента: позиция и неподтвержденные
+    // сообщения сохраняются между подключениями
+    durable: Option<String>,
+}
+
+// Структура для наблюдения за ключами компактного топика
+#[derive(Deserialize)]
+pub struct WatchRequest {
+    topic: String,
+    // Ключи через запятую
+    keys: Option<String>,
+    // Префикс ключей
+    prefix: Option<String>,
+    // Постоянный id клиента, по умолчанию создается новый
+    client_id: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -43,19 +117,104 @@ pub struct AcknowledgeRequest {
     message_id: String,
 }
 
+// Структура для отрицательного подтверждения
+#[derive(Deserialize)]
+pub struct NackRequest {
+    topic: String,
+    client_id: String,
+    message_id: String,
+    // requeue, delay (с полем delay_ms) или reject
+    #[serde(flatten)]
+    action: NackAction,
+    reason: Option<String>,
+}
+
 // Функция для публикации сообщения
 pub async fn publish(
     broker: web::Data<Arc<Mutex<Broker>>>,
     req: web::Json<PublishRequest>,
 ) -> Result<HttpResponse, Error> {
-    let broker = broker.lock().await;
-    let message = Message::new(req.payload.clone(), req.key.clone(), req.require_ack);
+    let message = Message::with_payload(req.payload.clone(), req.key.clone(), req.require_ack);
+    let id = message.id.clone();
+    // Брокер отпускаем до ожидания публикации
+    let publish = broker.lock().await.publish_message(&req.topic, message);
+    let offset = publish.await.map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(PublishResponse { id, offset }))
+}
+
+// Функция для отрицательного подтверждения сообщения
+pub async fn nack(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    req: web::Json<NackRequest>,
+) -> Result<HttpResponse, Error> {
+    let req = req.into_inner();
     broker
-        .publish_message(&req.topic, message)
+        .lock()
+        .await
+        .nack(
+            &req.topic,
+            req.client_id,
+            req.message_id,
+            req.action,
+            req.reason,
+        )
         .map_err(error::ErrorBadRequest)?;
+
     Ok(HttpResponse::Ok().finish())
 }
 
+// Функция для получения сообщения по offset
+pub async fn get_message(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    query: web::Query<GetMessageRequest>,
+) -> Result<HttpResponse, Error> {
+    let message = broker
+        .lock()
+        .await
+        .get_message(&query.topic, query.offset)
+        .await
+        .map_err(error::ErrorBadRequest)?
+        .ok_or_else(|| error::ErrorNotFound("Сообщение не найдено"))?;
+    Ok(HttpResponse::Ok().json(message))
+}
+
+// Размер ответа fetch по умолчанию и максимальный
+const DEFAULT_FETCH_SIZE: usize = 100;
+pub const MAX_FETCH_SIZE: usize = 1000;
+// Дольше этого fetch не ждет
+pub const MAX_FETCH_WAIT: Duration = Duration::from_secs(60);
+
+// Функция для чтения сообщений по запросу (long polling): возвращает JSON-массив,
+// пустой, если за timeout_ms ничего не появилось
+pub async fn fetch(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    req_http: HttpRequest,
+    query: web::Query<FetchRequest>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let from = match (query.offset, query.group) {
+        (Some(offset), None) => FetchFrom::Offset(offset),
+        (None, Some(group)) => FetchFrom::Group(group),
+        _ => return Err(error::ErrorBadRequest("Нужно указать offset или group")),
+    };
+    let client_id = client_id(&req_http, query.client_id.as_deref())?;
+    let fetch = Fetch {
+        client_id: client_id.clone(),
+        from,
+        max: query
+            .max
+            .unwrap_or(DEFAULT_FETCH_SIZE)
+            .clamp(1, MAX_FETCH_SIZE),
+        wait: Duration::from_millis(query.timeout_ms.unwrap_or(0)).min(MAX_FETCH_WAIT),
+    };
+    // Брокер отпускаем до ожидания сообщений
+    let fetch = broker.lock().await.fetch(&query.topic, fetch);
+    let messages = fetch.await.map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Client-Id", client_id))
+        .json(messages))
+}
+
 // Функция для подтверждения получения сообщения
 pub async fn acknowledge(
     broker: web::Data<Arc<Mutex<Broker>>>,
@@ -70,36 +229,155 @@ pub async fn acknowledge(
     Ok(HttpResponse::Ok().finish())
 }
 
+// Как часто отправлять комментарий-heartbeat, чтобы прокси не закрывали соединение
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+// Событие Server-Sent Events: id - offset сообщения, data - сообщение в JSON
+fn sse_event(message: &Message) -> Result<web::Bytes, Error> {
+    let data = serde_json::to_string(message).map_err(error::ErrorInternalServerError)?;
+    Ok(web::Bytes::from(format!(
+        "id: {}\nevent: message\ndata: {}\n\n",
+        message.offset, data
+    )))
+}
+
+// Ответ text/event-stream: первым событием клиент узнает свой id
+// (он же в заголовке X-Client-Id), затем сообщения из буфера, а в паузах heartbeat
+fn sse_response(rx: BufferStream, client_id: &str, topic: &str) -> HttpResponse {
+    let subscribed = serde_json::json!({"client_id": client_id, "topic": topic});
+    let subscribed = web::Bytes::from(format!("event: subscribed\ndata: {}\n\n", subscribed));
+    let messages = stream::unfold(rx, |mut rx| async move {
+        match actix_web::rt::time::timeout(HEARTBEAT_INTERVAL, rx.next()).await {
+            Ok(Some(delivery)) => Some((sse_event(&delivery.message), rx)),
+            // Сессия остановлена, например топик удален
+            Ok(None) => None,
+            Err(_) => Some((Ok(web::Bytes::from_static(b": heartbeat\n\n")), rx)),
+        }
+    });
+    HttpResponse::Ok()
+        .insert_header(("content-type", "text/event-stream"))
+        .insert_header(("cache-control", "no-cache"))
+        .insert_header(("X-Client-Id", client_id))
+        .streaming(stream::once(async { Ok(subscribed) }).chain(messages))
+}
+
+// Id клиента: свой постоянный из параметра client_id или заголовка X-Client-Id,
+// иначе новый
+pub fn client_id(req_http: &HttpRequest, requested: Option<&str>) -> Result<String, Error> {
+    let requested = requested.or_else(|| {
+        req_http
+            .headers()
+            .get("X-Client-Id")
+            .and_then(|v| v.to_str().ok())
+    });
+    match requested.map(str::trim) {
+        Some("") => Err(error::ErrorBadRequest("Пустой client_id")),
+        Some(client_id) => Ok(client_id.to_string()),
+        None => Ok(Uuid::new_v4().to_string()),
+    }
+}
+
 // Функция для подписки на топик
 pub async fn subscribe(
     broker: web::Data<Arc<Mutex<Broker>>>,
-    // _req: HttpRequest,
-    // _stream: web::Payload,
+    req_http: HttpRequest,
     path: web::Query<SubscribeRequest>,
 ) -> Result<HttpResponse, Error> {
-    // Создаем уникальный идентификатор клиента
-    let client_id = Uuid::new_v4();
+    // Разбираем позицию, с которой клиент хочет читать топик.
+    // При переподключении EventSource присылает Last-Event-ID - offset
+    // последнего полученного сообщения, продолжаем со следующего
+    let last_event_id = req_http
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().parse::<u64>())
+        .transpose()
+        .map_err(|_| error::ErrorBadRequest("Неверный Last-Event-ID"))?;
+    let from = match (last_event_id, &path.from) {
+        (Some(offset), _) => StartPosition::Offset(offset + 1),
+        (None, Some(from)) => from.parse().map_err(error::ErrorBadRequest)?,
+        (None, None) => StartPosition::default(),
+    };
 
-    let (tx, rx) = mpsc::unbounded();
+    let client_id = match &path.durable {
+        Some(name) if name.trim().is_empty() => {
+            return Err(error::ErrorBadRequest("Пустое имя постоянной подписки"))
+        }
+        Some(name) if path.client_id.as_ref().is_some_and(|id| id != name) => {
+            return Err(error::ErrorBadRequest(
+                "client_id постоянной подписки должен совпадать с ее именем",
+            ))
+        }
+        Some(name) => name.trim().to_string(),
+        None => client_id(&req_http, path.client_id.as_deref())?,
+    };
 
-    let ws = ClientSession { tx };
-    let addr = ws.start();
+    let buffer = SubscriberBuffer::new();
+    let rx = buffer.stream();
+
+    let addr = ClientSession::new(buffer.clone()).start();
 
     {
         let broker = broker.lock().await;
         broker
-            .subscribe(&path.topic, client_id.to_string(), addr.recipient())
+            .subscribe(
+                &path.topic,
+                Subscribe {
+                    client_id: client_id.clone(),
+                    addr: addr.recipient(),
+                    from,
+                    group: path.group.clone(),
+                    buffer: Some(buffer),
+                    durable: path.durable.is_some(),
+                },
+            )
+            .await
             .map_err(error::ErrorBadRequest)?;
 
         println!("Клиент подписался на топик {}", path.topic);
         println!("ID клиента: {}", client_id);
     }
 
-    let res = HttpResponse::Ok()
-        .insert_header(("content-type", "text/event-stream"))
-        .streaming(rx.map(|msg| Ok::<_, Error>(web::Bytes::from(msg.payload))));
+    Ok(sse_response(rx, &client_id, &path.topic))
+}
+
+// Функция для наблюдения за ключами: сначала текущие значения, затем изменения.
+// События те же, что у подписки, удаление ключа приходит с "payload": null
+pub async fn watch(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    req_http: HttpRequest,
+    query: web::Query<WatchRequest>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let client_id = client_id(&req_http, query.client_id.as_deref())?;
+    let keys = query
+        .keys
+        .iter()
+        .flat_map(|keys| keys.split(','))
+        .filter(|key| !key.is_empty())
+        .map(String::from)
+        .collect();
 
-    Ok(res)
+    let buffer = SubscriberBuffer::new();
+    let rx = buffer.stream();
+    let addr = ClientSession::new(buffer.clone()).start();
+    broker
+        .lock()
+        .await
+        .watch(
+            &query.topic,
+            client_id.clone(),
+            addr.recipient(),
+            keys,
+            query.prefix,
+            Some(buffer),
+        )
+        .await
+        .map_err(error::ErrorBadRequest)?;
+    println!("Клиент наблюдает за ключами топика {}", query.topic);
+    println!("ID клиента: {}", client_id);
+
+    Ok(sse_response(rx, &client_id, &query.topic))
 }
 
 pub async fn unsubscribe(
@@ -133,11 +411,7 @@ pub async fn create_topic_handler(
 ) -> Result<HttpResponse, Error> {
     let mut broker = broker.lock().await;
     broker
-        .create_topic(
-            req.name.clone(),
-            req.retention.map(Duration::from_secs),
-            req.compaction,
-        )
+        .create_topic(req.name.clone(), req.config.clone())
         .map_err(error::ErrorBadRequest)?;
     Ok(HttpResponse::Ok().finish())
 }
@@ -146,19 +420,258 @@ pub async fn create_topic_handler(
 pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/publish").route(web::post().to(publish)))
         .service(web::resource("/subscribe").route(web::get().to(subscribe)))
+        .service(web::resource("/watch").route(web::get().to(watch)))
         .service(web::resource("/unsubscribe").route(web::post().to(unsubscribe)))
         .service(web::resource("/ack").route(web::post().to(acknowledge)))
-        .service(web::resource("/create_topic").route(web::post().to(create_topic_handler)));
+        .service(web::resource("/nack").route(web::post().to(nack)))
+        .service(web::resource("/message").route(web::get().to(get_message)))
+        .service(web::resource("/fetch").route(web::get().to(fetch)))
+        .service(web::resource("/create_topic").route(web::post().to(create_topic_handler)))
+        .configure(admin::init_routes)
+        .configure(ws::init_routes)
+        .configure(kv::init_routes);
 }
 
 impl Actor for ClientSession {
     type Context = Context<Self>;
+
+    // Когда клиент отключается, поток ответа удаляется и буфер закрывается.
+    // Сессия останавливается, а топик по остановленной сессии
+    // понимает, что подписчика больше нет
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(DISCONNECT_CHECK_INTERVAL, |act, ctx| {
+            if act.buffer.is_closed() {
+                ctx.stop();
+            }
+        });
+    }
+
+    // Например, топик удален или медленный подписчик отключен:
+    // клиент дочитывает буфер, и ответ завершается
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.buffer.finish();
+    }
+}
+
+// Остановка сессии, когда сервер сам закрывает соединение клиента
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSession;
+
+impl Handler<CloseSession> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseSession, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop();
+    }
 }
 
 impl Handler<DeliverMessage> for ClientSession {
     type Result = ();
 
-    fn handle(&mut self, msg: DeliverMessage, _ctx: &mut Context<Self>) -> Self::Result {
-        let _ = self.tx.unbounded_send(msg.0);
+    fn handle(&mut self, msg: DeliverMessage, ctx: &mut Context<Self>) -> Self::Result {
+        if self.buffer.is_closed() {
+            ctx.stop();
+        } else {
+            self.buffer.push(msg);
+        }
+    }
+}
+
+// Подписки соединения на несколько топиков: у каждого топика своя сессия
+// и свой буфер, поэтому политика медленного потребителя топика считает
+// и вытесняет только его сообщения. Сообщения всех подписок приходят
+// одним потоком Deliveries
+pub struct TopicSessions {
+    sessions: HashMap<String, Addr<ClientSession>>,
+    streams: mpsc::UnboundedSender<BufferStream>,
+    // Топики, подписка на которые при сверке не удалась и уже попала в лог
+    failed: HashSet<String>,
+}
+
+// Изменения подписок после сверки с топиками брокера
+pub struct TopicChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl TopicSessions {
+    pub fn new() -> (Self, Deliveries) {
+        let (streams, rx) = mpsc::unbounded();
+        let sessions = TopicSessions {
+            sessions: HashMap::new(),
+            streams,
+            failed: HashSet::new(),
+        };
+        let deliveries = Deliveries {
+            streams: rx,
+            active: SelectAll::new(),
+            closed: false,
+        };
+        (sessions, deliveries)
+    }
+
+    // Подписка на топик отдельной сессией. Повторная подписка на тот же топик
+    // - ошибка, сессию, остановленную топиком, заменяет новая
+    pub async fn subscribe(
+        &mut self,
+        broker: &Mutex<Broker>,
+        topic: &str,
+        client_id: String,
+        from: StartPosition,
+        group: Option<String>,
+        durable: bool,
+    ) -> Result<(), String> {
+        if self.sessions.get(topic).is_some_and(Addr::connected) {
+            return Err(format!(
+                "Клиент {} уже подписан на топик {}",
+                client_id, topic
+            ));
+        }
+        let buffer = SubscriberBuffer::new();
+        let session = ClientSession::new(buffer.clone()).start();
+        let subscription = Subscribe {
+            client_id,
+            addr: session.clone().recipient(),
+            from,
+            group,
+            buffer: Some(buffer.clone()),
+            durable,
+        };
+        let subscribed = broker.lock().await.subscribe(topic, subscription).await;
+        if let Err(error) = subscribed {
+            session.do_send(CloseSession);
+            return Err(error);
+        }
+        let _ = self.streams.unbounded_send(buffer.stream());
+        self.sessions.insert(topic.to_string(), session);
+        Ok(())
+    }
+
+    // Сверка подписок с топиками брокера, которые выбирает wanted: от удаленных
+    // и больше не нужных топиков клиент отписывается, на новые подписывается
+    // с позиции from. Неудачная подписка повторяется при следующей сверке,
+    // а в лог попадает один раз, пока топик не перестанет быть нужным
+    pub async fn sync(
+        &mut self,
+        broker: &Mutex<Broker>,
+        client_id: &str,
+        wanted: impl Fn(&str) -> bool,
+        from: StartPosition,
+        durable: bool,
+    ) -> TopicChanges {
+        let names: Vec<String> = broker
+            .lock()
+            .await
+            .topic_names()
+            .into_iter()
+            .filter(|name| wanted(name))
+            .collect();
+        let removed: Vec<String> = self
+            .sessions
+            .keys()
+            .filter(|topic| !names.contains(topic))
+            .cloned()
+            .collect();
+        for topic in &removed {
+            let _ = broker
+                .lock()
+                .await
+                .unsubscribe(topic, client_id.to_string());
+            self.close(topic);
+        }
+        self.failed.retain(|topic| names.contains(topic));
+
+        let mut added = Vec::new();
+        for topic in names {
+            if self.sessions.contains_key(&topic) {
+                continue;
+            }
+            let subscribed = self
+                .subscribe(
+                    broker,
+                    &topic,
+                    client_id.to_string(),
+                    from.clone(),
+                    None,
+                    durable,
+                )
+                .await;
+            match subscribed {
+                Ok(()) => {
+                    self.failed.remove(&topic);
+                    added.push(topic);
+                }
+                Err(error) => {
+                    if self.failed.insert(topic.clone()) {
+                        println!("Не удалось подписать {} на {}: {}", client_id, topic, error);
+                    }
+                }
+            }
+        }
+        TopicChanges { added, removed }
+    }
+
+    // Топики, на которые открыты сессии
+    pub fn topics(&self) -> impl Iterator<Item = &String> {
+        self.sessions.keys()
+    }
+
+    // Поток подписки на топик заканчивается
+    pub fn close(&mut self, topic: &str) {
+        if let Some(session) = self.sessions.remove(topic) {
+            session.do_send(CloseSession);
+        }
+    }
+
+    // Остановка всех сессий, возвращается, когда они остановлены
+    pub async fn close_all(&mut self) {
+        let sessions: Vec<_> = self.sessions.drain().map(|(_, session)| session).collect();
+        for session in &sessions {
+            session.do_send(CloseSession);
+        }
+        while sessions.iter().any(Addr::connected) {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+impl Drop for TopicSessions {
+    fn drop(&mut self) {
+        for session in self.sessions.values() {
+            session.do_send(CloseSession);
+        }
+    }
+}
+
+// Сообщения всех подписок соединения. Поток заканчивается, когда удалены
+// TopicSessions и дочитаны все подписки
+pub struct Deliveries {
+    streams: mpsc::UnboundedReceiver<BufferStream>,
+    active: SelectAll<BufferStream>,
+    // Новых подписок больше не будет
+    closed: bool,
+}
+
+impl Stream for Deliveries {
+    type Item = DeliverMessage;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<DeliverMessage>> {
+        while !self.closed {
+            match self.streams.poll_next_unpin(cx) {
+                Poll::Ready(Some(stream)) => self.active.push(stream),
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => break,
+            }
+        }
+        match self.active.poll_next_unpin(cx) {
+            Poll::Ready(Some(delivery)) => Poll::Ready(Some(delivery)),
+            // Пустой набор подписок ждет новых
+            Poll::Ready(None) if self.closed => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
     }
 }
@@ -0,0 +1,77 @@
+use crate::broker::Broker;
+use actix_web::{error, web, Error, HttpResponse};
+use futures::lock::Mutex;
+use serde::Deserialize;
+use std::sync::Arc;
+
+// Размер страницы ключей по умолчанию и максимальный
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+// Параметры списка ключей
+#[derive(Deserialize)]
+pub struct KeysRequest {
+    // Только ключи с этим префиксом
+    prefix: Option<String>,
+    // Курсор: последний ключ предыдущей страницы
+    after: Option<String>,
+    limit: Option<usize>,
+}
+
+// Функция для получения текущего значения ключа
+pub async fn get_key(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    path: web::Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (topic, key) = path.into_inner();
+    let message = broker
+        .lock()
+        .await
+        .get_key(&topic, key)
+        .await
+        .map_err(error::ErrorBadRequest)?
+        .ok_or_else(|| error::ErrorNotFound("Ключ не найден"))?;
+    Ok(HttpResponse::Ok().json(message))
+}
+
+// Функция для получения страницы ключей
+pub async fn list_keys(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    topic: web::Path<String>,
+    query: web::Query<KeysRequest>,
+) -> Result<HttpResponse, Error> {
+    let query = query.into_inner();
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let page = broker
+        .lock()
+        .await
+        .list_keys(&topic, query.prefix, query.after, limit)
+        .await
+        .map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(page))
+}
+
+// Функция для получения всех текущих значений
+pub async fn get_table(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    topic: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let table = broker
+        .lock()
+        .await
+        .get_table(&topic)
+        .await
+        .map_err(error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(table))
+}
+
+// Чтение компактных топиков как таблицы ключ-значение
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/kv/{topic}").route(web::get().to(get_table)))
+        .service(web::resource("/kv/{topic}/keys").route(web::get().to(list_keys)))
+        // Ключ может содержать '/', поэтому забираем остаток пути целиком
+        .service(web::resource("/kv/{topic}/values/{key:.*}").route(web::get().to(get_key)));
+}
@@ -1,4 +1,15 @@
+pub mod admin;
 pub mod broker;
+pub mod buffer;
 pub mod client;
+pub mod kv;
 pub mod message;
+pub mod mqtt;
+pub mod resp;
+pub mod snapshot;
+pub mod stomp;
+pub mod storage;
+pub mod tcp;
 pub mod topic;
+pub mod wal;
+pub mod ws;
@@ -1,11 +1,102 @@
 use actix_web::{web, App, HttpServer};
 use futures::lock::Mutex;
-use mem_broker::{broker::Broker, client::init_routes};
+use mem_broker::{
+    broker::Broker,
+    client::init_routes,
+    mqtt,
+    resp::{self, RespOptions},
+    snapshot::BrokerSnapshot,
+    stomp, tcp,
+    wal::{FsyncPolicy, WalOptions},
+};
 use std::sync::Arc;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let broker = Arc::new(Mutex::new(Broker::new()));
+    // Если задан каталог данных, топики пишутся в журнал на диске
+    // и восстанавливаются из него при запуске
+    let mut broker = match std::env::var("MEM_BROKER_DATA_DIR") {
+        Ok(dir) => {
+            let mut options = WalOptions::new(dir);
+            if let Ok(fsync) = std::env::var("MEM_BROKER_FSYNC") {
+                options.fsync = fsync
+                    .parse::<FsyncPolicy>()
+                    .map_err(std::io::Error::other)?;
+            }
+            Broker::open(options)?
+        }
+        Err(_) => Broker::new(),
+    };
+    // Общий лимит памяти под сообщения всех топиков, в байтах
+    if let Ok(limit) = std::env::var("MEM_BROKER_MAX_MEMORY") {
+        let limit = limit.parse::<u64>().map_err(std::io::Error::other)?;
+        broker.set_memory_limit(Some(limit));
+    }
+    // Снимки по HTTP пишутся только в этот каталог
+    if let Ok(dir) = std::env::var("MEM_BROKER_SNAPSHOT_DIR") {
+        broker.set_snapshot_dir(Some(dir.into()));
+    }
+    // Брокер можно запустить из снимка, сделанного через /admin/snapshot
+    if let Ok(path) = std::env::var("MEM_BROKER_SNAPSHOT") {
+        let snapshot = BrokerSnapshot::load(path.as_ref())?;
+        broker
+            .restore_snapshot(snapshot)
+            .await
+            .map_err(std::io::Error::other)?;
+        println!("Брокер восстановлен из снимка {}", path);
+    }
+    let broker = Arc::new(Mutex::new(broker));
+
+    // Остальные протоколы слушают свои порты и включаются только по адресу.
+    // Бинарный протокол
+    if let Ok(tcp_addr) = std::env::var("MEM_BROKER_TCP_ADDR") {
+        let listener = actix_web::rt::net::TcpListener::bind(&tcp_addr).await?;
+        let tcp_broker = broker.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = tcp::serve(listener, tcp_broker).await {
+                println!("Ошибка TCP-сервера: {}", e);
+            }
+        });
+    }
+
+    // MQTT для устройств
+    if let Ok(mqtt_addr) = std::env::var("MEM_BROKER_MQTT_ADDR") {
+        let listener = actix_web::rt::net::TcpListener::bind(&mqtt_addr).await?;
+        let mqtt_broker = broker.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = mqtt::serve(listener, mqtt_broker).await {
+                println!("Ошибка MQTT-сервера: {}", e);
+            }
+        });
+    }
+
+    // STOMP для готовых клиентов
+    if let Ok(stomp_addr) = std::env::var("MEM_BROKER_STOMP_ADDR") {
+        let listener = actix_web::rt::net::TcpListener::bind(&stomp_addr).await?;
+        let stomp_broker = broker.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = stomp::serve(listener, stomp_broker).await {
+                println!("Ошибка STOMP-сервера: {}", e);
+            }
+        });
+    }
+
+    // Совместимость с pub/sub Redis
+    if let Ok(redis_addr) = std::env::var("MEM_BROKER_REDIS_ADDR") {
+        let options = RespOptions {
+            // Создание топиков при PUBLISH и SUBSCRIBE, по умолчанию включено
+            auto_create: std::env::var("MEM_BROKER_REDIS_AUTO_CREATE")
+                .map(|value| value != "false")
+                .unwrap_or(true),
+        };
+        let listener = actix_web::rt::net::TcpListener::bind(&redis_addr).await?;
+        let redis_broker = broker.clone();
+        actix_web::rt::spawn(async move {
+            if let Err(e) = resp::serve(listener, redis_broker, options).await {
+                println!("Ошибка RESP-сервера: {}", e);
+            }
+        });
+    }
 
     HttpServer::new(move || {
         App::new()
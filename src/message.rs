@@ -1,25 +1,63 @@
 use serde::{Deserialize, Serialize};
-use std::time::Instant;
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
     pub id: String,
+    // Порядковый номер сообщения в топике, назначается при публикации
+    #[serde(default)]
+    pub offset: u64,
     pub key: Option<String>,
-    pub payload: String,
+    // None - tombstone: в компактном топике удаляет значение ключа
+    pub payload: Option<String>,
     pub require_ack: bool,
+    // Служебные заголовки, например информация о доставке в dead-letter топике
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    // Время публикации в миллисекундах от UNIX_EPOCH,
+    // нужно для подписки с определенного момента времени
+    #[serde(default)]
+    pub published_at: u64,
     #[serde(skip)]
     pub timestamp: Option<Instant>,
 }
 
 impl Message {
     pub fn new(payload: String, key: Option<String>, require_ack: bool) -> Self {
+        Message::with_payload(Some(payload), key, require_ack)
+    }
+
+    // Сообщение с необязательным содержимым, без содержимого - tombstone
+    pub fn with_payload(payload: Option<String>, key: Option<String>, require_ack: bool) -> Self {
         Message {
             id: Uuid::new_v4().to_string(),
+            offset: 0,
             key,
             payload,
             require_ack,
+            headers: HashMap::new(),
+            published_at: now_millis(),
             timestamp: Some(Instant::now()),
         }
     }
+
+    // Размер сообщения для учета памяти: ключ и содержимое
+    pub fn size(&self) -> u64 {
+        (self.payload.as_ref().map_or(0, String::len) + self.key.as_ref().map_or(0, String::len))
+            as u64
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.payload.is_none()
+    }
+}
+
+// Текущее время в миллисекундах от UNIX_EPOCH
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
 }
@@ -0,0 +1,943 @@
+use crate::{
+    broker::Broker,
+    client::TopicSessions,
+    message::Message,
+    topic::{DeliverMessage, StartPosition, TopicConfig},
+};
+use actix_codec::{Decoder, Encoder, Framed};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::{interval, timeout};
+use actix_web::web::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    channel::{mpsc, oneshot},
+    future::Shared,
+    lock::Mutex,
+    stream::{self, LocalBoxStream, SplitSink},
+    FutureExt, SinkExt, StreamExt,
+};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// Фронтенд MQTT 3.1.1 для устройств, на отдельном порту.
+//
+// Топик MQTT - это топик брокера с тем же именем, при публикации топик
+// создается с настройками по умолчанию. Подписка с фильтром (+ и #)
+// подписывает клиента на все подходящие топики, в том числе созданные позже.
+// QoS 1 при публикации означает сообщение с подтверждением: PUBACK от
+// получателя подтверждает его в топике. QoS 2 не поддерживается.
+// Сессия без clean session - это постоянные подписки с именем id клиента.
+
+// Пакет больше этого размера считается ошибкой
+pub const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024;
+// Столько ждем CONNECT после подключения
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// Как часто проверяем новые топики и keep alive
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+// Столько ждем, пока старое соединение с тем же id клиента закроется
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Коды ответа CONNACK
+pub mod connack {
+    pub const ACCEPTED: u8 = 0;
+    pub const UNACCEPTABLE_PROTOCOL: u8 = 1;
+    pub const IDENTIFIER_REJECTED: u8 = 2;
+}
+
+// Код SUBACK для отклоненного фильтра
+pub const SUBSCRIBE_FAILURE: u8 = 0x80;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connect {
+    pub protocol_name: String,
+    pub protocol_level: u8,
+    pub client_id: String,
+    pub clean_session: bool,
+    // Секунды, 0 - без проверки
+    pub keep_alive: u16,
+    // Сообщение, которое публикуется, если клиент пропал без DISCONNECT
+    pub will: Option<Publish>,
+    pub username: Option<String>,
+    pub password: Option<Bytes>,
+}
+
+impl Connect {
+    pub fn new(client_id: &str, clean_session: bool) -> Self {
+        Connect {
+            protocol_name: "MQTT".into(),
+            protocol_level: 4,
+            client_id: client_id.into(),
+            clean_session,
+            keep_alive: 60,
+            will: None,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Bytes,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    // Есть только у QoS 1
+    pub packet_id: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Connect(Connect),
+    ConnAck {
+        session_present: bool,
+        code: u8,
+    },
+    Publish(Publish),
+    PubAck(u16),
+    Subscribe {
+        packet_id: u16,
+        filters: Vec<(String, u8)>,
+    },
+    SubAck {
+        packet_id: u16,
+        codes: Vec<u8>,
+    },
+    Unsubscribe {
+        packet_id: u16,
+        filters: Vec<String>,
+    },
+    UnsubAck(u16),
+    PingReq,
+    PingResp,
+    Disconnect,
+}
+
+fn invalid(error: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.into())
+}
+
+// Чтение полей пакета
+struct Reader(Bytes);
+
+impl Reader {
+    fn need(&self, len: usize) -> io::Result<()> {
+        if self.0.remaining() < len {
+            return Err(invalid("Пакет короче, чем ожидалось"));
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        self.need(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        self.need(2)?;
+        Ok(self.0.get_u16())
+    }
+
+    fn binary(&mut self) -> io::Result<Bytes> {
+        let len = self.u16()? as usize;
+        self.need(len)?;
+        Ok(self.0.split_to(len))
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.binary()?.to_vec()).map_err(|_| invalid("Строка не в UTF-8"))
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.0.has_remaining()
+    }
+}
+
+fn put_binary(dst: &mut BytesMut, value: &[u8]) {
+    dst.put_u16(value.len() as u16);
+    dst.put_slice(value);
+}
+
+// Разбор и запись пакетов MQTT
+pub struct MqttCodec;
+
+impl MqttCodec {
+    fn parse(first: u8, body: Bytes) -> io::Result<Packet> {
+        let flags = first & 0x0F;
+        let mut body = Reader(body);
+        let packet = match first >> 4 {
+            1 => {
+                let protocol_name = body.string()?;
+                let protocol_level = body.u8()?;
+                let connect_flags = body.u8()?;
+                let keep_alive = body.u16()?;
+                let client_id = body.string()?;
+                let will = if connect_flags & 0x04 != 0 {
+                    let qos = (connect_flags >> 3) & 0x03;
+                    if qos > 1 {
+                        return Err(invalid("QoS 2 не поддерживается"));
+                    }
+                    Some(Publish {
+                        topic: body.string()?,
+                        payload: body.binary()?,
+                        qos,
+                        retain: connect_flags & 0x20 != 0,
+                        dup: false,
+                        packet_id: None,
+                    })
+                } else {
+                    None
+                };
+                let username = match connect_flags & 0x80 {
+                    0 => None,
+                    _ => Some(body.string()?),
+                };
+                let password = match connect_flags & 0x40 {
+                    0 => None,
+                    _ => Some(body.binary()?),
+                };
+                Packet::Connect(Connect {
+                    protocol_name,
+                    protocol_level,
+                    client_id,
+                    clean_session: connect_flags & 0x02 != 0,
+                    keep_alive,
+                    will,
+                    username,
+                    password,
+                })
+            }
+            2 => Packet::ConnAck {
+                session_present: body.u8()? & 0x01 != 0,
+                code: body.u8()?,
+            },
+            3 => {
+                let qos = (flags >> 1) & 0x03;
+                if qos > 1 {
+                    return Err(invalid("QoS 2 не поддерживается"));
+                }
+                let topic = body.string()?;
+                let packet_id = match qos {
+                    0 => None,
+                    _ => Some(body.u16()?),
+                };
+                Packet::Publish(Publish {
+                    topic,
+                    payload: body.0,
+                    qos,
+                    retain: flags & 0x01 != 0,
+                    dup: flags & 0x08 != 0,
+                    packet_id,
+                })
+            }
+            4 => Packet::PubAck(body.u16()?),
+            8 => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.is_empty() {
+                    filters.push((body.string()?, body.u8()?));
+                }
+                if filters.is_empty() {
+                    return Err(invalid("SUBSCRIBE без фильтров"));
+                }
+                Packet::Subscribe { packet_id, filters }
+            }
+            9 => Packet::SubAck {
+                packet_id: body.u16()?,
+                codes: body.0.to_vec(),
+            },
+            10 => {
+                let packet_id = body.u16()?;
+                let mut filters = Vec::new();
+                while !body.is_empty() {
+                    filters.push(body.string()?);
+                }
+                if filters.is_empty() {
+                    return Err(invalid("UNSUBSCRIBE без фильтров"));
+                }
+                Packet::Unsubscribe { packet_id, filters }
+            }
+            11 => Packet::UnsubAck(body.u16()?),
+            12 => Packet::PingReq,
+            13 => Packet::PingResp,
+            14 => Packet::Disconnect,
+            5..=7 => return Err(invalid("QoS 2 не поддерживается")),
+            other => return Err(invalid(format!("Неизвестный тип пакета: {}", other))),
+        };
+        Ok(packet)
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Packet>> {
+        // Фиксированный заголовок: тип и флаги, затем длина остатка в 1-4 байтах
+        let mut len = 0usize;
+        let mut header = 1;
+        loop {
+            if src.len() <= header {
+                return Ok(None);
+            }
+            let byte = src[header];
+            len |= ((byte & 0x7F) as usize) << (7 * (header - 1));
+            header += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if header > 4 {
+                return Err(invalid("Неверная длина пакета"));
+            }
+        }
+        if len > MAX_PACKET_SIZE {
+            return Err(invalid(format!("Слишком большой пакет: {}", len)));
+        }
+        if src.len() < header + len {
+            src.reserve(header + len - src.len());
+            return Ok(None);
+        }
+        let first = src[0];
+        src.advance(header);
+        let body = src.split_to(len).freeze();
+        MqttCodec::parse(first, body).map(Some)
+    }
+}
+
+impl Encoder<Packet> for MqttCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> io::Result<()> {
+        let mut body = BytesMut::new();
+        let first = match packet {
+            Packet::Connect(connect) => {
+                put_binary(&mut body, connect.protocol_name.as_bytes());
+                body.put_u8(connect.protocol_level);
+                let mut flags = (connect.clean_session as u8) << 1;
+                if let Some(will) = &connect.will {
+                    flags |= 0x04 | (will.qos << 3) | ((will.retain as u8) << 5);
+                }
+                if connect.username.is_some() {
+                    flags |= 0x80;
+                }
+                if connect.password.is_some() {
+                    flags |= 0x40;
+                }
+                body.put_u8(flags);
+                body.put_u16(connect.keep_alive);
+                put_binary(&mut body, connect.client_id.as_bytes());
+                if let Some(will) = &connect.will {
+                    put_binary(&mut body, will.topic.as_bytes());
+                    put_binary(&mut body, &will.payload);
+                }
+                if let Some(username) = &connect.username {
+                    put_binary(&mut body, username.as_bytes());
+                }
+                if let Some(password) = &connect.password {
+                    put_binary(&mut body, password);
+                }
+                0x10
+            }
+            Packet::ConnAck {
+                session_present,
+                code,
+            } => {
+                body.put_u8(session_present as u8);
+                body.put_u8(code);
+                0x20
+            }
+            Packet::Publish(publish) => {
+                put_binary(&mut body, publish.topic.as_bytes());
+                if let Some(packet_id) = publish.packet_id {
+                    body.put_u16(packet_id);
+                }
+                body.put_slice(&publish.payload);
+                0x30 | ((publish.dup as u8) << 3) | (publish.qos << 1) | publish.retain as u8
+            }
+            Packet::PubAck(packet_id) => {
+                body.put_u16(packet_id);
+                0x40
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                body.put_u16(packet_id);
+                for (filter, qos) in filters {
+                    put_binary(&mut body, filter.as_bytes());
+                    body.put_u8(qos);
+                }
+                0x82
+            }
+            Packet::SubAck { packet_id, codes } => {
+                body.put_u16(packet_id);
+                body.put_slice(&codes);
+                0x90
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                body.put_u16(packet_id);
+                for filter in filters {
+                    put_binary(&mut body, filter.as_bytes());
+                }
+                0xA2
+            }
+            Packet::UnsubAck(packet_id) => {
+                body.put_u16(packet_id);
+                0xB0
+            }
+            Packet::PingReq => 0xC0,
+            Packet::PingResp => 0xD0,
+            Packet::Disconnect => 0xE0,
+        };
+        dst.reserve(5 + body.len());
+        dst.put_u8(first);
+        let mut len = body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            if len == 0 {
+                dst.put_u8(byte);
+                break;
+            }
+            dst.put_u8(byte | 0x80);
+        }
+        dst.put_slice(&body);
+        Ok(())
+    }
+}
+
+// Фильтр: уровни через /, + - ровно один уровень, # - все оставшиеся
+// и только последним уровнем
+pub fn valid_filter(filter: &str) -> bool {
+    let levels: Vec<&str> = filter.split('/').collect();
+    !filter.is_empty()
+        && levels.iter().enumerate().all(|(i, level)| match *level {
+            "+" => true,
+            "#" => i == levels.len() - 1,
+            level => !level.contains(['+', '#']),
+        })
+}
+
+// Имя топика для публикации: непустое и без wildcard
+fn valid_topic(topic: &str) -> bool {
+    !topic.is_empty() && !topic.contains(['+', '#'])
+}
+
+// Топики, начинающиеся с $, не попадают под фильтры, начинающиеся с + и #
+pub fn filter_matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+    let mut levels = topic.split('/');
+    for part in filter.split('/') {
+        match (part, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {}
+            (part, Some(level)) if part == level => {}
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+// Сессия клиента без clean session между подключениями
+struct StoredSession {
+    filters: HashMap<String, u8>,
+    topics: HashMap<String, u8>,
+}
+
+// Команды подключенному клиенту от других соединений
+enum Control {
+    // Подключился клиент с тем же id
+    TakeOver,
+    // Появился новый топик
+    Rescan,
+}
+
+#[derive(Default)]
+struct State {
+    // Последнее сообщение с флагом retain для каждого топика
+    retained: HashMap<String, Publish>,
+    sessions: HashMap<String, StoredSession>,
+    connections: HashMap<String, Running>,
+}
+
+// Подключенный клиент
+struct Running {
+    id: u64,
+    control: mpsc::UnboundedSender<Control>,
+    // Завершается, когда соединение закрыто и сессия сохранена
+    closed: Shared<oneshot::Receiver<()>>,
+}
+
+// Общее для всех соединений MQTT
+struct Server {
+    broker: Arc<Mutex<Broker>>,
+    state: Mutex<State>,
+    next_connection: AtomicU64,
+}
+
+impl Server {
+    // Публикация от клиента или его последняя воля
+    async fn publish(&self, publish: &Publish) -> Result<(), String> {
+        if !valid_topic(&publish.topic) {
+            return Err(format!("Недопустимое имя топика: {}", publish.topic));
+        }
+        // Пустое содержимое - обычное сообщение, а не tombstone: у сообщений MQTT нет ключа
+        let payload = String::from_utf8(publish.payload.to_vec())
+            .map_err(|_| "Содержимое сообщения не в UTF-8".to_string())?;
+        // Сохраненное с пустым содержимым удаляет сохраненное сообщение топика,
+        // само оно не сохраняется, но подписчикам доставляется
+        if publish.retain && publish.payload.is_empty() {
+            self.state.lock().await.retained.remove(&publish.topic);
+        }
+        let message = Message::new(payload, None, publish.qos > 0);
+        let (publishing, created) = {
+            let mut broker = self.broker.lock().await;
+            let created = !broker.has_topic(&publish.topic);
+            if created {
+                broker.create_topic(publish.topic.clone(), TopicConfig::default())?;
+            }
+            (broker.publish_message(&publish.topic, message), created)
+        };
+        // Подписчики с подходящими фильтрами подпишутся на новый топик
+        // с начала, поэтому успеют получить и это сообщение
+        if created {
+            let state = self.state.lock().await;
+            for running in state.connections.values() {
+                let _ = running.control.unbounded_send(Control::Rescan);
+            }
+        }
+        publishing.await?;
+
+        if publish.retain && !publish.payload.is_empty() {
+            let retained = Publish {
+                dup: false,
+                packet_id: None,
+                ..publish.clone()
+            };
+            let mut state = self.state.lock().await;
+            state.retained.insert(publish.topic.clone(), retained);
+        }
+        Ok(())
+    }
+}
+
+// Прием соединений
+pub async fn serve(listener: TcpListener, broker: Arc<Mutex<Broker>>) -> io::Result<()> {
+    let server = Arc::new(Server {
+        broker,
+        state: Mutex::new(State::default()),
+        next_connection: AtomicU64::new(1),
+    });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("Клиент подключился по MQTT - {}", peer);
+        actix_web::rt::spawn(handle_connection(stream, server.clone()));
+    }
+}
+
+// Проверка CONNECT, возвращает id клиента или код отказа
+fn accept(connect: &Connect) -> Result<String, u8> {
+    if connect.protocol_name != "MQTT" || connect.protocol_level != 4 {
+        return Err(connack::UNACCEPTABLE_PROTOCOL);
+    }
+    match connect.client_id.is_empty() {
+        // Сессию без id нельзя будет продолжить
+        true if !connect.clean_session => Err(connack::IDENTIFIER_REJECTED),
+        true => Ok(Uuid::new_v4().to_string()),
+        false => Ok(connect.client_id.clone()),
+    }
+}
+
+async fn handle_connection(stream: TcpStream, server: Arc<Server>) {
+    let (mut sink, packets) = Framed::new(stream, MqttCodec).split();
+    let mut packets = packets.boxed_local();
+
+    let connect = match timeout(CONNECT_TIMEOUT, packets.next()).await {
+        Ok(Some(Ok(Packet::Connect(connect)))) => connect,
+        _ => return,
+    };
+    let client_id = match accept(&connect) {
+        Ok(client_id) => client_id,
+        Err(code) => {
+            let reply = Packet::ConnAck {
+                session_present: false,
+                code,
+            };
+            let _ = sink.send(reply).await;
+            return;
+        }
+    };
+
+    // Клиент с тем же id отключается (или уже закрывает соединение),
+    // его сессия сохраняется до нашего подключения
+    let previous = server.state.lock().await.connections.remove(&client_id);
+    if let Some(previous) = previous {
+        let _ = previous.control.unbounded_send(Control::TakeOver);
+        let _ = timeout(TAKEOVER_TIMEOUT, previous.closed).await;
+    }
+
+    let id = server.next_connection.fetch_add(1, Ordering::Relaxed);
+    let (control, controls) = mpsc::unbounded();
+    let (closed, closed_rx) = oneshot::channel();
+    let stored = {
+        let mut state = server.state.lock().await;
+        let running = Running {
+            id,
+            control,
+            closed: closed_rx.shared(),
+        };
+        state.connections.insert(client_id.clone(), running);
+        state.sessions.remove(&client_id)
+    };
+
+    let (sessions, deliveries) = TopicSessions::new();
+    let mut connection = Connection {
+        server: server.clone(),
+        id,
+        client_id,
+        clean_session: connect.clean_session,
+        sessions,
+        sink,
+        filters: HashMap::new(),
+        topics: HashMap::new(),
+        inflight: HashMap::new(),
+        next_packet_id: 0,
+        last_packet: Instant::now(),
+        keep_alive: connect.keep_alive,
+        closed: Some(closed),
+    };
+
+    // Новая чистая сессия удаляет сохраненную вместе с постоянными подписками
+    let mut session_present = false;
+    if let Some(stored) = stored {
+        if connect.clean_session {
+            let broker = server.broker.lock().await;
+            for topic in stored.topics.keys() {
+                let _ = broker.unsubscribe(topic, connection.client_id.clone());
+            }
+        } else {
+            connection.filters = stored.filters;
+            session_present = true;
+        }
+    }
+    let reply = Packet::ConnAck {
+        session_present,
+        code: connack::ACCEPTED,
+    };
+    if connection.send(reply).await.is_err() {
+        connection.close(false, connect.will).await;
+        return;
+    }
+    println!("Клиент {} подключен по MQTT", connection.client_id);
+
+    // Продолжение сессии: постоянные подписки досылают пропущенное,
+    // топики, созданные без клиента, читаются с начала
+    if session_present {
+        connection.sync_topics(StartPosition::Earliest).await;
+    }
+
+    let events = stream::select_all([
+        packets
+            .map(|packet| Event::Packet(Some(packet)))
+            .chain(stream::once(async { Event::Packet(None) }))
+            .boxed_local(),
+        deliveries.map(Event::Deliver).boxed_local(),
+        controls.map(Event::Control).boxed_local(),
+        stream::unfold(interval(RESCAN_INTERVAL), |mut ticks| async move {
+            ticks.tick().await;
+            Some((Event::Tick, ticks))
+        })
+        .boxed_local(),
+    ])
+    .boxed_local();
+    let graceful = match connection.run(events).await {
+        Ok(()) => true,
+        Err(error) => {
+            println!(
+                "Соединение MQTT {} закрыто: {}",
+                connection.client_id, error
+            );
+            false
+        }
+    };
+    connection.close(graceful, connect.will).await;
+}
+
+enum Event {
+    // None - клиент закрыл соединение
+    Packet(Option<io::Result<Packet>>),
+    Deliver(DeliverMessage),
+    Control(Control),
+    Tick,
+}
+
+// Соединение: один id клиента на все топики, у каждого топика своя сессия
+struct Connection {
+    server: Arc<Server>,
+    id: u64,
+    client_id: String,
+    clean_session: bool,
+    sessions: TopicSessions,
+    sink: SplitSink<Framed<TcpStream, MqttCodec>, Packet>,
+    // Фильтры подписок и выданный по ним QoS
+    filters: HashMap<String, u8>,
+    // Топики брокера, на которые подписан клиент, и QoS доставки
+    topics: HashMap<String, u8>,
+    // Отправленные с QoS 1 и еще не подтвержденные: топик и id сообщения,
+    // None - сохраненное сообщение, которое в топике не ждет подтверждения
+    inflight: HashMap<u16, Option<(String, String)>>,
+    next_packet_id: u16,
+    last_packet: Instant,
+    keep_alive: u16,
+    // Сигнал следующему подключению с тем же id
+    closed: Option<oneshot::Sender<()>>,
+}
+
+impl Connection {
+    async fn send(&mut self, packet: Packet) -> Result<(), String> {
+        self.sink.send(packet).await.map_err(|e| e.to_string())
+    }
+
+    // Обработка событий до DISCONNECT (Ok) или обрыва соединения (Err)
+    async fn run(&mut self, mut events: LocalBoxStream<'static, Event>) -> Result<(), String> {
+        while let Some(event) = events.next().await {
+            match event {
+                Event::Packet(Some(Ok(Packet::Disconnect))) => return Ok(()),
+                Event::Packet(Some(Ok(packet))) => {
+                    self.last_packet = Instant::now();
+                    self.handle(packet).await?;
+                }
+                Event::Packet(Some(Err(e))) => return Err(e.to_string()),
+                Event::Packet(None) => return Err("Соединение закрыто без DISCONNECT".into()),
+                Event::Deliver(delivery) => self.deliver(delivery).await?,
+                Event::Control(Control::TakeOver) => {
+                    return Err("Подключился клиент с тем же id".into());
+                }
+                Event::Control(Control::Rescan) => self.sync_topics(StartPosition::Earliest).await,
+                Event::Tick => {
+                    // Клиент должен присылать пакеты не реже чем раз в полтора keep alive
+                    let keep_alive = Duration::from_millis(self.keep_alive as u64 * 1500);
+                    if self.keep_alive > 0 && self.last_packet.elapsed() > keep_alive {
+                        return Err("Истек keep alive".into());
+                    }
+                    if !self.filters.is_empty() {
+                        self.sync_topics(StartPosition::Earliest).await;
+                    }
+                }
+            }
+        }
+        Err("Соединение закрыто".into())
+    }
+
+    async fn handle(&mut self, packet: Packet) -> Result<(), String> {
+        match packet {
+            Packet::Publish(publish) => {
+                // Wildcard в имени топика - нарушение протокола, соединение закрывается
+                if !valid_topic(&publish.topic) {
+                    return Err(format!("Недопустимое имя топика: {}", publish.topic));
+                }
+                let result = self.server.publish(&publish).await;
+                match (publish.packet_id, result) {
+                    (Some(packet_id), Ok(())) => self.send(Packet::PubAck(packet_id)).await,
+                    // В MQTT 3.1.1 нет отрицательного PUBACK: соединение закрывается
+                    // без подтверждения, и клиент повторит QoS 1 после переподключения
+                    (Some(_), Err(error)) => Err(format!(
+                        "Сообщение {} не опубликовано: {}",
+                        publish.topic, error
+                    )),
+                    (None, Ok(())) => Ok(()),
+                    // QoS 0 не гарантирует доставку, отказ брокера только логируется
+                    (None, Err(error)) => {
+                        println!(
+                            "Сообщение {} от {} не опубликовано: {}",
+                            publish.topic, self.client_id, error
+                        );
+                        Ok(())
+                    }
+                }
+            }
+            Packet::PubAck(packet_id) => {
+                if let Some(Some((topic, message_id))) = self.inflight.remove(&packet_id) {
+                    let broker = self.server.broker.lock().await;
+                    if let Err(error) =
+                        broker.acknowledge(&topic, self.client_id.clone(), message_id)
+                    {
+                        println!("Подтверждение MQTT не доставлено: {}", error);
+                    }
+                }
+                Ok(())
+            }
+            Packet::Subscribe { packet_id, filters } => {
+                let mut codes = Vec::new();
+                for (filter, qos) in &filters {
+                    if valid_filter(filter) {
+                        self.filters.insert(filter.clone(), *qos.min(&1));
+                        codes.push(*qos.min(&1));
+                    } else {
+                        codes.push(SUBSCRIBE_FAILURE);
+                    }
+                }
+                self.sync_topics(StartPosition::Latest).await;
+                self.send(Packet::SubAck { packet_id, codes }).await?;
+
+                // Сохраненные сообщения подходящих топиков отправляются сразу после SUBACK
+                let retained: Vec<Publish> = {
+                    let state = self.server.state.lock().await;
+                    state
+                        .retained
+                        .values()
+                        .filter(|publish| {
+                            filters.iter().any(|(filter, _)| {
+                                valid_filter(filter) && filter_matches(filter, &publish.topic)
+                            })
+                        })
+                        .cloned()
+                        .collect()
+                };
+                for mut publish in retained {
+                    publish.qos = publish.qos.min(self.qos_for(&publish.topic));
+                    if publish.qos > 0 {
+                        publish.packet_id = Some(self.allocate(None)?);
+                    }
+                    self.send(Packet::Publish(publish)).await?;
+                }
+                Ok(())
+            }
+            Packet::Unsubscribe { packet_id, filters } => {
+                for filter in &filters {
+                    self.filters.remove(filter);
+                }
+                self.sync_topics(StartPosition::Latest).await;
+                self.send(Packet::UnsubAck(packet_id)).await
+            }
+            Packet::PingReq => self.send(Packet::PingResp).await,
+            Packet::Connect(_) => Err("Повторный CONNECT".into()),
+            other => Err(format!("Неожиданный пакет от клиента: {:?}", other)),
+        }
+    }
+
+    // Наибольший QoS среди фильтров, подходящих топику
+    fn qos_for(&self, topic: &str) -> u8 {
+        self.filters
+            .iter()
+            .filter(|(filter, _)| filter_matches(filter, topic))
+            .map(|(_, qos)| *qos)
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Подписка на топики под текущими фильтрами и отписка от остальных,
+    // без clean session подписки постоянные
+    async fn sync_topics(&mut self, from: StartPosition) {
+        let filters = &self.filters;
+        let wanted = |topic: &str| filters.keys().any(|filter| filter_matches(filter, topic));
+        let durable = !self.clean_session;
+        let changes = self
+            .sessions
+            .sync(&self.server.broker, &self.client_id, wanted, from, durable)
+            .await;
+        for topic in changes.removed {
+            self.topics.remove(&topic);
+        }
+        for topic in changes.added {
+            self.topics.insert(topic, 0);
+        }
+        // QoS доставки - наибольший из подходящих фильтров, они могли измениться
+        let topics: Vec<String> = self.topics.keys().cloned().collect();
+        for topic in topics {
+            let qos = self.qos_for(&topic);
+            self.topics.insert(topic, qos);
+        }
+    }
+
+    fn allocate(&mut self, message: Option<(String, String)>) -> Result<u16, String> {
+        if self.inflight.len() >= u16::MAX as usize {
+            return Err("Закончились идентификаторы пакетов".into());
+        }
+        loop {
+            self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+            if !self.inflight.contains_key(&self.next_packet_id) {
+                self.inflight.insert(self.next_packet_id, message);
+                return Ok(self.next_packet_id);
+            }
+        }
+    }
+
+    // Доставка сообщения топика. QoS - меньший из выданного подписке и QoS
+    // публикации; если сообщение ждет подтверждения, а клиент получает его
+    // с QoS 0, подтверждаем сразу
+    async fn deliver(&mut self, delivery: DeliverMessage) -> Result<(), String> {
+        let Some(&granted) = self.topics.get(&delivery.topic) else {
+            return Ok(());
+        };
+        let message = delivery.message;
+        let mut publish = Publish {
+            topic: delivery.topic,
+            payload: Bytes::from(message.payload.unwrap_or_default()),
+            qos: 0,
+            retain: false,
+            dup: false,
+            packet_id: None,
+        };
+        if message.require_ack && granted > 0 {
+            let key = Some((publish.topic.clone(), message.id));
+            // Повторная доставка идет с тем же id пакета и флагом DUP
+            let resent = self
+                .inflight
+                .iter()
+                .find(|(_, pending)| **pending == key)
+                .map(|(packet_id, _)| *packet_id);
+            publish.dup = resent.is_some();
+            publish.qos = 1;
+            publish.packet_id = Some(match resent {
+                Some(packet_id) => packet_id,
+                None => self.allocate(key)?,
+            });
+        } else if message.require_ack {
+            let broker = self.server.broker.lock().await;
+            let _ = broker.acknowledge(&publish.topic, self.client_id.clone(), message.id);
+        }
+        self.send(Packet::Publish(publish)).await
+    }
+
+    // Завершение соединения: сессия останавливается сразу, чтобы следующее
+    // подключение с тем же id могло подписаться, сессия без clean session
+    // сохраняется, без DISCONNECT публикуется последняя воля
+    async fn close(mut self, graceful: bool, will: Option<Publish>) {
+        let _ = self.sink.close().await;
+        let _ = timeout(TAKEOVER_TIMEOUT, self.sessions.close_all()).await;
+
+        {
+            let mut state = self.server.state.lock().await;
+            if state
+                .connections
+                .get(&self.client_id)
+                .is_some_and(|running| running.id == self.id)
+            {
+                state.connections.remove(&self.client_id);
+            }
+            if !self.clean_session {
+                let session = StoredSession {
+                    filters: std::mem::take(&mut self.filters),
+                    topics: std::mem::take(&mut self.topics),
+                };
+                state.sessions.insert(self.client_id.clone(), session);
+            }
+        }
+        if let (false, Some(will)) = (graceful, will) {
+            if let Err(error) = self.server.publish(&will).await {
+                println!(
+                    "Последняя воля {} не опубликована: {}",
+                    self.client_id, error
+                );
+            }
+        }
+        if let Some(tx) = self.closed.take() {
+            let _ = tx.send(());
+        }
+        println!("Клиент отключился от MQTT - {}", self.client_id);
+    }
+}
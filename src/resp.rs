@@ -0,0 +1,633 @@
+use crate::{
+    broker::Broker,
+    client::TopicSessions,
+    message::Message,
+    topic::{DeliverMessage, StartPosition, TopicConfig},
+};
+use actix_codec::{Decoder, Encoder, Framed};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::interval;
+use actix_web::web::{BufMut, Bytes, BytesMut};
+use futures::{
+    lock::Mutex,
+    stream::{self, LocalBoxStream, SplitSink},
+    SinkExt, StreamExt,
+};
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Совместимость с pub/sub Redis для существующих инструментов.
+//
+// Протокол RESP2, команды PUBLISH, SUBSCRIBE, UNSUBSCRIBE, PSUBSCRIBE,
+// PUNSUBSCRIBE, PING и QUIT. Канал - это топик брокера с тем же именем,
+// шаблон (glob, как в Redis) подписывает на все подходящие топики, в том числе
+// созданные позже. Сообщения с подтверждением подтверждаются при доставке:
+// в pub/sub Redis подтверждений нет.
+
+// Команда или ответ больше этого размера считается ошибкой
+pub const MAX_VALUE_SIZE: usize = 16 * 1024 * 1024;
+// Столько элементов может быть в массиве
+pub const MAX_ARRAY_LEN: usize = 1024 * 1024;
+// Как часто ищем появившиеся топики для каналов и шаблонов
+const RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+// Настройки слушателя
+#[derive(Debug, Clone)]
+pub struct RespOptions {
+    // Создавать топик с настройками по умолчанию при PUBLISH и SUBSCRIBE,
+    // иначе публикация в несуществующий канал никому не доставляется
+    pub auto_create: bool,
+}
+
+impl Default for RespOptions {
+    fn default() -> Self {
+        RespOptions { auto_create: true }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    // None - nil
+    Bulk(Option<Bytes>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn bulk(value: impl Into<Bytes>) -> Self {
+        Value::Bulk(Some(value.into()))
+    }
+
+    // Команда клиента: массив строк
+    pub fn command(args: &[&str]) -> Self {
+        Value::Array(
+            args.iter()
+                .map(|arg| Value::bulk(arg.to_string()))
+                .collect(),
+        )
+    }
+}
+
+fn invalid(error: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.into())
+}
+
+// Разбор и запись значений RESP. Строка без префикса типа - inline-команда,
+// как ее отправляют telnet и redis-cli. Команды - плоские массивы,
+// вложенные массивы не принимаются
+#[derive(Default)]
+pub struct RespCodec {
+    // Массив, который пришел не целиком: разобранные элементы уже удалены
+    // из буфера, и разбор продолжается со следующего
+    array: Option<PartialArray>,
+}
+
+struct PartialArray {
+    len: usize,
+    items: Vec<Value>,
+    // Байт массива, уже разобранных
+    size: usize,
+}
+
+// Позиция конца строки, начиная с from
+fn line_end(src: &[u8], from: usize) -> Option<usize> {
+    src[from..]
+        .windows(2)
+        .position(|w| w == b"\r\n")
+        .map(|i| from + i)
+}
+
+fn parse_int(line: &[u8]) -> io::Result<i64> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|line| line.parse().ok())
+        .ok_or_else(|| invalid("Неверное число"))
+}
+
+// Разбор значения в начале буфера, кроме массива. Возвращает значение
+// и его длину, inline-команда разбирается, только если разрешена
+fn parse(src: &[u8], inline: bool) -> io::Result<Option<(Value, usize)>> {
+    let Some(end) = line_end(src, 0) else {
+        if src.len() > MAX_VALUE_SIZE {
+            return Err(invalid("Слишком длинная строка"));
+        }
+        return Ok(None);
+    };
+    let line = &src[1..end];
+    let next = end + 2;
+    let text = || String::from_utf8_lossy(line).into_owned();
+    let value = match src[0] {
+        b'+' => (Value::Simple(text()), next),
+        b'-' => (Value::Error(text()), next),
+        b':' => (Value::Integer(parse_int(line)?), next),
+        b'$' => {
+            let len = parse_int(line)?;
+            if len < 0 {
+                return Ok(Some((Value::Bulk(None), next)));
+            }
+            let len = len as usize;
+            if len > MAX_VALUE_SIZE {
+                return Err(invalid(format!("Слишком длинная строка: {}", len)));
+            }
+            if src.len() < next + len + 2 {
+                return Ok(None);
+            }
+            if &src[next + len..next + len + 2] != b"\r\n" {
+                return Err(invalid("Строка не завершена CRLF"));
+            }
+            let value = Bytes::copy_from_slice(&src[next..next + len]);
+            (Value::Bulk(Some(value)), next + len + 2)
+        }
+        b'*' => return Err(invalid("Вложенные массивы не поддерживаются")),
+        _ if !inline => return Err(invalid("Неверный тип элемента массива")),
+        _ => {
+            let line = &src[..end];
+            let args = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| Value::bulk(Bytes::copy_from_slice(arg)))
+                .collect();
+            (Value::Array(args), next)
+        }
+    };
+    Ok(Some(value))
+}
+
+impl Decoder for RespCodec {
+    type Item = Value;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Value>> {
+        let array = match &mut self.array {
+            Some(array) => array,
+            None => {
+                if src.is_empty() {
+                    return Ok(None);
+                }
+                if src[0] != b'*' {
+                    let Some((value, len)) = parse(src, true)? else {
+                        return Ok(None);
+                    };
+                    let _ = src.split_to(len);
+                    return Ok(Some(value));
+                }
+                // Заголовок массива: число элементов
+                let Some(end) = line_end(src, 0) else {
+                    if src.len() > MAX_VALUE_SIZE {
+                        return Err(invalid("Слишком длинная строка"));
+                    }
+                    return Ok(None);
+                };
+                let count = parse_int(&src[1..end])?;
+                let _ = src.split_to(end + 2);
+                if count <= 0 {
+                    return Ok(Some(Value::Array(Vec::new())));
+                }
+                if count as u64 > MAX_ARRAY_LEN as u64 {
+                    return Err(invalid(format!("Слишком много элементов: {}", count)));
+                }
+                self.array.insert(PartialArray {
+                    len: count as usize,
+                    items: Vec::new(),
+                    size: end + 2,
+                })
+            }
+        };
+        while array.items.len() < array.len {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            let Some((item, len)) = parse(src, false)? else {
+                return Ok(None);
+            };
+            array.size += len;
+            if array.size > MAX_VALUE_SIZE {
+                return Err(invalid("Слишком большой массив"));
+            }
+            let _ = src.split_to(len);
+            array.items.push(item);
+        }
+        let items = self
+            .array
+            .take()
+            .map(|array| array.items)
+            .unwrap_or_default();
+        Ok(Some(Value::Array(items)))
+    }
+}
+
+impl Encoder<Value> for RespCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, value: Value, dst: &mut BytesMut) -> io::Result<()> {
+        match value {
+            Value::Simple(text) => dst.put_slice(format!("+{}\r\n", text).as_bytes()),
+            Value::Error(text) => dst.put_slice(format!("-{}\r\n", text).as_bytes()),
+            Value::Integer(value) => dst.put_slice(format!(":{}\r\n", value).as_bytes()),
+            Value::Bulk(None) => dst.put_slice(b"$-1\r\n"),
+            Value::Bulk(Some(value)) => {
+                dst.put_slice(format!("${}\r\n", value.len()).as_bytes());
+                dst.put_slice(&value);
+                dst.put_slice(b"\r\n");
+            }
+            Value::Array(items) => {
+                dst.put_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    self.encode(item, dst)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Шаблон как в PSUBSCRIBE Redis: * - любая строка, ? - один символ,
+// [abc], [^a], [a-z] - символ из набора, \ экранирует следующий символ
+pub fn glob_matches(pattern: &[u8], text: &[u8]) -> bool {
+    // Перебор без рекурсии: при несовпадении возвращаемся только к последней *,
+    // которая забирает на один символ больше, поэтому время не больше O(n * m)
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+            continue;
+        }
+        if let Some(len) = glob_token(&pattern[p..], text[t]) {
+            p += len;
+            t += 1;
+            continue;
+        }
+        let Some((after_star, skipped)) = star else {
+            return false;
+        };
+        p = after_star;
+        t = skipped + 1;
+        star = Some((after_star, t));
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Длина первого элемента шаблона (не *), если он совпадает с символом c
+fn glob_token(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern {
+        [] => None,
+        [b'?', ..] => Some(1),
+        [b'[', rest @ ..] => {
+            let (negate, mut set) = match rest.split_first() {
+                Some((b'^', set)) => (true, set),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match set {
+                    [] => return None,
+                    [b']', after @ ..] => {
+                        set = after;
+                        break;
+                    }
+                    [b'\\', escaped, after @ ..] => {
+                        matched |= *escaped == c;
+                        set = after;
+                    }
+                    [from, b'-', to, after @ ..] if *to != b']' => {
+                        let (from, to) = (*from.min(to), *from.max(to));
+                        matched |= (from..=to).contains(&c);
+                        set = after;
+                    }
+                    [other, after @ ..] => {
+                        matched |= *other == c;
+                        set = after;
+                    }
+                }
+            }
+            (matched != negate).then_some(pattern.len() - set.len())
+        }
+        [b'\\', escaped, ..] => (*escaped == c).then_some(2),
+        [other, ..] => (*other == c).then_some(1),
+    }
+}
+
+// Прием соединений
+pub async fn serve(
+    listener: TcpListener,
+    broker: Arc<Mutex<Broker>>,
+    options: RespOptions,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("Клиент подключился по RESP - {}", peer);
+        actix_web::rt::spawn(handle_connection(stream, broker.clone(), options.clone()));
+    }
+}
+
+enum Event {
+    // None - клиент закрыл соединение
+    Command(Option<io::Result<Value>>),
+    Deliver(DeliverMessage),
+    Tick,
+}
+
+async fn handle_connection(stream: TcpStream, broker: Arc<Mutex<Broker>>, options: RespOptions) {
+    let (sink, commands) = Framed::new(stream, RespCodec::default()).split();
+    let (sessions, deliveries) = TopicSessions::new();
+    let mut connection = Connection {
+        broker,
+        options,
+        client_id: Uuid::new_v4().to_string(),
+        sessions,
+        sink,
+        channels: BTreeSet::new(),
+        patterns: BTreeSet::new(),
+    };
+
+    let mut events: LocalBoxStream<'static, Event> = stream::select_all([
+        commands
+            .map(|command| Event::Command(Some(command)))
+            .chain(stream::once(async { Event::Command(None) }))
+            .boxed_local(),
+        deliveries.map(Event::Deliver).boxed_local(),
+        stream::unfold(interval(RESCAN_INTERVAL), |mut ticks| async move {
+            ticks.tick().await;
+            Some((Event::Tick, ticks))
+        })
+        .boxed_local(),
+    ])
+    .boxed_local();
+
+    while let Some(event) = events.next().await {
+        let result = match event {
+            Event::Command(Some(Ok(command))) => connection.handle(command).await,
+            Event::Command(Some(Err(e))) => {
+                let reply = Value::Error(format!("ERR Protocol error: {}", e));
+                let _ = connection.sink.send(reply).await;
+                Ok(false)
+            }
+            Event::Command(None) => Ok(false),
+            Event::Deliver(delivery) => connection.deliver(delivery).await.map(|_| true),
+            Event::Tick => {
+                if connection.subscribed() > 0 {
+                    connection.sync_topics(StartPosition::Earliest).await;
+                }
+                Ok(true)
+            }
+        };
+        if !matches!(result, Ok(true)) {
+            break;
+        }
+    }
+
+    // Подписки удаляются сразу, не дожидаясь проверки отключившихся клиентов
+    let broker = connection.broker.lock().await;
+    for topic in connection.sessions.topics() {
+        let _ = broker.unsubscribe(topic, connection.client_id.clone());
+    }
+    println!("Клиент отключился от RESP - {}", connection.client_id);
+}
+
+// Соединение: один id клиента на все каналы и шаблоны, у каждого топика своя сессия
+struct Connection {
+    broker: Arc<Mutex<Broker>>,
+    options: RespOptions,
+    client_id: String,
+    sessions: TopicSessions,
+    sink: SplitSink<Framed<TcpStream, RespCodec>, Value>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Connection {
+    fn subscribed(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    async fn send(&mut self, value: Value) -> Result<(), String> {
+        self.sink.send(value).await.map_err(|e| e.to_string())
+    }
+
+    // Выполнение команды, false - соединение закрывается
+    async fn handle(&mut self, command: Value) -> Result<bool, String> {
+        let args = match command {
+            Value::Array(args) if !args.is_empty() => args,
+            Value::Array(_) => return Ok(true),
+            _ => {
+                let error = Value::Error("ERR Protocol error: expected array".into());
+                self.send(error).await?;
+                return Ok(false);
+            }
+        };
+        let mut strings = Vec::new();
+        for arg in args {
+            match arg {
+                Value::Bulk(Some(arg)) => strings.push(arg),
+                _ => {
+                    let error = Value::Error("ERR Protocol error: expected bulk string".into());
+                    self.send(error).await?;
+                    return Ok(false);
+                }
+            }
+        }
+        let name = String::from_utf8_lossy(&strings[0]).to_uppercase();
+        let args = &strings[1..];
+
+        // В режиме подписки доступны только команды подписки, PING и QUIT
+        let subscriber_command = matches!(
+            name.as_str(),
+            "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "PING" | "QUIT"
+        );
+        if self.subscribed() > 0 && !subscriber_command {
+            let error = format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+                name.to_lowercase()
+            );
+            self.send(Value::Error(error)).await?;
+            return Ok(true);
+        }
+
+        let arity = match name.as_str() {
+            "PUBLISH" => args.len() == 2,
+            "SUBSCRIBE" | "PSUBSCRIBE" => !args.is_empty(),
+            "PING" => args.len() <= 1,
+            _ => true,
+        };
+        if !arity {
+            let error = format!(
+                "ERR wrong number of arguments for '{}' command",
+                name.to_lowercase()
+            );
+            self.send(Value::Error(error)).await?;
+            return Ok(true);
+        }
+
+        let args: Result<Vec<String>, _> = args
+            .iter()
+            .map(|arg| String::from_utf8(arg.to_vec()))
+            .collect();
+        let Ok(args) = args else {
+            self.send(Value::Error("ERR arguments must be UTF-8".into()))
+                .await?;
+            return Ok(true);
+        };
+
+        match name.as_str() {
+            "PING" if self.subscribed() > 0 => {
+                let message = args.first().cloned().unwrap_or_default();
+                let reply = Value::Array(vec![Value::bulk("pong"), Value::bulk(message)]);
+                self.send(reply).await?;
+            }
+            "PING" => {
+                let reply = match args.first() {
+                    Some(message) => Value::bulk(message.clone()),
+                    None => Value::Simple("PONG".into()),
+                };
+                self.send(reply).await?;
+            }
+            "QUIT" => {
+                self.send(Value::Simple("OK".into())).await?;
+                return Ok(false);
+            }
+            "PUBLISH" => {
+                let reply = match self.publish(&args[0], &args[1]).await {
+                    Ok(receivers) => Value::Integer(receivers as i64),
+                    Err(error) => Value::Error(format!("ERR {}", error)),
+                };
+                self.send(reply).await?;
+            }
+            "SUBSCRIBE" => {
+                for channel in &args {
+                    if self.options.auto_create {
+                        self.create_topic(channel).await?;
+                    }
+                    self.channels.insert(channel.clone());
+                }
+                self.sync_topics(StartPosition::Latest).await;
+                for channel in args {
+                    self.confirm("subscribe", Some(channel)).await?;
+                }
+            }
+            "PSUBSCRIBE" => {
+                self.patterns.extend(args.iter().cloned());
+                self.sync_topics(StartPosition::Latest).await;
+                for pattern in args {
+                    self.confirm("psubscribe", Some(pattern)).await?;
+                }
+            }
+            "UNSUBSCRIBE" | "PUNSUBSCRIBE" => {
+                let (kind, subscriptions) = match name.as_str() {
+                    "UNSUBSCRIBE" => ("unsubscribe", &mut self.channels),
+                    _ => ("punsubscribe", &mut self.patterns),
+                };
+                // Без аргументов - отписка от всех
+                let removed: Vec<String> = match args.is_empty() {
+                    true => std::mem::take(subscriptions).into_iter().collect(),
+                    false => {
+                        for arg in &args {
+                            subscriptions.remove(arg);
+                        }
+                        args
+                    }
+                };
+                self.sync_topics(StartPosition::Latest).await;
+                if removed.is_empty() {
+                    self.confirm(kind, None).await?;
+                }
+                for name in removed {
+                    self.confirm(kind, Some(name)).await?;
+                }
+            }
+            other => {
+                let error = format!("ERR unknown command '{}'", other.to_lowercase());
+                self.send(Value::Error(error)).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Ответ на (p)(un)subscribe: вид, канал или шаблон и число подписок
+    async fn confirm(&mut self, kind: &str, name: Option<String>) -> Result<(), String> {
+        let reply = Value::Array(vec![
+            Value::bulk(kind.to_string()),
+            Value::Bulk(name.map(Bytes::from)),
+            Value::Integer(self.subscribed() as i64),
+        ]);
+        self.send(reply).await
+    }
+
+    async fn create_topic(&self, name: &str) -> Result<(), String> {
+        let mut broker = self.broker.lock().await;
+        if !broker.has_topic(name) {
+            broker.create_topic(name.to_string(), TopicConfig::default())?;
+        }
+        Ok(())
+    }
+
+    // Публикация, возвращает число подписчиков топика
+    async fn publish(&self, channel: &str, payload: &str) -> Result<usize, String> {
+        if self.options.auto_create {
+            self.create_topic(channel).await?;
+        }
+        let message = Message::new(payload.to_string(), None, false);
+        let (publishing, receivers) = {
+            let broker = self.broker.lock().await;
+            if !broker.has_topic(channel) {
+                return Ok(0);
+            }
+            let receivers = broker.describe_topic(channel).await?.subscriber_count;
+            (broker.publish_message(channel, message), receivers)
+        };
+        publishing.await?;
+        Ok(receivers)
+    }
+
+    // Подписка на топики каналов и шаблонов и отписка от остальных
+    async fn sync_topics(&mut self, from: StartPosition) {
+        let (channels, patterns) = (&self.channels, &self.patterns);
+        let wanted = |topic: &str| {
+            channels.contains(topic)
+                || patterns
+                    .iter()
+                    .any(|pattern| glob_matches(pattern.as_bytes(), topic.as_bytes()))
+        };
+        self.sessions
+            .sync(&self.broker, &self.client_id, wanted, from, false)
+            .await;
+    }
+
+    // Сообщение приходит один раз на канал и по разу на каждый подходящий шаблон
+    async fn deliver(&mut self, delivery: DeliverMessage) -> Result<(), String> {
+        let DeliverMessage { topic, message } = delivery;
+        if message.require_ack {
+            let broker = self.broker.lock().await;
+            let _ = broker.acknowledge(&topic, self.client_id.clone(), message.id.clone());
+        }
+        let payload = Bytes::from(message.payload.unwrap_or_default());
+        let mut replies = Vec::new();
+        if self.channels.contains(&topic) {
+            replies.push(Value::Array(vec![
+                Value::bulk("message"),
+                Value::bulk(topic.clone()),
+                Value::Bulk(Some(payload.clone())),
+            ]));
+        }
+        for pattern in &self.patterns {
+            if glob_matches(pattern.as_bytes(), topic.as_bytes()) {
+                replies.push(Value::Array(vec![
+                    Value::bulk("pmessage"),
+                    Value::bulk(pattern.clone()),
+                    Value::bulk(topic.clone()),
+                    Value::Bulk(Some(payload.clone())),
+                ]));
+            }
+        }
+        for reply in replies {
+            self.send(reply).await?;
+        }
+        Ok(())
+    }
+}
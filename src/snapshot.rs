@@ -0,0 +1,63 @@
+use crate::message::Message;
+use crate::topic::TopicConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Снимок всего брокера
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BrokerSnapshot {
+    // Время создания снимка в миллисекундах от UNIX_EPOCH
+    pub created_at: u64,
+    pub topics: Vec<TopicSnapshot>,
+}
+
+// Снимок одного топика
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TopicSnapshot {
+    pub name: String,
+    pub config: TopicConfig,
+    // Offset, который получит следующее сообщение
+    pub next_offset: u64,
+    // Сохраненные сообщения, для компактного топика - последние по ключам
+    pub messages: Vec<Message>,
+    // Позиции групп потребителей
+    pub group_offsets: HashMap<String, u64>,
+    // Позиции постоянных подписок, в старых снимках их нет
+    #[serde(default)]
+    pub durables: HashMap<String, u64>,
+    // Сообщения, ожидающие подтверждения
+    pub pending_acks: Vec<PendingSnapshot>,
+}
+
+// Сообщение, ожидающее подтверждения, на момент снимка
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PendingSnapshot {
+    pub message: Message,
+    pub client_ids: Vec<String>,
+    pub attempts: u32,
+    // Причина последней неудачной доставки, в старых снимках ее нет
+    #[serde(default)]
+    pub last_failure: Option<String>,
+    // Группа fetch, выдавшая сообщение, и ждет ли оно следующего запроса группы
+    #[serde(default)]
+    pub fetch_group: Option<String>,
+    #[serde(default)]
+    pub refetch: bool,
+}
+
+impl BrokerSnapshot {
+    // Запись снимка в файл, через временный файл, чтобы не оставить его наполовину
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(self)?)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        fs::rename(tmp, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<BrokerSnapshot> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+}
@@ -0,0 +1,610 @@
+use crate::{
+    broker::Broker,
+    buffer::SubscriberBuffer,
+    client::{ClientSession, CloseSession, HEARTBEAT_INTERVAL},
+    message::Message,
+    topic::{DeliverMessage, NackAction, StartPosition, Subscribe},
+};
+use actix::prelude::*;
+use actix_codec::{Decoder, Encoder, Framed};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::timeout;
+use actix_web::web::{BufMut, Bytes, BytesMut};
+use futures::{
+    future,
+    lock::Mutex,
+    stream::{self, LocalBoxStream, SelectAll, SplitSink},
+    SinkExt, StreamExt,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+// STOMP 1.2 поверх TCP для готовых клиентов на JavaScript и Python.
+//
+// Destination - это имя топика брокера. Каждая подписка STOMP - отдельный
+// подписчик топика с id "<id соединения>:<id подписки>", поэтому подписки
+// одного соединения на один топик получают сообщения независимо.
+// Режим ack=auto подтверждает сообщение при доставке, client и
+// client-individual передают ACK и NACK клиента в ожидающие подтверждения
+// топика: client подтверждает все сообщения подписки до указанного включительно.
+
+// Кадр больше этого размера считается ошибкой
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+// Версия протокола
+pub const VERSION: &str = "1.2";
+// Столько ждем CONNECT после подключения
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Frame {
+    // Пустая команда - heartbeat (перевод строки между кадрами)
+    pub command: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Bytes,
+}
+
+impl Frame {
+    pub fn new(command: &str) -> Self {
+        Frame {
+            command: command.into(),
+            ..Frame::default()
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Bytes>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // При повторе заголовка действует первое значение
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn require(&self, name: &str) -> Result<&str, String> {
+        self.get(name)
+            .ok_or_else(|| format!("В кадре {} нет заголовка {}", self.command, name))
+    }
+
+    fn heartbeat() -> Self {
+        Frame::default()
+    }
+}
+
+fn invalid(error: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.into())
+}
+
+// В CONNECT и CONNECTED заголовки не экранируются
+fn escaped(command: &str) -> bool {
+    command != "CONNECT" && command != "CONNECTED"
+}
+
+fn unescape(value: &str) -> io::Result<String> {
+    let mut result = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('r') => result.push('\r'),
+            Some('n') => result.push('\n'),
+            Some('c') => result.push(':'),
+            Some('\\') => result.push('\\'),
+            _ => return Err(invalid("Неверная escape-последовательность в заголовке")),
+        }
+    }
+    Ok(result)
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n")
+        .replace(':', "\\c")
+}
+
+// Разбор и запись кадров STOMP
+pub struct StompCodec;
+
+impl Decoder for StompCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        // Переводы строк между кадрами - heartbeat
+        if src.starts_with(b"\r\n") {
+            let _ = src.split_to(2);
+            return Ok(Some(Frame::heartbeat()));
+        }
+        if src.starts_with(b"\n") {
+            let _ = src.split_to(1);
+            return Ok(Some(Frame::heartbeat()));
+        }
+
+        // Команда и заголовки до пустой строки
+        let lf = src.windows(2).position(|w| w == b"\n\n");
+        let crlf = src
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|pos| pos + 1);
+        let Some(head_end) = lf.into_iter().chain(crlf).min() else {
+            if src.len() > MAX_FRAME_SIZE {
+                return Err(invalid("Слишком большой кадр"));
+            }
+            return Ok(None);
+        };
+        // Пустая строка может быть из \n\n или \r\n\r\n
+        let body_start = if src[head_end..].starts_with(b"\n\n") {
+            head_end + 2
+        } else {
+            head_end + 3
+        };
+        let head = std::str::from_utf8(&src[..head_end])
+            .map_err(|_| invalid("Заголовки кадра не в UTF-8"))?;
+        let mut lines = head.split('\n').map(|line| line.trim_end_matches('\r'));
+        let command = lines.next().unwrap_or_default().to_string();
+        let mut headers = Vec::new();
+        for line in lines {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| invalid(format!("Неверный заголовок: {}", line)))?;
+            match escaped(&command) {
+                true => headers.push((unescape(name)?, unescape(value)?)),
+                false => headers.push((name.to_string(), value.to_string())),
+            }
+        }
+
+        // Тело: content-length байт или до нулевого байта
+        let length = headers
+            .iter()
+            .find(|(name, _)| name == "content-length")
+            .map(|(_, value)| value.parse::<usize>())
+            .transpose()
+            .map_err(|_| invalid("Неверный content-length"))?;
+        let body_end = match length {
+            Some(length) if length > MAX_FRAME_SIZE => {
+                return Err(invalid(format!("Слишком большое тело кадра: {}", length)))
+            }
+            Some(length) => {
+                if src.len() <= body_start + length {
+                    return Ok(None);
+                }
+                if src[body_start + length] != 0 {
+                    return Err(invalid("Тело кадра не завершено нулевым байтом"));
+                }
+                body_start + length
+            }
+            None => match src[body_start..].iter().position(|b| *b == 0) {
+                Some(pos) => body_start + pos,
+                None if src.len() > MAX_FRAME_SIZE => {
+                    return Err(invalid("Слишком большой кадр"));
+                }
+                None => return Ok(None),
+            },
+        };
+        let frame = src.split_to(body_end + 1);
+        let body = Bytes::copy_from_slice(&frame[body_start..body_end]);
+        Ok(Some(Frame {
+            command,
+            headers,
+            body,
+        }))
+    }
+}
+
+impl Encoder<Frame> for StompCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        if frame.command.is_empty() {
+            dst.put_u8(b'\n');
+            return Ok(());
+        }
+        let escaped = escaped(&frame.command);
+        dst.put_slice(frame.command.as_bytes());
+        dst.put_u8(b'\n');
+        for (name, value) in &frame.headers {
+            if name == "content-length" {
+                continue;
+            }
+            let line = match escaped {
+                true => format!("{}:{}\n", escape(name), escape(value)),
+                false => format!("{}:{}\n", name, value),
+            };
+            dst.put_slice(line.as_bytes());
+        }
+        if !frame.body.is_empty() {
+            dst.put_slice(format!("content-length:{}\n", frame.body.len()).as_bytes());
+        }
+        dst.put_u8(b'\n');
+        dst.put_slice(&frame.body);
+        dst.put_u8(0);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AckMode {
+    Auto,
+    Client,
+    ClientIndividual,
+}
+
+struct StompSubscription {
+    destination: String,
+    ack: AckMode,
+    // Id подписчика в топике
+    client_id: String,
+    session: Addr<ClientSession>,
+    // Доставленные в режимах client: номер ack и id сообщения, если сообщение
+    // ждет подтверждения в топике
+    unacked: BTreeMap<u64, Option<String>>,
+}
+
+// Прием соединений
+pub async fn serve(listener: TcpListener, broker: Arc<Mutex<Broker>>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("Клиент подключился по STOMP - {}", peer);
+        actix_web::rt::spawn(handle_connection(stream, broker.clone()));
+    }
+}
+
+// Интервал heartbeat из заголовка клиента и нашего: 0 - без heartbeat,
+// иначе больший из двух
+fn negotiate(client: Option<&str>) -> u64 {
+    let client = client.and_then(|value| value.trim().parse::<u64>().ok());
+    match client {
+        None | Some(0) => 0,
+        Some(client) => client.max(HEARTBEAT_INTERVAL.as_millis() as u64),
+    }
+}
+
+// Проверка CONNECT, возвращает ответ CONNECTED и интервалы heartbeat в мс:
+// как часто пишет сервер и как часто должен писать клиент
+fn connect(frame: &Frame) -> Result<(Frame, u64, u64), String> {
+    if frame.command != "CONNECT" && frame.command != "STOMP" {
+        return Err("Первым кадром ожидается CONNECT".into());
+    }
+    let versions = frame.get("accept-version").unwrap_or("1.0");
+    if !versions.split(',').any(|version| version.trim() == VERSION) {
+        return Err(format!("Поддерживается только версия STOMP {}", VERSION));
+    }
+    let (client_sends, client_wants) = frame
+        .get("heart-beat")
+        .and_then(|value| value.split_once(','))
+        .unwrap_or(("0", "0"));
+    let outgoing = negotiate(Some(client_wants));
+    let incoming = negotiate(Some(client_sends));
+    let connected = Frame::new("CONNECTED")
+        .header("version", VERSION)
+        .header("heart-beat", format!("{},{}", outgoing, incoming))
+        .header("server", "mem_broker");
+    Ok((connected, outgoing, incoming))
+}
+
+async fn handle_connection(stream: TcpStream, broker: Arc<Mutex<Broker>>) {
+    let (mut sink, mut frames) = Framed::new(stream, StompCodec).split();
+    let first = loop {
+        match timeout(CONNECT_TIMEOUT, frames.next()).await {
+            Ok(Some(Ok(frame))) if frame.command.is_empty() => continue,
+            Ok(Some(Ok(frame))) => break frame,
+            _ => return,
+        }
+    };
+    let (connected, outgoing, incoming) = match connect(&first) {
+        Ok(accepted) => accepted,
+        Err(error) => {
+            let reply = Frame::new("ERROR")
+                .header("version", VERSION)
+                .header("message", error);
+            let _ = sink.send(reply).await;
+            return;
+        }
+    };
+    if sink.send(connected).await.is_err() {
+        return;
+    }
+
+    let mut connection = Connection {
+        broker,
+        client_id: Uuid::new_v4().to_string(),
+        sink,
+        subscriptions: HashMap::new(),
+        acks: HashMap::new(),
+        next_ack: 0,
+        last_write: Instant::now(),
+    };
+    // В наборе всегда есть бесконечный поток, поэтому он не заканчивается
+    // без подписок
+    let mut deliveries: SelectAll<LocalBoxStream<'static, (String, DeliverMessage)>> =
+        stream::select_all([stream::pending().boxed_local()]);
+    let outgoing = Duration::from_millis(outgoing);
+    let incoming = Duration::from_millis(incoming);
+    let mut last_read = Instant::now();
+
+    let result: Result<(), String> = loop {
+        // Клиент, обещавший heartbeat, должен что-то прислать за два интервала
+        if !incoming.is_zero() && last_read.elapsed() > incoming * 2 {
+            break Err("Нет heartbeat от клиента".into());
+        }
+        let wait = match outgoing.is_zero() {
+            true => Duration::from_secs(1),
+            false => outgoing.saturating_sub(connection.last_write.elapsed()),
+        };
+        let next = timeout(wait, future::select(frames.next(), deliveries.next())).await;
+        let handled = match next {
+            Ok(future::Either::Left((Some(Ok(frame)), _))) => {
+                last_read = Instant::now();
+                if frame.command.is_empty() {
+                    continue;
+                }
+                let receipt = frame.get("receipt").map(str::to_string);
+                let disconnect = frame.command == "DISCONNECT";
+                match connection.handle(frame, &mut deliveries).await {
+                    Ok(()) => {
+                        if let Some(receipt) = receipt {
+                            let reply = Frame::new("RECEIPT").header("receipt-id", receipt);
+                            if let Err(error) = connection.send(reply).await {
+                                break Err(error);
+                            }
+                        }
+                        if disconnect {
+                            break Ok(());
+                        }
+                        Ok(())
+                    }
+                    Err(error) => {
+                        let mut reply = Frame::new("ERROR").header("message", error.clone());
+                        if let Some(receipt) = receipt {
+                            reply = reply.header("receipt-id", receipt);
+                        }
+                        let _ = connection.send(reply).await;
+                        break Err(error);
+                    }
+                }
+            }
+            Ok(future::Either::Left((Some(Err(e)), _))) => {
+                let reply = Frame::new("ERROR").header("message", e.to_string());
+                let _ = connection.send(reply).await;
+                break Err(e.to_string());
+            }
+            Ok(future::Either::Left((None, _))) => break Err("Соединение закрыто".into()),
+            Ok(future::Either::Right((Some((id, delivery)), _))) => {
+                connection.deliver(&id, delivery).await
+            }
+            // Набор подписок не заканчивается
+            Ok(future::Either::Right((None, _))) => Ok(()),
+            Err(_) if !outgoing.is_zero() && connection.last_write.elapsed() >= outgoing => {
+                connection.send(Frame::heartbeat()).await
+            }
+            Err(_) => Ok(()),
+        };
+        if let Err(error) = handled {
+            break Err(error);
+        }
+    };
+    if let Err(error) = result {
+        println!(
+            "Соединение STOMP {} закрыто: {}",
+            connection.client_id, error
+        );
+    }
+    connection.close().await;
+}
+
+// Соединение STOMP: подписки по id клиента
+struct Connection {
+    broker: Arc<Mutex<Broker>>,
+    client_id: String,
+    sink: SplitSink<Framed<TcpStream, StompCodec>, Frame>,
+    subscriptions: HashMap<String, StompSubscription>,
+    // Номер ack из MESSAGE - подписка, которой он выдан
+    acks: HashMap<u64, String>,
+    next_ack: u64,
+    // Heartbeat сервера нужен, только если он давно ничего не отправлял
+    last_write: Instant,
+}
+
+impl Connection {
+    async fn send(&mut self, frame: Frame) -> Result<(), String> {
+        self.last_write = Instant::now();
+        self.sink.send(frame).await.map_err(|e| e.to_string())
+    }
+
+    async fn handle(
+        &mut self,
+        frame: Frame,
+        deliveries: &mut SelectAll<LocalBoxStream<'static, (String, DeliverMessage)>>,
+    ) -> Result<(), String> {
+        match frame.command.as_str() {
+            "SEND" => {
+                let destination = frame.require("destination")?.to_string();
+                let payload = String::from_utf8(frame.body.to_vec())
+                    .map_err(|_| "Тело кадра не в UTF-8".to_string())?;
+                let key = frame.get("key").map(str::to_string);
+                let require_ack = frame.get("require-ack") == Some("true");
+                let message = Message::new(payload, key, require_ack);
+                // Брокер отпускаем до ожидания публикации
+                let publish = self
+                    .broker
+                    .lock()
+                    .await
+                    .publish_message(&destination, message);
+                publish.await?;
+                Ok(())
+            }
+            "SUBSCRIBE" => {
+                let id = frame.require("id")?.to_string();
+                let destination = frame.require("destination")?.to_string();
+                let ack = match frame.get("ack").unwrap_or("auto") {
+                    "auto" => AckMode::Auto,
+                    "client" => AckMode::Client,
+                    "client-individual" => AckMode::ClientIndividual,
+                    other => return Err(format!("Неизвестный режим ack: {}", other)),
+                };
+                if self.subscriptions.contains_key(&id) {
+                    return Err(format!("Подписка {} уже есть", id));
+                }
+                let client_id = format!("{}:{}", self.client_id, id);
+                let buffer = SubscriberBuffer::new();
+                let session = ClientSession::new(buffer.clone()).start();
+                let subscription = Subscribe {
+                    client_id: client_id.clone(),
+                    addr: session.clone().recipient(),
+                    from: StartPosition::Latest,
+                    group: None,
+                    buffer: Some(buffer.clone()),
+                    durable: false,
+                };
+                self.broker
+                    .lock()
+                    .await
+                    .subscribe(&destination, subscription)
+                    .await?;
+                let subscription_id = id.clone();
+                deliveries.push(
+                    buffer
+                        .stream()
+                        .map(move |delivery| (subscription_id.clone(), delivery))
+                        .boxed_local(),
+                );
+                self.subscriptions.insert(
+                    id,
+                    StompSubscription {
+                        destination,
+                        ack,
+                        client_id,
+                        session,
+                        unacked: BTreeMap::new(),
+                    },
+                );
+                Ok(())
+            }
+            "UNSUBSCRIBE" => {
+                let id = frame.require("id")?;
+                let subscription = self
+                    .subscriptions
+                    .remove(id)
+                    .ok_or_else(|| format!("Подписки {} нет", id))?;
+                self.acks.retain(|_, owner| owner != id);
+                self.unsubscribe(subscription).await;
+                Ok(())
+            }
+            "ACK" | "NACK" => {
+                let ack: u64 = frame
+                    .require("id")?
+                    .parse()
+                    .map_err(|_| "Неверный id подтверждения".to_string())?;
+                // Неизвестный id - подписка уже закрыта или сообщение уже подтверждено
+                let Some(id) = self.acks.get(&ack).cloned() else {
+                    return Ok(());
+                };
+                let Some(subscription) = self.subscriptions.get_mut(&id) else {
+                    return Ok(());
+                };
+                let acked: Vec<u64> = match subscription.ack {
+                    AckMode::Client => subscription
+                        .unacked
+                        .range(..=ack)
+                        .map(|(n, _)| *n)
+                        .collect(),
+                    _ => vec![ack],
+                };
+                let mut message_ids = Vec::new();
+                for n in acked {
+                    self.acks.remove(&n);
+                    if let Some(Some(message_id)) = subscription.unacked.remove(&n) {
+                        message_ids.push(message_id);
+                    }
+                }
+                let broker = self.broker.lock().await;
+                for message_id in message_ids {
+                    let client_id = subscription.client_id.clone();
+                    let destination = &subscription.destination;
+                    match frame.command.as_str() {
+                        "ACK" => broker.acknowledge(destination, client_id, message_id)?,
+                        _ => broker.nack(
+                            destination,
+                            client_id,
+                            message_id,
+                            NackAction::Requeue,
+                            None,
+                        )?,
+                    }
+                }
+                Ok(())
+            }
+            "DISCONNECT" => Ok(()),
+            "BEGIN" | "COMMIT" | "ABORT" => Err("Транзакции не поддерживаются".into()),
+            "CONNECT" | "STOMP" => Err("Соединение уже установлено".into()),
+            other => Err(format!("Неизвестная команда: {}", other)),
+        }
+    }
+
+    // Доставка в подписку: в режиме auto сообщение сразу подтверждается
+    async fn deliver(&mut self, id: &str, delivery: DeliverMessage) -> Result<(), String> {
+        let Some(subscription) = self.subscriptions.get_mut(id) else {
+            return Ok(());
+        };
+        let message = delivery.message;
+        let mut frame = Frame::new("MESSAGE")
+            .header("subscription", id)
+            .header("message-id", message.id.clone())
+            .header("destination", delivery.topic.clone())
+            .header("content-type", "text/plain");
+        if let Some(key) = &message.key {
+            frame = frame.header("key", key.clone());
+        }
+        match subscription.ack {
+            AckMode::Auto => {
+                if message.require_ack {
+                    let broker = self.broker.lock().await;
+                    let client_id = subscription.client_id.clone();
+                    let _ = broker.acknowledge(&delivery.topic, client_id, message.id.clone());
+                }
+            }
+            AckMode::Client | AckMode::ClientIndividual => {
+                self.next_ack += 1;
+                let ack = self.next_ack;
+                let pending = message.require_ack.then(|| message.id.clone());
+                subscription.unacked.insert(ack, pending);
+                self.acks.insert(ack, id.to_string());
+                frame = frame.header("ack", ack.to_string());
+            }
+        }
+        self.send(frame.body(message.payload.unwrap_or_default()))
+            .await
+    }
+
+    async fn unsubscribe(&self, subscription: StompSubscription) {
+        let broker = self.broker.lock().await;
+        let _ = broker.unsubscribe(&subscription.destination, subscription.client_id);
+        // Поток подписки заканчивается и уходит из набора доставки
+        subscription.session.do_send(CloseSession);
+    }
+
+    // Подписки удаляются сразу, не дожидаясь проверки отключившихся клиентов
+    async fn close(mut self) {
+        let _ = self.sink.close().await;
+        for (_, subscription) in std::mem::take(&mut self.subscriptions) {
+            self.unsubscribe(subscription).await;
+        }
+        println!("Клиент отключился от STOMP - {}", self.client_id);
+    }
+}
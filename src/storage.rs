@@ -0,0 +1,444 @@
+use crate::message::Message;
+use crate::wal::TopicLog;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Где хранятся сообщения топика
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageKind {
+    // В памяти, при заданном каталоге данных дополнительно пишется журнал
+    #[default]
+    Memory,
+    // На диске, в памяти остается только индекс
+    File,
+}
+
+// Параметры компакции
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompactOptions {
+    // Оставлять ли сообщения без ключа
+    pub keep_keyless: bool,
+    // Tombstone, опубликованные раньше этого времени (мс от UNIX_EPOCH),
+    // удаляются вместе с ключом
+    pub tombstones_before: u64,
+}
+
+// Хранилище сообщений топика
+pub trait TopicStorage {
+    // Добавление сообщения в конец
+    fn append(&mut self, message: Message) -> io::Result<()>;
+    // До limit сообщений начиная с offset (включительно) по возрастанию offset
+    fn read_range(&self, from: u64, limit: usize) -> io::Result<Vec<Message>>;
+    // Последнее сообщение с указанным ключом
+    fn read_key(&self, key: &str) -> io::Result<Option<Message>>;
+    // Ключи, для которых есть сообщения
+    fn keys(&self) -> Vec<String>;
+    // Удаление сообщений, опубликованных раньше указанного времени (мс от UNIX_EPOCH)
+    fn truncate_before_time(&mut self, millis: u64) -> io::Result<()>;
+    // Удаление самых старых сообщений, пока суммарный размер больше max_bytes
+    fn truncate_to_size(&mut self, max_bytes: u64) -> io::Result<()>;
+    // Удаление самых старых сообщений, пока их больше max_messages
+    fn truncate_to_len(&mut self, max_messages: usize) -> io::Result<()>;
+    // Компакция: остаются только последние сообщения для каждого ключа
+    fn compact(&mut self, options: &CompactOptions) -> io::Result<()>;
+    // Количество сохраненных сообщений
+    fn len(&self) -> usize;
+    // Суммарный размер сохраненных сообщений
+    fn size_bytes(&self) -> u64;
+    // Offset, который получит следующее сообщение
+    fn next_offset(&self) -> u64;
+    // Пропуск offset'ов, следующее сообщение получит как минимум offset
+    // (нужно при восстановлении из снимка, где хвост уже удален)
+    fn skip_to(&mut self, offset: u64) -> io::Result<()>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Хранилище в памяти, по желанию с журналом на диске для восстановления
+#[derive(Default)]
+pub struct MemoryStorage {
+    // Сообщения, используем VecDeque для быстрого доступа к началу и концу,
+    // так как мы будем удалять старые сообщения, а также добавлять новые в конец
+    messages: VecDeque<Message>,
+    // Offset последнего сообщения для каждого ключа
+    keys: HashMap<String, u64>,
+    bytes: u64,
+    next_offset: u64,
+    log: Option<TopicLog>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Хранилище с журналом, сообщения из журнала загружаются в память
+    pub fn durable(log: TopicLog, messages: Vec<Message>) -> Self {
+        let mut storage = MemoryStorage {
+            next_offset: log.next_offset(),
+            ..Default::default()
+        };
+        for message in messages {
+            storage.push(message);
+        }
+        storage.log = Some(log);
+        storage
+    }
+
+    fn push(&mut self, message: Message) {
+        if let Some(key) = &message.key {
+            self.keys.insert(key.clone(), message.offset);
+        }
+        self.bytes += message.size();
+        self.next_offset = self.next_offset.max(message.offset + 1);
+        self.messages.push_back(message);
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(message) = self.messages.pop_front() {
+            forget_key(&mut self.keys, message.key.as_deref(), message.offset);
+            self.bytes -= message.size();
+        }
+    }
+
+    fn find(&self, offset: u64) -> Option<&Message> {
+        self.messages
+            .binary_search_by_key(&offset, |m| m.offset)
+            .ok()
+            .map(|index| &self.messages[index])
+    }
+
+    // Удаление из журнала сегментов, которых больше нет в памяти
+    fn sync_log_start(&mut self) -> io::Result<()> {
+        let first = self
+            .messages
+            .front()
+            .map_or(self.next_offset, |message| message.offset);
+        match &mut self.log {
+            Some(log) => log.remove_before(first),
+            None => Ok(()),
+        }
+    }
+}
+
+impl TopicStorage for MemoryStorage {
+    fn append(&mut self, message: Message) -> io::Result<()> {
+        // Сначала журнал, чтобы в памяти не оказалось то, что не сохранилось
+        if let Some(log) = &mut self.log {
+            log.append(&message)?;
+        }
+        self.push(message);
+        Ok(())
+    }
+
+    fn read_range(&self, from: u64, limit: usize) -> io::Result<Vec<Message>> {
+        let start = self.messages.partition_point(|m| m.offset < from);
+        Ok(self.messages.range(start..).take(limit).cloned().collect())
+    }
+
+    fn read_key(&self, key: &str) -> io::Result<Option<Message>> {
+        Ok(self
+            .keys
+            .get(key)
+            .and_then(|offset| self.find(*offset))
+            .cloned())
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.keys.keys().cloned().collect()
+    }
+
+    fn truncate_before_time(&mut self, millis: u64) -> io::Result<()> {
+        while self
+            .messages
+            .front()
+            .is_some_and(|message| message.published_at < millis)
+        {
+            self.pop_front();
+        }
+        self.sync_log_start()
+    }
+
+    fn truncate_to_size(&mut self, max_bytes: u64) -> io::Result<()> {
+        while self.bytes > max_bytes && !self.messages.is_empty() {
+            self.pop_front();
+        }
+        self.sync_log_start()
+    }
+
+    fn truncate_to_len(&mut self, max_messages: usize) -> io::Result<()> {
+        while self.messages.len() > max_messages {
+            self.pop_front();
+        }
+        self.sync_log_start()
+    }
+
+    fn compact(&mut self, options: &CompactOptions) -> io::Result<()> {
+        for message in &self.messages {
+            if message.is_tombstone() && message.published_at < options.tombstones_before {
+                forget_key(&mut self.keys, message.key.as_deref(), message.offset);
+            }
+        }
+        let keys = &self.keys;
+        let before = self.messages.len();
+        self.messages
+            .retain(|message| survives(keys, options, message.key.as_deref(), message.offset));
+        if self.messages.len() != before {
+            self.bytes = self.messages.iter().map(Message::size).sum();
+        }
+        if let Some(log) = &mut self.log {
+            log.compact(|message| survives(keys, options, message.key.as_deref(), message.offset))?;
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn next_offset(&self) -> u64 {
+        self.next_offset
+    }
+
+    fn skip_to(&mut self, offset: u64) -> io::Result<()> {
+        if let Some(log) = &mut self.log {
+            log.skip_to(offset)?;
+        }
+        self.next_offset = self.next_offset.max(offset);
+        Ok(())
+    }
+}
+
+// Метаданные сообщения, которое лежит на диске
+struct Entry {
+    offset: u64,
+    published_at: u64,
+    size: u64,
+    key: Option<String>,
+    tombstone: bool,
+}
+
+// Хранилище на диске: сообщения читаются из журнала по индексу
+pub struct FileStorage {
+    log: TopicLog,
+    entries: VecDeque<Entry>,
+    // Offset последнего сообщения для каждого ключа
+    keys: HashMap<String, u64>,
+    bytes: u64,
+}
+
+impl FileStorage {
+    // Хранилище поверх журнала, в памяти остаются только метаданные сообщений
+    pub fn open(log: TopicLog, messages: Vec<Message>) -> Self {
+        let mut storage = FileStorage {
+            log,
+            entries: VecDeque::new(),
+            keys: HashMap::new(),
+            bytes: 0,
+        };
+        for message in &messages {
+            storage.push(message);
+        }
+        storage
+    }
+
+    fn push(&mut self, message: &Message) {
+        if let Some(key) = &message.key {
+            self.keys.insert(key.clone(), message.offset);
+        }
+        self.bytes += message.size();
+        self.entries.push_back(Entry {
+            offset: message.offset,
+            published_at: message.published_at,
+            size: message.size(),
+            key: message.key.clone(),
+            tombstone: message.is_tombstone(),
+        });
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(entry) = self.entries.pop_front() {
+            forget_key(&mut self.keys, entry.key.as_deref(), entry.offset);
+            self.bytes -= entry.size;
+        }
+    }
+
+    // Удаление сегментов, в которых не осталось сохраненных сообщений
+    fn sync_log_start(&mut self) -> io::Result<()> {
+        let first = self
+            .entries
+            .front()
+            .map_or(self.log.next_offset(), |entry| entry.offset);
+        self.log.remove_before(first)
+    }
+}
+
+impl TopicStorage for FileStorage {
+    fn append(&mut self, message: Message) -> io::Result<()> {
+        self.log.append(&message)?;
+        self.push(&message);
+        Ok(())
+    }
+
+    fn read_range(&self, from: u64, limit: usize) -> io::Result<Vec<Message>> {
+        let start = self.entries.partition_point(|entry| entry.offset < from);
+        let mut messages = Vec::new();
+        for entry in self.entries.range(start..).take(limit) {
+            if let Some(message) = self.log.read(entry.offset)? {
+                messages.push(message);
+            }
+        }
+        Ok(messages)
+    }
+
+    fn read_key(&self, key: &str) -> io::Result<Option<Message>> {
+        match self.keys.get(key) {
+            Some(offset) => self.log.read(*offset),
+            None => Ok(None),
+        }
+    }
+
+    fn keys(&self) -> Vec<String> {
+        self.keys.keys().cloned().collect()
+    }
+
+    fn truncate_before_time(&mut self, millis: u64) -> io::Result<()> {
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.published_at < millis)
+        {
+            self.pop_front();
+        }
+        self.sync_log_start()
+    }
+
+    fn truncate_to_size(&mut self, max_bytes: u64) -> io::Result<()> {
+        while self.bytes > max_bytes && !self.entries.is_empty() {
+            self.pop_front();
+        }
+        self.sync_log_start()
+    }
+
+    fn truncate_to_len(&mut self, max_messages: usize) -> io::Result<()> {
+        while self.entries.len() > max_messages {
+            self.pop_front();
+        }
+        self.sync_log_start()
+    }
+
+    fn compact(&mut self, options: &CompactOptions) -> io::Result<()> {
+        for entry in &self.entries {
+            if entry.tombstone && entry.published_at < options.tombstones_before {
+                forget_key(&mut self.keys, entry.key.as_deref(), entry.offset);
+            }
+        }
+        let keys = &self.keys;
+        self.entries
+            .retain(|entry| survives(keys, options, entry.key.as_deref(), entry.offset));
+        self.bytes = self.entries.iter().map(|entry| entry.size).sum();
+        self.log
+            .compact(|message| survives(keys, options, message.key.as_deref(), message.offset))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    fn next_offset(&self) -> u64 {
+        self.log.next_offset()
+    }
+
+    fn skip_to(&mut self, offset: u64) -> io::Result<()> {
+        self.log.skip_to(offset)
+    }
+}
+
+// Общий для всех топиков брокера бюджет памяти под сообщения
+#[derive(Debug)]
+pub struct MemoryBudget {
+    // Лимит в байтах, u64::MAX - без ограничения
+    limit: AtomicU64,
+    // Сколько байт сообщений сейчас хранится в памяти
+    used: AtomicU64,
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        MemoryBudget {
+            limit: AtomicU64::new(u64::MAX),
+            used: AtomicU64::new(0),
+        }
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        let budget = Self::default();
+        budget.set_limit(limit);
+        budget
+    }
+
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.limit
+            .store(limit.unwrap_or(u64::MAX), Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.limit.load(Ordering::Relaxed)).filter(|limit| *limit != u64::MAX)
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    // Поместятся ли еще bytes байт
+    pub fn fits(&self, bytes: u64) -> bool {
+        self.used().saturating_add(bytes) <= self.limit.load(Ordering::Relaxed)
+    }
+
+    // Учет изменения размера топика: было before байт, стало after
+    pub fn update(&self, before: u64, after: u64) {
+        if after > before {
+            self.used.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(before - after, Ordering::Relaxed);
+        }
+    }
+}
+
+// Остается ли сообщение после компакции: последнее для своего ключа
+// или без ключа, если такие сообщения сохраняются
+fn survives(
+    keys: &HashMap<String, u64>,
+    options: &CompactOptions,
+    key: Option<&str>,
+    offset: u64,
+) -> bool {
+    match key {
+        Some(key) => keys.get(key) == Some(&offset),
+        None => options.keep_keyless,
+    }
+}
+
+// Ключ забывается, только если удалено его последнее сообщение
+fn forget_key(keys: &mut HashMap<String, u64>, key: Option<&str>, offset: u64) {
+    if let Some(key) = key {
+        if keys.get(key) == Some(&offset) {
+            keys.remove(key);
+        }
+    }
+}
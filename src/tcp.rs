@@ -0,0 +1,545 @@
+use crate::{
+    broker::Broker,
+    client::{Deliveries, TopicSessions, HEARTBEAT_INTERVAL, MAX_FETCH_SIZE, MAX_FETCH_WAIT},
+    message::Message,
+    topic::{Fetch, FetchFrom, NackAction, StartPosition},
+};
+use actix_codec::{Decoder, Encoder, Framed};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::timeout;
+use actix_web::web::{Buf, BufMut, Bytes, BytesMut};
+use futures::{channel::mpsc, future, lock::Mutex, SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+// Бинарный протокол для внутренних сервисов, на отдельном порту.
+//
+// Кадр: u32 длина остатка кадра, u8 код, u32 id запроса, тело. Ответ приходит
+// с тем же id запроса, сообщения подписок и heartbeat сервера - с id 0.
+// Числа в big-endian, строка - u32 длина и UTF-8, необязательное значение -
+// u8 флаг (0 - нет значения) и значение.
+// Первым кадром клиент отправляет HELLO с версией протокола.
+
+// Версия протокола
+pub const PROTOCOL_VERSION: u16 = 1;
+// Кадр больше этого размера считается ошибкой
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+// Соединение, от которого так долго нет кадров, закрывается
+const IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+// Коды кадров
+pub mod opcode {
+    // Клиент
+    // u16 версия, необязательный id клиента
+    pub const HELLO: u8 = 0x01;
+    // Топик, ключ?, payload?, u8 require_ack
+    pub const PUBLISH: u8 = 0x02;
+    // Топик, u32 количество, затем для каждого: ключ?, payload?, u8 require_ack
+    pub const PUBLISH_BATCH: u8 = 0x03;
+    // Топик, from?, группа?, u8 постоянная подписка
+    pub const SUBSCRIBE: u8 = 0x04;
+    // Топик
+    pub const UNSUBSCRIBE: u8 = 0x05;
+    // Топик, id сообщения
+    pub const ACK: u8 = 0x06;
+    // Топик, id сообщения, u8 действие (0 requeue, 1 delay, 2 reject), u64 delay_ms, причина?
+    pub const NACK: u8 = 0x07;
+    // Топик, u8 откуда (0 - u64 offset, 1 - строка группа), u32 максимум, u32 ожидание в мс
+    pub const FETCH: u8 = 0x08;
+    // Без тела, сервер отвечает тем же
+    pub const HEARTBEAT: u8 = 0x09;
+
+    // Сервер
+    // Без тела
+    pub const OK: u8 = 0x80;
+    // u16 версия, id клиента
+    pub const HELLO_OK: u8 = 0x81;
+    // Id сообщения, u64 offset
+    pub const PUBLISHED: u8 = 0x82;
+    // u32 количество, затем id и u64 offset каждого
+    pub const PUBLISHED_BATCH: u8 = 0x83;
+    // u32 количество, затем сообщения
+    pub const MESSAGES: u8 = 0x84;
+    // Топик, сообщение
+    pub const DELIVER: u8 = 0x85;
+    // Текст ошибки
+    pub const ERROR: u8 = 0xFF;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub request_id: u32,
+    pub body: Bytes,
+}
+
+impl Frame {
+    pub fn new(opcode: u8, request_id: u32, body: Bytes) -> Self {
+        Frame {
+            opcode,
+            request_id,
+            body,
+        }
+    }
+}
+
+// Разбиение потока байт на кадры
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Frame>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize;
+        if !(5..=MAX_FRAME_SIZE).contains(&len) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Неверная длина кадра: {}", len),
+            ));
+        }
+        if src.len() < 4 + len {
+            src.reserve(4 + len - src.len());
+            return Ok(None);
+        }
+        src.advance(4);
+        let opcode = src.get_u8();
+        let request_id = src.get_u32();
+        let body = src.split_to(len - 5).freeze();
+        Ok(Some(Frame::new(opcode, request_id, body)))
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> io::Result<()> {
+        dst.reserve(9 + frame.body.len());
+        dst.put_u32(5 + frame.body.len() as u32);
+        dst.put_u8(frame.opcode);
+        dst.put_u32(frame.request_id);
+        dst.put_slice(&frame.body);
+        Ok(())
+    }
+}
+
+// Запись тела кадра
+#[derive(Default)]
+pub struct BodyWriter(BytesMut);
+
+impl BodyWriter {
+    pub fn u8(mut self, value: u8) -> Self {
+        self.0.put_u8(value);
+        self
+    }
+
+    pub fn u16(mut self, value: u16) -> Self {
+        self.0.put_u16(value);
+        self
+    }
+
+    pub fn u32(mut self, value: u32) -> Self {
+        self.0.put_u32(value);
+        self
+    }
+
+    pub fn u64(mut self, value: u64) -> Self {
+        self.0.put_u64(value);
+        self
+    }
+
+    pub fn string(mut self, value: &str) -> Self {
+        self.0.put_u32(value.len() as u32);
+        self.0.put_slice(value.as_bytes());
+        self
+    }
+
+    pub fn opt_string(self, value: Option<&str>) -> Self {
+        match value {
+            Some(value) => self.u8(1).string(value),
+            None => self.u8(0),
+        }
+    }
+
+    // Id, u64 offset, ключ?, payload?, u8 require_ack, u64 время публикации,
+    // u32 количество заголовков, затем имя и значение каждого
+    pub fn message(self, message: &Message) -> Self {
+        let mut body = self
+            .string(&message.id)
+            .u64(message.offset)
+            .opt_string(message.key.as_deref())
+            .opt_string(message.payload.as_deref())
+            .u8(message.require_ack as u8)
+            .u64(message.published_at)
+            .u32(message.headers.len() as u32);
+        for (name, value) in &message.headers {
+            body = body.string(name).string(value);
+        }
+        body
+    }
+
+    pub fn finish(self) -> Bytes {
+        self.0.freeze()
+    }
+}
+
+// Чтение тела кадра
+pub struct BodyReader(Bytes);
+
+impl BodyReader {
+    pub fn new(body: Bytes) -> Self {
+        BodyReader(body)
+    }
+
+    fn need(&self, len: usize) -> Result<(), String> {
+        if self.0.remaining() < len {
+            return Err("Кадр короче, чем ожидалось".into());
+        }
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        self.need(1)?;
+        Ok(self.0.get_u8())
+    }
+
+    pub fn u16(&mut self) -> Result<u16, String> {
+        self.need(2)?;
+        Ok(self.0.get_u16())
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        self.need(4)?;
+        Ok(self.0.get_u32())
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        self.need(8)?;
+        Ok(self.0.get_u64())
+    }
+
+    pub fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()? as usize;
+        self.need(len)?;
+        String::from_utf8(self.0.split_to(len).to_vec()).map_err(|_| "Строка не в UTF-8".into())
+    }
+
+    pub fn opt_string(&mut self) -> Result<Option<String>, String> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => self.string().map(Some),
+        }
+    }
+
+    pub fn message(&mut self) -> Result<Message, String> {
+        let mut message = Message::with_payload(None, None, false);
+        message.id = self.string()?;
+        message.offset = self.u64()?;
+        message.key = self.opt_string()?;
+        message.payload = self.opt_string()?;
+        message.require_ack = self.u8()? != 0;
+        message.published_at = self.u64()?;
+        let count = self.u32()?;
+        let mut headers = HashMap::new();
+        for _ in 0..count {
+            headers.insert(self.string()?, self.string()?);
+        }
+        message.headers = headers;
+        Ok(message)
+    }
+}
+
+// Прием соединений
+pub async fn serve(listener: TcpListener, broker: Arc<Mutex<Broker>>) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        println!("Клиент подключился по TCP - {}", peer);
+        actix_web::rt::spawn(handle_connection(stream, broker.clone()));
+    }
+}
+
+fn error_frame(request_id: u32, error: &str) -> Frame {
+    Frame::new(
+        opcode::ERROR,
+        request_id,
+        BodyWriter::default().string(error).finish(),
+    )
+}
+
+// Рукопожатие: версия протокола и id клиента
+fn hello(frame: &Frame) -> Result<String, String> {
+    if frame.opcode != opcode::HELLO {
+        return Err("Первым кадром ожидается HELLO".into());
+    }
+    let mut body = BodyReader::new(frame.body.clone());
+    let version = body.u16()?;
+    if version != PROTOCOL_VERSION {
+        return Err(format!(
+            "Версия протокола {} не поддерживается, сервер поддерживает {}",
+            version, PROTOCOL_VERSION
+        ));
+    }
+    match body.opt_string()? {
+        Some(client_id) if client_id.trim().is_empty() => Err("Пустой client_id".into()),
+        Some(client_id) => Ok(client_id),
+        None => Ok(Uuid::new_v4().to_string()),
+    }
+}
+
+async fn handle_connection(stream: TcpStream, broker: Arc<Mutex<Broker>>) {
+    let (mut sink, mut frames) = Framed::new(stream, FrameCodec).split();
+
+    let client_id = match timeout(IDLE_TIMEOUT, frames.next()).await {
+        Ok(Some(Ok(frame))) => match hello(&frame) {
+            Ok(client_id) => {
+                let body = BodyWriter::default()
+                    .u16(PROTOCOL_VERSION)
+                    .string(&client_id)
+                    .finish();
+                let reply = Frame::new(opcode::HELLO_OK, frame.request_id, body);
+                if sink.send(reply).await.is_err() {
+                    return;
+                }
+                client_id
+            }
+            Err(error) => {
+                let _ = sink.send(error_frame(frame.request_id, &error)).await;
+                return;
+            }
+        },
+        _ => return,
+    };
+
+    let (sessions, deliveries) = TopicSessions::new();
+    let (tx, rx) = mpsc::unbounded();
+    let mut connection = Connection {
+        broker,
+        sessions,
+        client_id,
+        tx,
+    };
+    actix_web::rt::spawn(write_frames(sink, rx, deliveries));
+
+    loop {
+        let frame = match timeout(IDLE_TIMEOUT, frames.next()).await {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(Some(Err(e))) => {
+                connection.reply(error_frame(0, &e.to_string()));
+                break;
+            }
+            // Клиент закрыл соединение или давно молчит
+            Ok(None) | Err(_) => break,
+        };
+        connection.handle(frame).await;
+    }
+    println!("Клиент отключился от TCP - {}", connection.client_id);
+}
+
+// Отправка ответов и сообщений подписок. Когда отправка заканчивается,
+// буферы подписок закрываются, сессии останавливаются и топики отписывают клиента
+async fn write_frames(
+    mut sink: futures::stream::SplitSink<Framed<TcpStream, FrameCodec>, Frame>,
+    mut replies: mpsc::UnboundedReceiver<Frame>,
+    mut deliveries: Deliveries,
+) {
+    loop {
+        let next = timeout(
+            HEARTBEAT_INTERVAL,
+            future::select(replies.next(), deliveries.next()),
+        )
+        .await;
+        let frame = match next {
+            Ok(future::Either::Left((Some(frame), _))) => frame,
+            Ok(future::Either::Right((Some(delivery), _))) => {
+                let body = BodyWriter::default()
+                    .string(&delivery.topic)
+                    .message(&delivery.message)
+                    .finish();
+                Frame::new(opcode::DELIVER, 0, body)
+            }
+            // Соединение закрыто или сессия остановлена
+            Ok(future::Either::Left((None, _))) | Ok(future::Either::Right((None, _))) => break,
+            Err(_) => Frame::new(opcode::HEARTBEAT, 0, Bytes::new()),
+        };
+        if sink.send(frame).await.is_err() {
+            break;
+        }
+    }
+    let _ = sink.close().await;
+}
+
+// Соединение: один id клиента на все подписки, у каждой подписки своя сессия
+struct Connection {
+    broker: Arc<Mutex<Broker>>,
+    client_id: String,
+    sessions: TopicSessions,
+    tx: mpsc::UnboundedSender<Frame>,
+}
+
+impl Connection {
+    fn reply(&self, frame: Frame) {
+        let _ = self.tx.unbounded_send(frame);
+    }
+
+    async fn handle(&mut self, frame: Frame) {
+        let request_id = frame.request_id;
+        // Ожидание fetch не задерживает остальные запросы соединения
+        if frame.opcode == opcode::FETCH {
+            let fetch = self.fetch(frame.body);
+            let tx = self.tx.clone();
+            actix_web::rt::spawn(async move {
+                let reply = match fetch {
+                    Ok(fetch) => fetch.await,
+                    Err(error) => Err(error),
+                };
+                let reply = match reply {
+                    Ok(body) => Frame::new(opcode::MESSAGES, request_id, body),
+                    Err(error) => error_frame(request_id, &error),
+                };
+                let _ = tx.unbounded_send(reply);
+            });
+            return;
+        }
+        let reply = match self.apply(frame.opcode, BodyReader::new(frame.body)).await {
+            Ok((opcode, body)) => Frame::new(opcode, request_id, body),
+            Err(error) => error_frame(request_id, &error),
+        };
+        self.reply(reply);
+    }
+
+    // Выполнение запроса, возвращает код и тело ответа
+    async fn apply(&mut self, op: u8, mut body: BodyReader) -> Result<(u8, Bytes), String> {
+        let ok = Ok((opcode::OK, Bytes::new()));
+        match op {
+            opcode::HEARTBEAT => Ok((opcode::HEARTBEAT, Bytes::new())),
+            opcode::PUBLISH => {
+                let topic = body.string()?;
+                let message = read_publish(&mut body)?;
+                let id = message.id.clone();
+                // Брокер отпускаем до ожидания публикации
+                let publish = self.broker.lock().await.publish_message(&topic, message);
+                let offset = publish.await?;
+                let reply = BodyWriter::default().string(&id).u64(offset).finish();
+                Ok((opcode::PUBLISHED, reply))
+            }
+            opcode::PUBLISH_BATCH => {
+                let topic = body.string()?;
+                let count = body.u32()?;
+                let mut messages = Vec::new();
+                for _ in 0..count {
+                    messages.push(read_publish(&mut body)?);
+                }
+                // Публикуем по порядку, при ошибке остальные не публикуются
+                let publishes: Vec<_> = {
+                    let broker = self.broker.lock().await;
+                    messages
+                        .into_iter()
+                        .map(|message| {
+                            (message.id.clone(), broker.publish_message(&topic, message))
+                        })
+                        .collect()
+                };
+                let mut reply = BodyWriter::default().u32(count);
+                for (published, (id, publish)) in publishes.into_iter().enumerate() {
+                    let offset = publish
+                        .await
+                        .map_err(|e| format!("Опубликовано {} из {}: {}", published, count, e))?;
+                    reply = reply.string(&id).u64(offset);
+                }
+                Ok((opcode::PUBLISHED_BATCH, reply.finish()))
+            }
+            opcode::SUBSCRIBE => {
+                let topic = body.string()?;
+                let from = match body.opt_string()? {
+                    Some(from) => from.parse()?,
+                    None => StartPosition::default(),
+                };
+                let group = body.opt_string()?;
+                let durable = body.u8()? != 0;
+                let client_id = self.client_id.clone();
+                self.sessions
+                    .subscribe(&self.broker, &topic, client_id, from, group, durable)
+                    .await?;
+                ok
+            }
+            opcode::UNSUBSCRIBE => {
+                let topic = body.string()?;
+                self.broker
+                    .lock()
+                    .await
+                    .unsubscribe(&topic, self.client_id.clone())?;
+                self.sessions.close(&topic);
+                ok
+            }
+            opcode::ACK => {
+                let topic = body.string()?;
+                let message_id = body.string()?;
+                let broker = self.broker.lock().await;
+                broker.acknowledge(&topic, self.client_id.clone(), message_id)?;
+                ok
+            }
+            opcode::NACK => {
+                let topic = body.string()?;
+                let message_id = body.string()?;
+                let action = body.u8()?;
+                let delay_ms = body.u64()?;
+                let action = match action {
+                    0 => NackAction::Requeue,
+                    1 => NackAction::Delay { delay_ms },
+                    2 => NackAction::Reject,
+                    other => return Err(format!("Неизвестное действие nack: {}", other)),
+                };
+                let reason = body.opt_string()?;
+                let broker = self.broker.lock().await;
+                broker.nack(&topic, self.client_id.clone(), message_id, action, reason)?;
+                ok
+            }
+            opcode::HELLO => Err("Рукопожатие уже выполнено".into()),
+            other => Err(format!("Неизвестный код кадра: {:#04x}", other)),
+        }
+    }
+
+    // Разбор fetch, ожидание сообщений выполняется отдельно
+    fn fetch(
+        &self,
+        body: Bytes,
+    ) -> Result<impl std::future::Future<Output = Result<Bytes, String>>, String> {
+        let mut body = BodyReader::new(body);
+        let topic = body.string()?;
+        let from = match body.u8()? {
+            0 => FetchFrom::Offset(body.u64()?),
+            1 => FetchFrom::Group(body.string()?),
+            other => return Err(format!("Неизвестный источник fetch: {}", other)),
+        };
+        let fetch = Fetch {
+            client_id: self.client_id.clone(),
+            from,
+            max: (body.u32()? as usize).clamp(1, MAX_FETCH_SIZE),
+            wait: Duration::from_millis(body.u32()? as u64).min(MAX_FETCH_WAIT),
+        };
+        let broker = self.broker.clone();
+        Ok(async move {
+            let fetch = broker.lock().await.fetch(&topic, fetch);
+            let messages = fetch.await?;
+            let mut reply = BodyWriter::default().u32(messages.len() as u32);
+            for message in &messages {
+                reply = reply.message(message);
+            }
+            Ok(reply.finish())
+        })
+    }
+}
+
+// Ключ?, payload?, u8 require_ack
+fn read_publish(body: &mut BodyReader) -> Result<Message, String> {
+    let key = body.opt_string()?;
+    let payload = body.opt_string()?;
+    let require_ack = body.u8()? != 0;
+    Ok(Message::with_payload(payload, key, require_ack))
+}
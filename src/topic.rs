@@ -1,42 +1,419 @@
-use crate::message::Message;
+use crate::buffer::SubscriberBuffer;
+use crate::message::{now_millis, Message};
+use crate::snapshot::{PendingSnapshot, TopicSnapshot};
+use crate::storage::{CompactOptions, MemoryBudget, MemoryStorage, StorageKind, TopicStorage};
 use actix::prelude::*;
-use std::collections::{HashMap, HashSet, VecDeque};
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+// Настройки топика
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TopicConfig {
+    // Время после которого сообщения удаляются
+    pub retention: Option<u64>, // Время в секундах
+    // Флаг компакции, если true, то для каждого ключа
+    // хранится только последнее сообщение
+    pub compaction: bool,
+    // Что делать с сообщениями без ключа в компактном топике
+    pub keyless: KeylessPolicy,
+    // Сколько хранится tombstone (сообщение без payload), прежде чем
+    // компакция удалит его вместе с ключом
+    pub tombstone_retention_ms: u64,
+    // Где хранить сообщения: в памяти или на диске
+    pub storage: StorageKind,
+    // Время ожидания подтверждения до первой повторной доставки
+    pub ack_timeout_ms: u64,
+    // Сколько раз сообщение доставляется, прежде чем уйти в dead-letter топик
+    pub max_delivery_attempts: u32,
+    // Во сколько раз растет ожидание после каждой повторной доставки
+    pub backoff_multiplier: f64,
+    // Верхняя граница ожидания между доставками
+    pub max_backoff_ms: u64,
+    // Топик, куда попадают сообщения, так и не получившие подтверждения
+    pub dead_letter_topic: Option<String>,
+    // Максимальное количество сохраненных сообщений
+    pub max_messages: Option<usize>,
+    // Максимальный суммарный размер сохраненных сообщений в байтах
+    pub max_bytes: Option<u64>,
+    // Что делать с публикацией, когда топик или память брокера заполнены
+    pub overflow: OverflowPolicy,
+    // Сколько ждет заблокированный издатель, прежде чем получить ошибку
+    pub block_timeout_ms: u64,
+    // Сколько непрочитанных сообщений может накопиться у подписчика
+    pub subscriber_buffer: usize,
+    // Что делать, когда буфер подписчика заполнен
+    pub slow_consumer: SlowConsumerPolicy,
+    // Сколько постоянная подписка хранится без подключенного клиента
+    pub subscription_expiry_ms: u64,
+}
+
+// Поведение при заполненном буфере подписчика
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    // Вытеснять самые старые непрочитанные сообщения
+    DropOldest,
+    // Не доставлять новые сообщения
+    DropNewest,
+    // Отключать подписчика
+    Disconnect,
+    // Приостановить доставку и продолжить с той же позиции,
+    // когда подписчик прочитает накопленное
+    #[default]
+    Pause,
+}
+
+// Сообщения без ключа в компактном топике
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeylessPolicy {
+    // Отклонять публикацию с ошибкой
+    #[default]
+    Reject,
+    // Хранить, пока их не удалит retention
+    Keep,
+}
+
+// Поведение при заполненном топике
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    // Удалять самые старые сообщения
+    #[default]
+    DropOldest,
+    // Отклонять публикацию с ошибкой
+    Reject,
+    // Задерживать публикацию, пока не освободится место
+    Block,
+}
+
+impl Default for TopicConfig {
+    fn default() -> Self {
+        TopicConfig {
+            retention: None,
+            compaction: false,
+            keyless: KeylessPolicy::Reject,
+            tombstone_retention_ms: 86_400_000,
+            storage: StorageKind::Memory,
+            ack_timeout_ms: 30_000,
+            max_delivery_attempts: 5,
+            backoff_multiplier: 2.0,
+            max_backoff_ms: 600_000,
+            dead_letter_topic: None,
+            max_messages: None,
+            max_bytes: None,
+            overflow: OverflowPolicy::DropOldest,
+            block_timeout_ms: 30_000,
+            subscriber_buffer: 1000,
+            slow_consumer: SlowConsumerPolicy::Pause,
+            subscription_expiry_ms: 3_600_000,
+        }
+    }
+}
+
+impl TopicConfig {
+    // Время хранения сообщений
+    pub fn retention(&self) -> Option<Duration> {
+        self.retention.map(Duration::from_secs)
+    }
+
+    // Ожидание подтверждения после доставки с номером attempt (начиная с 1)
+    pub fn redelivery_delay(&self, attempt: u32) -> Duration {
+        let factor = self
+            .backoff_multiplier
+            .max(1.0)
+            .powi(attempt.saturating_sub(1) as i32);
+        let delay = (self.ack_timeout_ms as f64 * factor).min(self.max_backoff_ms as f64);
+        Duration::from_millis(delay as u64)
+    }
+}
+
+// Как часто проверять, не отключились ли подписчики
+pub const DISCONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Заголовки, которые получает сообщение при переносе в dead-letter топик
+pub const HEADER_ORIGINAL_TOPIC: &str = "x-original-topic";
+pub const HEADER_DELIVERY_ATTEMPTS: &str = "x-delivery-attempts";
+pub const HEADER_FAILURE_REASON: &str = "x-failure-reason";
+
 // Структура топика
 pub struct Topic {
     // Название топика
-    // name: String,
-    // Время хранения сообщений
-    retention: Option<Duration>,
-    // Флаг компакции, если true, то мы используем last_message_by_key
-    // чтобы хранить последнее сообщение для каждого ключа
-    compaction: bool,
-    // Сообщения, используем VecDeque для быстрого доступа к началу и концу,
-    // так как мы будем удалять старые сообщения, а также добавлять новые в конец
-    messages: VecDeque<Message>,
-    // Последнее сообщение для каждого ключа
-    last_message_by_key: HashMap<String, Message>,
+    name: String,
+    // Настройки хранения и доставки
+    config: TopicConfig,
+    // Топик для сообщений, исчерпавших попытки доставки
+    dead_letter: Option<Addr<Topic>>,
+    // Хранилище сообщений
+    storage: Box<dyn TopicStorage>,
     // Подписчики на топик
-    subscribers: HashMap<String, Recipient<DeliverMessage>>,
-    // Ожидающие подтверждения сообщения
-    pending_acks: HashMap<String, HashSet<String>>, // message_id -> set of client_ids
+    subscribers: HashMap<String, Subscriber>,
+    // Группы потребителей, внутри группы сообщение получает только один участник
+    groups: HashMap<String, ConsumerGroup>,
+    // Позиция каждой группы: offset следующего сообщения для нее.
+    // Сохраняется и после ухода всех участников, чтобы группа продолжила с того же места
+    group_offsets: HashMap<String, u64>,
+    // Постоянные подписки по имени, хранятся и пока клиент не подключен
+    durables: HashMap<String, Durable>,
+    // Наблюдатели за ключами, получают изменения без подтверждений
+    watchers: HashMap<String, Watcher>,
+    // Ожидающие подтверждения сообщения (in-flight)
+    pending_acks: HashMap<String, PendingAck>, // message_id -> сообщение и client_ids
+    // Бюджет памяти брокера и сколько байт топик в нем уже учел
+    budget: Option<Arc<MemoryBudget>>,
+    accounted: u64,
+    // Публикации, ожидающие места в топике (политика Block)
+    blocked: VecDeque<BlockedPublish>,
+    // Запросы fetch, ожидающие новых сообщений
+    fetches: VecDeque<WaitingFetch>,
+}
+
+// Публикация, ожидающая места в топике
+struct BlockedPublish {
+    message: Message,
+    deadline: Instant,
+    tx: oneshot::Sender<Result<u64, String>>,
+}
+
+// Подписчик топика
+struct Subscriber {
+    outlet: Outlet,
+    // Группа потребителей, если подписчик в нее входит
+    group: Option<String>,
+    // Доставка приостановлена, offset следующего сообщения для подписчика
+    paused_at: Option<u64>,
+}
+
+// Постоянная подписка
+struct Durable {
+    // Offset следующего сообщения для подписки
+    position: u64,
+    // Когда подписка будет удалена, None - клиент подключен
+    expires_at: Option<u64>,
 }
 
-// Сообщение для публикации
+// Наблюдатель за ключами
+struct Watcher {
+    outlet: Outlet,
+    keys: HashSet<String>,
+    prefix: Option<String>,
+    // Изменения пропускались, после паузы нужно заново отправить текущие значения
+    stale: bool,
+}
+
+// Адрес получателя и его буфер
+struct Outlet {
+    topic: String,
+    addr: Recipient<DeliverMessage>,
+    buffer: Option<Arc<SubscriberBuffer>>,
+}
+
+impl Outlet {
+    fn deliver(&self, message: Message) {
+        if let Some(buffer) = &self.buffer {
+            buffer.sent();
+        }
+        self.addr.do_send(DeliverMessage {
+            topic: self.topic.clone(),
+            message,
+        });
+    }
+
+    // Сколько еще сообщений поместится в буфер
+    fn room(&self, capacity: usize) -> usize {
+        self.buffer.as_ref().map_or(usize::MAX, |buffer| {
+            capacity.saturating_sub(buffer.queued())
+        })
+    }
+
+    // Прочитана ли хотя бы половина буфера, чтобы продолжить доставку после паузы
+    fn drained(&self, capacity: usize) -> bool {
+        self.room(capacity) >= capacity.div_ceil(2)
+    }
+
+    fn drop_oldest(&self) {
+        if let Some(buffer) = &self.buffer {
+            buffer.drop_oldest();
+        }
+    }
+
+    // Отключение медленного получателя: он дочитывает буфер, и поток завершается
+    fn disconnect(&self) {
+        if let Some(buffer) = &self.buffer {
+            buffer.finish();
+        }
+    }
+}
+
+impl Watcher {
+    fn matches(&self, key: &str) -> bool {
+        self.keys.contains(key)
+            || self
+                .prefix
+                .as_deref()
+                .is_some_and(|prefix| key.starts_with(prefix))
+    }
+}
+
+// Группа потребителей, делит поток сообщений между участниками
+#[derive(Default)]
+struct ConsumerGroup {
+    // Участники в порядке подключения
+    members: Vec<String>,
+    // Следующий участник для round-robin
+    next: usize,
+    // Доставка группе приостановлена, продолжится с ее позиции в group_offsets
+    paused: bool,
+}
+
+impl ConsumerGroup {
+    // Выбор участника: по хешу ключа, если он есть, иначе по кругу
+    fn pick(&mut self, key: Option<&str>) -> Option<String> {
+        if self.members.is_empty() {
+            return None;
+        }
+        let index = match key {
+            Some(key) => {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % self.members.len() as u64) as usize
+            }
+            None => {
+                let index = self.next % self.members.len();
+                self.next = index + 1;
+                index
+            }
+        };
+        Some(self.members[index].clone())
+    }
+}
+
+// Сообщение, ожидающее подтверждения, и клиенты, которые его еще не подтвердили
+struct PendingAck {
+    message: Message,
+    client_ids: HashSet<String>,
+    // Сколько раз сообщение уже доставлялось
+    attempts: u32,
+    // Причина последней неудачной доставки
+    last_failure: Option<String>,
+    // Таймер следующей проверки подтверждения
+    timer: Option<SpawnHandle>,
+    // Группа, которая получила сообщение через fetch
+    fetch_group: Option<String>,
+    // Подтверждение не пришло, сообщение вернется группе при следующем fetch
+    refetch: bool,
+}
+
+// Чтение сообщений по запросу клиента вместо доставки подписчикам
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Result<Vec<Message>, String>")]
+pub struct Fetch {
+    // Клиент, от которого ждем подтверждения сообщений с require_ack
+    pub client_id: String,
+    pub from: FetchFrom,
+    // Сколько сообщений вернуть максимум
+    pub max: usize,
+    // Сколько ждать, если сообщений пока нет
+    pub wait: Duration,
+}
+
+// Откуда читает fetch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FetchFrom {
+    // С offset, позицию хранит клиент
+    Offset(u64),
+    // С позиции группы, она сдвигается на выданные сообщения
+    Group(String),
+}
+
+// Запрос fetch, ожидающий новых сообщений
+struct WaitingFetch {
+    request: Fetch,
+    deadline: Instant,
+    tx: oneshot::Sender<Result<Vec<Message>, String>>,
+}
+
+// Сообщение для публикации, в ответ возвращается назначенный offset
+#[derive(Message)]
+#[rtype(result = "Result<u64, String>")]
 pub struct PublishMessage(pub Message);
 
-// Сообщение для подписки на топик
+// Запрос сообщения по offset, если оно еще хранится в топике
 #[derive(Message)]
-#[rtype(result = "()")]
+#[rtype(result = "Option<Message>")]
+pub struct GetMessage {
+    pub offset: u64,
+}
+
+// Позиция, с которой подписчик начинает получать сообщения
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum StartPosition {
+    // Все сохраненные сообщения топика
+    Earliest,
+    // Только новые сообщения (поведение по умолчанию)
+    #[default]
+    Latest,
+    // Сообщения после указанного id, если id уже удален - все сохраненные
+    AfterMessage(String),
+    // Сообщения начиная с указанного offset (включительно)
+    Offset(u64),
+    // Сообщения, опубликованные не раньше указанного времени (мс от UNIX_EPOCH)
+    Timestamp(u64),
+}
+
+// Разбор позиции из строки запроса: earliest, latest, offset:<n>, time:<мс> или id сообщения
+impl FromStr for StartPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err("Пустая позиция подписки".into()),
+            "earliest" => Ok(StartPosition::Earliest),
+            "latest" => Ok(StartPosition::Latest),
+            _ => {
+                if let Some(offset) = s.strip_prefix("offset:") {
+                    offset
+                        .parse()
+                        .map(StartPosition::Offset)
+                        .map_err(|_| format!("Некорректный offset: {}", offset))
+                } else if let Some(millis) = s.strip_prefix("time:") {
+                    millis
+                        .parse()
+                        .map(StartPosition::Timestamp)
+                        .map_err(|_| format!("Некорректное время: {}", millis))
+                } else {
+                    Ok(StartPosition::AfterMessage(s.to_string()))
+                }
+            }
+        }
+    }
+}
+
+// Сообщение для подписки на топик. Подписка с id, который уже занят
+// подключенным клиентом, отклоняется
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
 pub struct Subscribe {
     pub client_id: String,
     pub addr: Recipient<DeliverMessage>,
+    pub from: StartPosition,
+    // Группа потребителей, None - подписчик получает все сообщения
+    pub group: Option<String>,
+    // Буфер подписчика, None - без ограничения
+    pub buffer: Option<Arc<SubscriberBuffer>>,
+    // Постоянная подписка с именем client_id: позиция и неподтвержденные
+    // сообщения сохраняются, пока клиент не подключен
+    pub durable: bool,
 }
 
-// Сообщение для отписки от топика
+// Сообщение для отписки от топика, постоянная подписка удаляется
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Unsubscribe {
@@ -51,89 +428,1042 @@ pub struct Acknowledge {
     pub message_id: String,
 }
 
-// Сообщение для доставки сообщения
+// Что делать с сообщением, которое клиент не смог обработать
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum NackAction {
+    // Доставить повторно сразу
+    Requeue,
+    // Доставить повторно через указанное время
+    Delay { delay_ms: u64 },
+    // Сразу отправить в dead-letter топик
+    Reject,
+}
+
+// Сообщение для отрицательного подтверждения
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Nack {
+    pub client_id: String,
+    pub message_id: String,
+    pub action: NackAction,
+    // Причина отказа, попадает в заголовок dead-letter сообщения
+    pub reason: Option<String>,
+}
+
+// Описание топика для администрирования
+#[derive(Serialize, Debug)]
+pub struct TopicDescription {
+    pub name: String,
+    pub config: TopicConfig,
+    // Количество и суммарный размер сохраненных сообщений
+    pub message_count: usize,
+    pub size_bytes: u64,
+    pub next_offset: u64,
+    pub subscriber_count: usize,
+    // Наблюдатели за ключами
+    pub watcher_count: usize,
+    // Группы потребителей и количество участников в каждой
+    pub groups: HashMap<String, usize>,
+    pub pending_acks: usize,
+    // Публикации, ожидающие места в топике
+    pub blocked_publishes: usize,
+    // Запросы fetch, ожидающие новых сообщений
+    pub waiting_fetches: usize,
+    // Постоянные подписки
+    pub durable_subscriptions: HashMap<String, DurableDescription>,
+}
+
+// Состояние постоянной подписки
+#[derive(Serialize, Debug)]
+pub struct DurableDescription {
+    pub position: u64,
+    pub connected: bool,
+    // Время удаления отключенной подписки в миллисекундах от UNIX_EPOCH
+    pub expires_at: Option<u64>,
+}
+
+// Запрос описания топика
+#[derive(Message)]
+#[rtype(result = "TopicDescription")]
+pub struct Describe;
+
+// Новые настройки живого топика, применяются и к уже сохраненным сообщениям
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct AlterConfig {
+    pub config: TopicConfig,
+    pub dead_letter: Option<Addr<Topic>>,
+}
+
+// Удаление топика: подписчики отключаются, актор останавливается
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeleteTopic;
+
+// Подписка на изменения отдельных ключей компактного топика: сначала приходят
+// текущие значения, затем каждое изменение и удаление (tombstone)
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Watch {
+    pub client_id: String,
+    pub addr: Recipient<DeliverMessage>,
+    // Ключи, за которыми следим
+    pub keys: HashSet<String>,
+    // И/или все ключи с этим префиксом
+    pub prefix: Option<String>,
+    // Буфер наблюдателя, None - без ограничения
+    pub buffer: Option<Arc<SubscriberBuffer>>,
+}
+
+// Текущее значение ключа компактного топика
+#[derive(Message)]
+#[rtype(result = "Result<Option<Message>, String>")]
+pub struct GetKey {
+    pub key: String,
+}
+
+// Страница ключей компактного топика, ключи отсортированы
+#[derive(Message)]
+#[rtype(result = "Result<KeyPage, String>")]
+pub struct ListKeys {
+    // Только ключи с этим префиксом
+    pub prefix: Option<String>,
+    // Ключи строго после этого (последний ключ предыдущей страницы)
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub struct KeyPage {
+    pub keys: Vec<String>,
+    // Курсор следующей страницы, None - ключей больше нет
+    pub next: Option<String>,
+}
+
+// Все текущие значения компактного топика
 #[derive(Message)]
+#[rtype(result = "Result<TableSnapshot, String>")]
+pub struct GetTable;
+
+#[derive(Serialize, Debug)]
+pub struct TableSnapshot {
+    // Offset, с которого продолжать чтение изменений после снимка
+    pub next_offset: u64,
+    pub entries: BTreeMap<String, String>,
+}
+
+// Запрос снимка состояния топика
+#[derive(Message)]
+#[rtype(result = "TopicSnapshot")]
+pub struct TakeSnapshot;
+
+// Восстановление состояния топика из снимка
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct RestoreSnapshot(pub TopicSnapshot);
+
+// Сообщение для доставки сообщения, одна сессия может получать сообщения нескольких топиков
+#[derive(Message, Debug)]
 #[rtype(result = "()")]
-pub struct DeliverMessage(pub Message);
+pub struct DeliverMessage {
+    pub topic: String,
+    pub message: Message,
+}
 
 impl Topic {
-    pub fn new(retention: Option<Duration>, compaction: bool) -> Self {
+    pub fn new(name: String, config: TopicConfig) -> Self {
         Topic {
-            // name,
-            retention,
-            compaction,
-            messages: VecDeque::new(),
-            last_message_by_key: HashMap::new(),
+            name,
+            config,
+            dead_letter: None,
+            storage: Box::new(MemoryStorage::new()),
             subscribers: HashMap::new(),
+            groups: HashMap::new(),
+            group_offsets: HashMap::new(),
+            durables: HashMap::new(),
+            watchers: HashMap::new(),
             pending_acks: HashMap::new(),
+            budget: None,
+            accounted: 0,
+            blocked: VecDeque::new(),
+            fetches: VecDeque::new(),
         }
     }
 
-    // Очистка старых сообщений
+    // Подключение другого хранилища, например с уже сохраненными сообщениями
+    pub fn with_storage(mut self, storage: Box<dyn TopicStorage>) -> Self {
+        self.storage = storage;
+        self.clean_up_messages();
+        self
+    }
+
+    // Подключение dead-letter топика
+    pub fn with_dead_letter(mut self, topic: Addr<Topic>) -> Self {
+        self.dead_letter = Some(topic);
+        self
+    }
+
+    // Подключение общего бюджета памяти брокера
+    pub fn with_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.budget = Some(budget);
+        self.sync_budget();
+        self
+    }
+
+    // Очистка старых сообщений, компакция и ограничения по количеству и размеру
     fn clean_up_messages(&mut self) {
-        // Если установлено время хранения сообщений, запускаем цикл
-        // и удаляем старые сообщения
-        if let Some(retention_duration) = self.retention {
-            // Получаем текущее время
-            let now = Instant::now();
-
-            // Начинаем с начала очереди сообщений, самые старые сообщения
-            while let Some(message) = self.messages.front() {
-                // Если сообщение не имеет времени, то мы не можем его удалить
-                if let Some(timestamp) = message.timestamp {
-                    if now.duration_since(timestamp) > retention_duration {
-                        // Удаляем сообщение
-                        self.messages.pop_front();
-                    }
-                } else {
-                    break;
-                }
+        if let Err(e) = self.apply_retention() {
+            println!("Ошибка очистки топика {}: {}", self.name, e);
+        }
+        self.sync_budget();
+    }
+
+    fn apply_retention(&mut self) -> std::io::Result<()> {
+        // Если установлено время хранения сообщений, удаляем старые сообщения,
+        // в компактном топике тоже
+        if let Some(retention) = self.config.retention() {
+            let oldest = now_millis().saturating_sub(retention.as_millis() as u64);
+            self.storage.truncate_before_time(oldest)?;
+        }
+        // Компакция в фоне: удаляем сообщения, замененные более новыми по ключу,
+        // и tombstone, пережившие свой срок
+        if self.config.compaction {
+            self.storage.compact(&CompactOptions {
+                keep_keyless: self.config.keyless == KeylessPolicy::Keep,
+                tombstones_before: now_millis().saturating_sub(self.config.tombstone_retention_ms),
+            })?;
+        }
+        if let Some(max_messages) = self.config.max_messages {
+            self.storage.truncate_to_len(max_messages)?;
+        }
+        if let Some(max_bytes) = self.config.max_bytes {
+            self.storage.truncate_to_size(max_bytes)?;
+        }
+        Ok(())
+    }
+
+    // Учет размера топика в бюджете брокера, на диске память не расходуется
+    fn sync_budget(&mut self) {
+        if let Some(budget) = &self.budget {
+            let size = match self.config.storage {
+                StorageKind::Memory => self.storage.size_bytes(),
+                StorageKind::File => 0,
+            };
+            budget.update(self.accounted, size);
+            self.accounted = size;
+        }
+    }
+
+    // Хватит ли места для сообщения размером size без удаления старых
+    fn has_room(&self, size: u64) -> bool {
+        let messages_fit = self
+            .config
+            .max_messages
+            .is_none_or(|max| self.storage.len() < max);
+        let bytes_fit = self
+            .config
+            .max_bytes
+            .is_none_or(|max| self.storage.size_bytes() + size <= max);
+        let budget_fits = match (&self.budget, self.config.storage) {
+            (Some(budget), StorageKind::Memory) => budget.fits(size),
+            _ => true,
+        };
+        messages_fit && bytes_fit && budget_fits
+    }
+
+    // Хватит ли места, если удалить все сообщения топика. Память, занятую
+    // другими топиками, удаление своих сообщений не освободит
+    fn can_make_room(&self, size: u64) -> bool {
+        let messages_fit = self.config.max_messages.is_none_or(|max| max > 0);
+        let bytes_fit = self.config.max_bytes.is_none_or(|max| size <= max);
+        let budget_fits = match (&self.budget, self.config.storage) {
+            (Some(budget), StorageKind::Memory) => budget
+                .limit()
+                .is_none_or(|limit| budget.used().saturating_sub(self.accounted) + size <= limit),
+            _ => true,
+        };
+        messages_fit && bytes_fit && budget_fits
+    }
+
+    // Id клиента занят, если под ним подключен живой подписчик или наблюдатель
+    fn check_client_id(&self, client_id: &str) -> Result<(), String> {
+        let connected = self
+            .subscribers
+            .get(client_id)
+            .is_some_and(|subscriber| subscriber.outlet.addr.connected())
+            || self
+                .watchers
+                .get(client_id)
+                .is_some_and(|watcher| watcher.outlet.addr.connected());
+        if connected {
+            return Err(format!("Клиент {} уже подписан на топик", client_id));
+        }
+        Ok(())
+    }
+
+    // Текущее значение ключа, удаленный ключ (tombstone) значения не имеет
+    fn current_value(&self, key: &str) -> Result<Option<Message>, String> {
+        if !self.config.compaction {
+            return Err(format!("Топик {} не компактный", self.name));
+        }
+        let message = self
+            .storage
+            .read_key(key)
+            .map_err(|e| format!("Ошибка чтения топика: {}", e))?;
+        Ok(message.filter(|message| !message.is_tombstone()))
+    }
+
+    // Ключи с префиксом по возрастанию, начиная после after
+    fn sorted_keys(&self, prefix: Option<&str>, after: Option<&str>) -> Vec<String> {
+        let mut keys: Vec<String> = self
+            .storage
+            .keys()
+            .into_iter()
+            .filter(|key| prefix.is_none_or(|prefix| key.starts_with(prefix)))
+            .filter(|key| after.is_none_or(|after| key.as_str() > after))
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    // Tombstone и, в компактном топике, остальные сообщения должны иметь ключ
+    fn check_key(&self, message: &Message) -> Result<(), String> {
+        if message.key.is_some() {
+            return Ok(());
+        }
+        if message.is_tombstone() {
+            return Err("Сообщение без payload должно иметь ключ".into());
+        }
+        if self.config.compaction && self.config.keyless == KeylessPolicy::Reject {
+            return Err(format!(
+                "Компактный топик {} не принимает сообщения без ключа",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    // Публикация по политике DropOldest или Reject
+    fn admit(&mut self, message: Message, ctx: &mut Context<Self>) -> Result<u64, String> {
+        let size = message.size();
+        if self.config.overflow == OverflowPolicy::DropOldest && self.can_make_room(size) {
+            // Освобождаем место за счет самых старых сообщений топика
+            while !self.has_room(size) && !self.storage.is_empty() {
+                self.storage
+                    .truncate_to_len(self.storage.len() - 1)
+                    .map_err(|e| format!("Ошибка очистки топика: {}", e))?;
+                self.sync_budget();
+            }
+        }
+        if !self.has_room(size) {
+            return Err(format!("Топик {} переполнен", self.name));
+        }
+        self.publish(message, ctx)
+    }
+
+    // Сохранение сообщения и доставка подписчикам
+    fn publish(&mut self, mut message: Message, ctx: &mut Context<Self>) -> Result<u64, String> {
+        // Назначаем сообщению следующий offset топика
+        message.offset = self.storage.next_offset();
+
+        // Сначала сохраняем сообщение, чтобы не подтвердить то, что не сохранилось
+        self.storage
+            .append(message.clone())
+            .map_err(|e| format!("Ошибка записи сообщения: {}", e))?;
+        self.sync_budget();
+
+        // Отправляем сообщение подписчикам и ожидающим fetch
+        self.deliver_message(&message, ctx);
+        self.serve_fetches(ctx);
+        Ok(message.offset)
+    }
+
+    // Публикация ожидающих сообщений, когда появилось место или истекло время ожидания
+    fn release_blocked(&mut self, ctx: &mut Context<Self>) {
+        while let Some(front) = self.blocked.front() {
+            let blocking = self.config.overflow == OverflowPolicy::Block;
+            let has_room = self.has_room(front.message.size());
+            if blocking && !has_room && front.deadline > Instant::now() {
+                break;
             }
+            let Some(blocked) = self.blocked.pop_front() else {
+                break;
+            };
+            let result = if !blocking {
+                // Политику изменили, ожидающие публикуются по новой
+                self.admit(blocked.message, ctx)
+            } else if has_room {
+                self.publish(blocked.message, ctx)
+            } else {
+                Err(format!(
+                    "Истекло время ожидания места в топике {}",
+                    self.name
+                ))
+            };
+            let _ = blocked.tx.send(result);
+        }
+    }
+
+    // Сохраненные сообщения в порядке публикации
+    fn retained_messages(&self) -> Vec<Message> {
+        let messages = self.storage.read_range(0, usize::MAX).unwrap_or_else(|e| {
+            println!("Ошибка чтения топика {}: {}", self.name, e);
+            Vec::new()
+        });
+        if !self.config.compaction {
+            return messages;
+        }
+
+        // В компактном топике видно только последнее сообщение по ключу
+        // (в том числе tombstone, пока компакция его не удалила),
+        // даже если фоновая компакция до него еще не дошла
+        let mut latest = HashMap::new();
+        for message in &messages {
+            if let Some(key) = &message.key {
+                latest.insert(key.clone(), message.offset);
+            }
+        }
+        messages
+            .into_iter()
+            .filter(|message| match &message.key {
+                Some(key) => latest.get(key) == Some(&message.offset),
+                None => self.config.keyless == KeylessPolicy::Keep,
+            })
+            .collect()
+    }
+
+    // Сообщения, которые нужно отправить новому подписчику до начала живой доставки
+    fn backlog(&self, from: &StartPosition) -> Vec<Message> {
+        let messages = self.retained_messages();
+        match from {
+            StartPosition::Latest => Vec::new(),
+            StartPosition::Earliest => messages,
+            StartPosition::AfterMessage(id) => match messages.iter().position(|m| &m.id == id) {
+                Some(index) => messages[index + 1..].to_vec(),
+                None => messages,
+            },
+            StartPosition::Offset(offset) => messages
+                .into_iter()
+                .filter(|m| m.offset >= *offset)
+                .collect(),
+            StartPosition::Timestamp(millis) => messages
+                .into_iter()
+                .filter(|m| m.published_at >= *millis)
+                .collect(),
         }
     }
 
     // Отправка сообщения подписчикам, + проверка на подтверждение (если требуется)
     fn deliver_message(&mut self, message: &Message, ctx: &mut Context<Self>) {
-        // рассылаем сообщение подписчикам
-        for (client_id, subscriber) in &self.subscribers {
-            let _ = subscriber.do_send(DeliverMessage(message.clone()));
+        // Группа не должна выбрать участника, который уже отключился
+        self.remove_disconnected(ctx);
+
+        // Подписчики без группы получают каждое сообщение
+        let subscribers: Vec<String> = self
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.group.is_none())
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in subscribers {
+            self.offer(&client_id, message, ctx);
+        }
+
+        // Из каждой группы выбираем одного участника
+        let groups: Vec<String> = self.groups.keys().cloned().collect();
+        for group in groups {
+            self.offer_to_group(&group, message, ctx);
+        }
+
+        // Наблюдатели получают изменения своих ключей
+        if let Some(key) = &message.key {
+            let capacity = self.config.subscriber_buffer;
+            let mut disconnected = Vec::new();
+            for (client_id, watcher) in &mut self.watchers {
+                if !watcher.matches(key) || watcher.stale {
+                    continue;
+                }
+                if watcher.outlet.room(capacity) == 0 {
+                    match self.config.slow_consumer {
+                        SlowConsumerPolicy::DropNewest => continue,
+                        SlowConsumerPolicy::DropOldest => watcher.outlet.drop_oldest(),
+                        SlowConsumerPolicy::Disconnect => {
+                            disconnected.push(client_id.clone());
+                            continue;
+                        }
+                        SlowConsumerPolicy::Pause => {
+                            watcher.stale = true;
+                            continue;
+                        }
+                    }
+                }
+                watcher.outlet.deliver(message.clone());
+            }
+            for client_id in disconnected {
+                println!("Медленный наблюдатель отключен - {}", client_id);
+                if let Some(watcher) = self.watchers.remove(&client_id) {
+                    watcher.outlet.disconnect();
+                }
+            }
+        }
+    }
+
+    // Доставка подписчику без группы с учетом заполненности его буфера
+    fn offer(&mut self, client_id: &str, message: &Message, ctx: &mut Context<Self>) {
+        let capacity = self.config.subscriber_buffer;
+        let Some(subscriber) = self.subscribers.get_mut(client_id) else {
+            return;
+        };
+        if subscriber.paused_at.is_some() {
+            return;
+        }
+        if subscriber.outlet.room(capacity) == 0 {
+            match self.config.slow_consumer {
+                SlowConsumerPolicy::DropNewest => return,
+                SlowConsumerPolicy::DropOldest => subscriber.outlet.drop_oldest(),
+                SlowConsumerPolicy::Disconnect => {
+                    println!("Медленный подписчик отключен - {}", client_id);
+                    subscriber.outlet.disconnect();
+                    self.remove_subscriber(client_id, ctx);
+                    return;
+                }
+                SlowConsumerPolicy::Pause => {
+                    subscriber.paused_at = Some(message.offset);
+                    return;
+                }
+            }
+        }
+        self.send_to(client_id, message, ctx);
+    }
+
+    // Доставка одному участнику группы. При паузе останавливается вся группа,
+    // так как позиция у группы общая
+    fn offer_to_group(&mut self, name: &str, message: &Message, ctx: &mut Context<Self>) {
+        let capacity = self.config.subscriber_buffer;
+        loop {
+            let Some(group) = self.groups.get_mut(name) else {
+                return;
+            };
+            if group.paused {
+                return;
+            }
+            let Some(member) = group.pick(message.key.as_deref()) else {
+                return;
+            };
+            let room = self
+                .subscribers
+                .get(&member)
+                .map_or(0, |subscriber| subscriber.outlet.room(capacity));
+            if room == 0 {
+                match self.config.slow_consumer {
+                    SlowConsumerPolicy::DropNewest => {}
+                    SlowConsumerPolicy::DropOldest => {
+                        self.subscribers[&member].outlet.drop_oldest();
+                        self.send_to(&member, message, ctx);
+                    }
+                    SlowConsumerPolicy::Disconnect => {
+                        // Сообщение достанется другому участнику
+                        println!("Медленный подписчик отключен - {}", member);
+                        self.subscribers[&member].outlet.disconnect();
+                        self.remove_subscriber(&member, ctx);
+                        continue;
+                    }
+                    SlowConsumerPolicy::Pause => {
+                        group.paused = true;
+                        return;
+                    }
+                }
+            } else {
+                self.send_to(&member, message, ctx);
+            }
+            self.group_offsets
+                .insert(name.to_string(), message.offset + 1);
+            return;
+        }
+    }
+
+    // Сохраненные сообщения начиная с offset, не больше limit
+    fn messages_from(&self, offset: u64, limit: usize) -> Vec<Message> {
+        if self.config.compaction {
+            return self
+                .retained_messages()
+                .into_iter()
+                .filter(|m| m.offset >= offset)
+                .take(limit)
+                .collect();
+        }
+        self.storage.read_range(offset, limit).unwrap_or_else(|e| {
+            println!("Ошибка чтения топика {}: {}", self.name, e);
+            Vec::new()
+        })
+    }
+
+    // Продолжение доставки приостановленным подписчикам, прочитавшим накопленное
+    fn resume_paused(&mut self, ctx: &mut Context<Self>) {
+        let capacity = self.config.subscriber_buffer;
 
-            // Если сообщение требует подтверждения, добавляем в ожидающие
-            if message.require_ack {
-                self.pending_acks
-                    .entry(message.id.clone())
-                    .or_insert_with(HashSet::new)
-                    .insert(client_id.clone());
+        let paused: Vec<(String, u64)> = self
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.outlet.drained(capacity))
+            .filter_map(|(client_id, subscriber)| Some((client_id.clone(), subscriber.paused_at?)))
+            .collect();
+        for (client_id, from) in paused {
+            let room = self.subscribers[&client_id].outlet.room(capacity);
+            let messages = self.messages_from(from, room);
+            // Меньше, чем поместилось бы - значит подписчик догнал топик
+            let caught_up = messages.len() < room;
+            let next = messages.last().map_or(from, |m| m.offset + 1);
+            for message in &messages {
+                self.send_to(&client_id, message, ctx);
+            }
+            if let Some(subscriber) = self.subscribers.get_mut(&client_id) {
+                subscriber.paused_at = (!caught_up).then_some(next);
             }
         }
 
+        let paused: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, group)| {
+                group.paused
+                    && group.members.iter().any(|member| {
+                        self.subscribers
+                            .get(member)
+                            .is_some_and(|subscriber| subscriber.outlet.drained(capacity))
+                    })
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in paused {
+            let from = self.group_offsets.get(&name).copied().unwrap_or(0);
+            let messages = self.messages_from(from, capacity);
+            let caught_up = messages.len() < capacity;
+            if let Some(group) = self.groups.get_mut(&name) {
+                group.paused = false;
+            }
+            for message in &messages {
+                self.offer_to_group(&name, message, ctx);
+                if self.groups.get(&name).is_none_or(|group| group.paused) {
+                    break;
+                }
+            }
+            // Сообщения, не поместившиеся в этот раз, дочитаем из хранилища
+            if !caught_up {
+                if let Some(group) = self.groups.get_mut(&name) {
+                    group.paused = true;
+                }
+            }
+        }
+
+        // Наблюдателям после паузы заново отправляем текущие значения
+        let stale: Vec<String> = self
+            .watchers
+            .iter()
+            .filter(|(_, watcher)| watcher.stale && watcher.outlet.drained(capacity))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in stale {
+            let watcher = &self.watchers[&client_id];
+            let mut keys: Vec<String> = watcher.keys.iter().cloned().collect();
+            if let Some(prefix) = &watcher.prefix {
+                keys.extend(self.sorted_keys(Some(prefix), None));
+            }
+            keys.sort();
+            keys.dedup();
+            let values: Vec<Message> = keys
+                .iter()
+                .filter_map(|key| self.current_value(key).ok().flatten())
+                .collect();
+            if let Some(watcher) = self.watchers.get_mut(&client_id) {
+                for message in values {
+                    watcher.outlet.deliver(message);
+                }
+                watcher.stale = false;
+            }
+        }
+    }
+
+    // Отправка сообщения одному подписчику с учетом подтверждения
+    fn send_to(&mut self, client_id: &str, message: &Message, ctx: &mut Context<Self>) {
+        let Some(subscriber) = self.subscribers.get(client_id) else {
+            return;
+        };
+        subscriber.outlet.deliver(message.clone());
+        self.advance(client_id, message.offset);
+
+        // Если сообщение требует подтверждения, добавляем в ожидающие
         if message.require_ack {
-            let message_id = message.id.clone();
-            // тут делаем spawn, чтобы не блокировать текущий контекст
-            // через n секунд проверяем, что все получили сообщение
-            ctx.run_later(Duration::from_secs(30), move |act, ctx| {
-                act.check_pending_ack(message_id.clone(), ctx);
+            self.track_ack(client_id, message, None, ctx);
+        }
+    }
+
+    // Повторная отправка ожидающего подтверждения сообщения с учетом
+    // заполненности буфера. Не отправленное сейчас сообщение остается
+    // в ожидающих и уйдет при следующей проверке подтверждения
+    fn resend_to(&mut self, client_id: &str, message: &Message, ctx: &mut Context<Self>) {
+        let capacity = self.config.subscriber_buffer;
+        let Some(subscriber) = self.subscribers.get(client_id) else {
+            return;
+        };
+        if subscriber.outlet.room(capacity) == 0 {
+            match self.config.slow_consumer {
+                SlowConsumerPolicy::DropNewest | SlowConsumerPolicy::Pause => return,
+                SlowConsumerPolicy::DropOldest => subscriber.outlet.drop_oldest(),
+                SlowConsumerPolicy::Disconnect => {
+                    println!("Медленный подписчик отключен - {}", client_id);
+                    subscriber.outlet.disconnect();
+                    self.remove_subscriber(client_id, ctx);
+                    return;
+                }
+            }
+        }
+        subscriber.outlet.deliver(message.clone());
+    }
+
+    // Ожидание подтверждения сообщения от клиента
+    fn track_ack(
+        &mut self,
+        client_id: &str,
+        message: &Message,
+        fetch_group: Option<&str>,
+        ctx: &mut Context<Self>,
+    ) {
+        let pending = self
+            .pending_acks
+            .entry(message.id.clone())
+            .or_insert_with(|| PendingAck {
+                message: message.clone(),
+                client_ids: HashSet::new(),
+                attempts: 1,
+                last_failure: None,
+                timer: None,
+                fetch_group: None,
+                refetch: false,
             });
+        pending.client_ids.insert(client_id.to_string());
+        if let Some(group) = fetch_group {
+            pending.fetch_group = Some(group.to_string());
+        }
+
+        // Первая доставка - запускаем таймер ожидания подтверждения
+        if pending.timer.is_none() {
+            let attempts = pending.attempts;
+            self.schedule_ack_check(message.id.clone(), attempts, ctx);
+        }
+    }
+
+    // Сообщения для запроса fetch, пустой результат - сообщений пока нет
+    fn take_fetch(
+        &mut self,
+        fetch: &Fetch,
+        ctx: &mut Context<Self>,
+    ) -> Result<Vec<Message>, String> {
+        let group = match &fetch.from {
+            FetchFrom::Offset(offset) => {
+                let messages = self.messages_from(*offset, fetch.max);
+                for message in messages.iter().filter(|m| m.require_ack) {
+                    self.track_ack(&fetch.client_id, message, None, ctx);
+                }
+                return Ok(messages);
+            }
+            FetchFrom::Group(group) => group,
+        };
+        if self.groups.contains_key(group) {
+            return Err(format!("Группа {} получает сообщения по подписке", group));
+        }
+
+        // Сначала сообщения, подтверждение которых не пришло вовремя,
+        // теперь их ждем от нового клиента
+        let mut refetch: Vec<&mut PendingAck> = self
+            .pending_acks
+            .values_mut()
+            .filter(|pending| pending.refetch && pending.fetch_group.as_ref() == Some(group))
+            .collect();
+        refetch.sort_by_key(|pending| pending.message.offset);
+        let mut messages = Vec::new();
+        for pending in refetch.into_iter().take(fetch.max) {
+            pending.refetch = false;
+            pending
+                .client_ids
+                .retain(|client_id| self.subscribers.contains_key(client_id));
+            pending.client_ids.insert(fetch.client_id.clone());
+            messages.push(pending.message.clone());
+        }
+
+        // Новая группа начинает с самого старого сохраненного сообщения
+        let position = self.group_offsets.get(group).copied().unwrap_or(0);
+        let fresh = self.messages_from(position, fetch.max - messages.len());
+        if let Some(last) = fresh.last() {
+            self.group_offsets.insert(group.clone(), last.offset + 1);
+        }
+        for message in fresh.iter().filter(|m| m.require_ack) {
+            self.track_ack(&fetch.client_id, message, Some(group), ctx);
+        }
+        messages.extend(fresh);
+        Ok(messages)
+    }
+
+    // Ответ ожидающим fetch, когда появились сообщения или истекло время ожидания
+    fn serve_fetches(&mut self, ctx: &mut Context<Self>) {
+        for waiting in std::mem::take(&mut self.fetches) {
+            // Клиент ушел, не забирая сообщения
+            if waiting.tx.is_canceled() {
+                continue;
+            }
+            let result = self.take_fetch(&waiting.request, ctx);
+            let empty = matches!(&result, Ok(messages) if messages.is_empty());
+            if empty && waiting.deadline > Instant::now() {
+                self.fetches.push_back(waiting);
+                continue;
+            }
+            let _ = waiting.tx.send(result);
+        }
+    }
+
+    // Планирование проверки подтверждения после доставки с номером attempt
+    fn schedule_ack_check(&mut self, message_id: String, attempt: u32, ctx: &mut Context<Self>) {
+        let delay = self.config.redelivery_delay(attempt);
+        // тут делаем run_later, чтобы не блокировать текущий контекст
+        // через n секунд проверяем, что все получили сообщение
+        let id = message_id.clone();
+        let handle = ctx.run_later(delay, move |act, ctx| {
+            act.check_pending_ack(id, ctx);
+        });
+        if let Some(pending) = self.pending_acks.get_mut(&message_id) {
+            if let Some(old) = pending.timer.replace(handle) {
+                ctx.cancel_future(old);
+            }
         }
     }
 
     // Проверка на подтверждение получения сообщения
-    fn check_pending_ack(&mut self, message_id: String, _: &mut Context<Self>) {
-        if let Some(client_ids) = self.pending_acks.get(&message_id) {
-            if !client_ids.is_empty() {
-                // Если не все получили сообщение, повторяем отправку
-                for client_id in client_ids {
-                    if let Some(subscriber) = self.subscribers.get(client_id) {
-                        let _ = subscriber.do_send(DeliverMessage(
-                            self.last_message_by_key[&message_id].clone(),
-                        ));
+    fn check_pending_ack(&mut self, message_id: String, ctx: &mut Context<Self>) {
+        let Some(pending) = self.pending_acks.get_mut(&message_id) else {
+            return;
+        };
+        pending.timer = None;
+        if pending.client_ids.is_empty() {
+            self.pending_acks.remove(&message_id);
+            return;
+        }
+        // Пока клиенты постоянных подписок не подключены, а приостановленные
+        // подписчики не разобрали буфер, попытки не расходуются
+        let capacity = self.config.subscriber_buffer;
+        let pause = self.config.slow_consumer == SlowConsumerPolicy::Pause;
+        let waiting =
+            pending
+                .client_ids
+                .iter()
+                .all(|client_id| match self.subscribers.get(client_id) {
+                    Some(subscriber) => pause && subscriber.outlet.room(capacity) == 0,
+                    None => self.durables.contains_key(client_id),
+                });
+        if waiting {
+            let attempts = pending.attempts;
+            self.schedule_ack_check(message_id, attempts, ctx);
+            return;
+        }
+        if pending.last_failure.is_none() {
+            pending.last_failure = Some("ack timeout".into());
+        }
+
+        // Попытки исчерпаны - переносим сообщение в dead-letter топик
+        if pending.attempts >= self.config.max_delivery_attempts {
+            if let Some(pending) = self.pending_acks.remove(&message_id) {
+                self.dead_letter(pending.message, pending.attempts, pending.last_failure);
+            }
+            return;
+        }
+
+        // Если не все получили сообщение, повторяем отправку
+        pending.attempts += 1;
+        let attempts = pending.attempts;
+        let message = pending.message.clone();
+        let client_ids: Vec<String> = pending.client_ids.iter().cloned().collect();
+        // Полученное через fetch сообщение заберет следующий запрос группы
+        if pending.fetch_group.is_some() {
+            pending.refetch = true;
+        }
+        for client_id in client_ids {
+            self.resend_to(&client_id, &message, ctx);
+        }
+        // Отключенный медленный подписчик мог быть последним, кто ждал сообщение
+        if self.pending_acks.contains_key(&message_id) {
+            self.schedule_ack_check(message_id, attempts, ctx);
+        }
+    }
+
+    // Перенос сообщения в dead-letter топик с информацией о доставке
+    fn dead_letter(&mut self, mut message: Message, attempts: u32, reason: Option<String>) {
+        println!(
+            "Сообщение {} не подтверждено после {} попыток",
+            message.id, attempts
+        );
+        let Some(dead_letter) = &self.dead_letter else {
+            return;
+        };
+        message
+            .headers
+            .insert(HEADER_ORIGINAL_TOPIC.into(), self.name.clone());
+        message
+            .headers
+            .insert(HEADER_DELIVERY_ATTEMPTS.into(), attempts.to_string());
+        if let Some(reason) = reason {
+            message.headers.insert(HEADER_FAILURE_REASON.into(), reason);
+        }
+        dead_letter.do_send(PublishMessage(message));
+    }
+
+    // Повторная доставка сообщения после nack, в группе сообщение
+    // может достаться другому участнику
+    fn redeliver(&mut self, message_id: &str, client_id: &str, ctx: &mut Context<Self>) {
+        let Some(pending) = self.pending_acks.get_mut(message_id) else {
+            return;
+        };
+        pending.timer = None;
+        pending.attempts += 1;
+        let attempts = pending.attempts;
+        let message = pending.message.clone();
+
+        let target = self
+            .subscribers
+            .get(client_id)
+            .and_then(|subscriber| subscriber.group.as_ref())
+            .and_then(|group| self.groups.get_mut(group))
+            .and_then(|group| group.pick(message.key.as_deref()))
+            .unwrap_or_else(|| client_id.to_string());
+        if let Some(pending) = self.pending_acks.get_mut(message_id) {
+            pending.client_ids.remove(client_id);
+            pending.client_ids.insert(target.clone());
+        }
+        self.resend_to(&target, &message, ctx);
+        if self.pending_acks.contains_key(message_id) {
+            self.schedule_ack_check(message_id.to_string(), attempts, ctx);
+        }
+    }
+
+    // Удаление отключившихся подписчиков и наблюдателей: их сессия остановлена,
+    // сообщения, ждавшие от них подтверждения, передаются другим участникам группы
+    fn remove_disconnected(&mut self, ctx: &mut Context<Self>) {
+        let disconnected: Vec<String> = self
+            .subscribers
+            .iter()
+            .filter(|(_, subscriber)| !subscriber.outlet.addr.connected())
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in disconnected {
+            println!("Клиент отключился от топика {} - {}", self.name, client_id);
+            self.remove_subscriber(&client_id, ctx);
+        }
+        self.watchers
+            .retain(|_, watcher| watcher.outlet.addr.connected());
+    }
+
+    // Удаление подписчика, неподтвержденные им сообщения группы
+    // передаются другим участникам этой группы
+    fn remove_subscriber(&mut self, client_id: &str, ctx: &mut Context<Self>) {
+        let Some(subscriber) = self.subscribers.remove(client_id) else {
+            return;
+        };
+
+        // Постоянная подписка ждет возвращения клиента вместе со своими
+        // неподтвержденными сообщениями
+        if let Some(durable) = self.durables.get_mut(client_id) {
+            // Приостановленная подписка продолжит с места паузы
+            if let Some(paused_at) = subscriber.paused_at {
+                durable.position = paused_at;
+            }
+            durable.expires_at = Some(now_millis() + self.config.subscription_expiry_ms);
+            return;
+        }
+
+        // Сообщения, которые ждали подтверждения от ушедшего клиента,
+        // счетчик попыток сохраняется при передаче другому участнику
+        let orphaned: Vec<Message> = self
+            .pending_acks
+            .values_mut()
+            .filter_map(|pending| {
+                pending
+                    .client_ids
+                    .remove(client_id)
+                    .then(|| pending.message.clone())
+            })
+            .collect();
+
+        if let Some(group_name) = subscriber.group {
+            if let Some(group) = self.groups.get_mut(&group_name) {
+                group.members.retain(|member| member != client_id);
+            }
+            // Перераспределяем сообщения между оставшимися участниками
+            for message in &orphaned {
+                let member = self
+                    .groups
+                    .get_mut(&group_name)
+                    .and_then(|group| group.pick(message.key.as_deref()));
+                if let Some(member) = member {
+                    self.send_to(&member, message, ctx);
+                }
+            }
+            if self
+                .groups
+                .get(&group_name)
+                .is_some_and(|group| group.members.is_empty())
+            {
+                self.groups.remove(&group_name);
+            }
+        }
+
+        self.forget_orphaned(orphaned, ctx);
+    }
+
+    // Сообщения, которые больше никто не ждет, забываем
+    fn forget_orphaned(&mut self, orphaned: Vec<Message>, ctx: &mut Context<Self>) {
+        for message in orphaned {
+            if let Some(pending) = self.pending_acks.get(&message.id) {
+                if pending.client_ids.is_empty() {
+                    if let Some(timer) = pending.timer {
+                        ctx.cancel_future(timer);
                     }
+                    self.pending_acks.remove(&message.id);
                 }
-            } else {
-                self.pending_acks.remove(&message_id);
             }
         }
     }
+
+    // Продвижение позиции постоянной подписки после доставки
+    fn advance(&mut self, client_id: &str, offset: u64) {
+        if let Some(durable) = self.durables.get_mut(client_id) {
+            durable.position = durable.position.max(offset + 1);
+        }
+    }
+
+    // Удаление постоянных подписок, клиент которых не вернулся вовремя
+    fn remove_expired(&mut self, ctx: &mut Context<Self>) {
+        let now = now_millis();
+        let expired: Vec<String> = self
+            .durables
+            .iter()
+            .filter(|(_, durable)| durable.expires_at.is_some_and(|at| at <= now))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in expired {
+            println!("Постоянная подписка {} истекла - {}", name, self.name);
+            self.durables.remove(&name);
+            let orphaned: Vec<Message> = self
+                .pending_acks
+                .values_mut()
+                .filter_map(|pending| {
+                    pending
+                        .client_ids
+                        .remove(&name)
+                        .then(|| pending.message.clone())
+                })
+                .collect();
+            self.forget_orphaned(orphaned, ctx);
+        }
+    }
 }
 
 impl Actor for Topic {
@@ -144,39 +1474,214 @@ impl Actor for Topic {
         ctx.run_interval(Duration::from_secs(60), |act, _| {
             act.clean_up_messages();
         });
+        // Место в топике или в памяти брокера может освободиться в любой момент,
+        // поэтому заблокированные публикации проверяются часто.
+        // Так же часто продолжаем доставку подписчикам, разобравшим свой буфер
+        ctx.run_interval(Duration::from_millis(100), |act, ctx| {
+            act.release_blocked(ctx);
+            act.resume_paused(ctx);
+            act.serve_fetches(ctx);
+        });
+        ctx.run_interval(DISCONNECT_CHECK_INTERVAL, |act, ctx| {
+            act.remove_disconnected(ctx);
+            act.remove_expired(ctx);
+        });
     }
 }
 
 impl Handler<PublishMessage> for Topic {
-    type Result = ();
+    type Result = ResponseFuture<Result<u64, String>>;
 
     // Обработка сообщения для публикации
     fn handle(&mut self, msg: PublishMessage, ctx: &mut Self::Context) -> Self::Result {
         let message = msg.0;
+        if let Err(error) = self.check_key(&message) {
+            return Box::pin(async move { Err(error) });
+        }
+        let size = message.size();
+        let fits_at_all = self.config.max_messages != Some(0)
+            && self.config.max_bytes.is_none_or(|max| size <= max);
+        if !fits_at_all {
+            let error = format!("Сообщение не помещается в топик {}", self.name);
+            return Box::pin(async move { Err(error) });
+        }
 
-        // Если включена компакция, то мы храним последнее сообщение для каждого ключа
-        if self.compaction {
-            if let Some(key) = &message.key {
-                self.last_message_by_key
-                    .insert(key.clone(), message.clone());
-            }
-        } else {
-            // Иначе просто добавляем сообщение в очередь
-            self.messages.push_back(message.clone());
+        // Издатель ждет, пока не освободится место; порядок публикаций сохраняется
+        if self.config.overflow == OverflowPolicy::Block
+            && (!self.blocked.is_empty() || !self.has_room(size))
+        {
+            let (tx, rx) = oneshot::channel();
+            self.blocked.push_back(BlockedPublish {
+                message,
+                deadline: Instant::now() + Duration::from_millis(self.config.block_timeout_ms),
+                tx,
+            });
+            return Box::pin(async move {
+                rx.await.unwrap_or_else(|_| Err("Топик удален".to_string()))
+            });
         }
 
-        // Отправляем сообщение подписчикам
-        self.deliver_message(&message, ctx);
+        let result = self.admit(message, ctx);
+        Box::pin(async move { result })
+    }
+}
+
+// Чтение сообщений по запросу, если их нет - ждем до истечения времени ожидания
+impl Handler<Fetch> for Topic {
+    type Result = ResponseFuture<Result<Vec<Message>, String>>;
+
+    fn handle(&mut self, msg: Fetch, ctx: &mut Self::Context) -> Self::Result {
+        let result = self.take_fetch(&msg, ctx);
+        let empty = matches!(&result, Ok(messages) if messages.is_empty());
+        if empty && !msg.wait.is_zero() {
+            let (tx, rx) = oneshot::channel();
+            self.fetches.push_back(WaitingFetch {
+                deadline: Instant::now() + msg.wait,
+                request: msg,
+                tx,
+            });
+            return Box::pin(async move {
+                rx.await.unwrap_or_else(|_| Err("Топик удален".to_string()))
+            });
+        }
+        Box::pin(async move { result })
+    }
+}
+
+// Поиск сообщения по offset
+impl Handler<GetMessage> for Topic {
+    type Result = Option<Message>;
+
+    fn handle(&mut self, msg: GetMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let message = self
+            .storage
+            .read_range(msg.offset, 1)
+            .ok()?
+            .into_iter()
+            .find(|m| m.offset == msg.offset)?;
+        // В компактном топике сообщение, замененное более новым, уже не видно
+        if self.config.compaction {
+            let Some(key) = message.key.as_deref() else {
+                return (self.config.keyless == KeylessPolicy::Keep).then_some(message);
+            };
+            let latest = self.storage.read_key(key).ok()??;
+            return (latest.offset == message.offset).then_some(message);
+        }
+        Some(message)
     }
 }
 
 // Обработка сообщения для подписки
 impl Handler<Subscribe> for Topic {
-    type Result = ();
+    type Result = Result<(), String>;
 
-    fn handle(&mut self, msg: Subscribe, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: Subscribe, ctx: &mut Self::Context) -> Self::Result {
+        self.check_client_id(&msg.client_id)?;
+        if msg.durable && msg.group.is_some() {
+            return Err("Постоянная подписка не может входить в группу".into());
+        }
+        if !msg.durable && self.durables.contains_key(&msg.client_id) {
+            return Err(format!(
+                "{} - имя постоянной подписки, подключитесь к ней",
+                msg.client_id
+            ));
+        }
+        // Подписка с id отключившегося клиента заменяет старую
+        self.remove_subscriber(&msg.client_id, ctx);
+
+        // Историю получает либо отдельный подписчик, либо первый участник
+        // новой группы, иначе сообщения задублируются внутри группы.
+        // Группа с сохраненной позицией продолжает с нее, постоянная подписка тоже
+        let resumed = self.durables.get(&msg.client_id).map(|d| d.position);
+        let replay = match &msg.group {
+            _ if resumed.is_some() => resumed.map(StartPosition::Offset),
+            Some(group) if self.groups.contains_key(group) => None,
+            Some(group) => match (&msg.from, self.group_offsets.get(group)) {
+                (StartPosition::Latest, Some(offset)) => Some(StartPosition::Offset(*offset)),
+                _ => Some(msg.from.clone()),
+            },
+            None => Some(msg.from.clone()),
+        };
+
+        if let Some(group) = &msg.group {
+            self.groups
+                .entry(group.clone())
+                .or_default()
+                .members
+                .push(msg.client_id.clone());
+        }
         // Добавляем подписчика
-        self.subscribers.insert(msg.client_id, msg.addr);
+        let outlet = Outlet {
+            topic: self.name.clone(),
+            addr: msg.addr,
+            buffer: msg.buffer,
+        };
+        self.subscribers.insert(
+            msg.client_id.clone(),
+            Subscriber {
+                outlet,
+                group: msg.group.clone(),
+                paused_at: None,
+            },
+        );
+        if msg.durable {
+            let position = self.storage.next_offset();
+            self.durables
+                .entry(msg.client_id.clone())
+                .or_insert(Durable {
+                    position,
+                    expires_at: None,
+                })
+                .expires_at = None;
+
+            // Неподтвержденные сообщения возвращаются клиенту первыми,
+            // попытки доставки при этом не расходуются
+            let mut unacked: Vec<Message> = self
+                .pending_acks
+                .values()
+                .filter(|pending| pending.client_ids.contains(&msg.client_id))
+                .map(|pending| pending.message.clone())
+                .collect();
+            unacked.sort_by_key(|message| message.offset);
+            for message in unacked {
+                self.subscribers[&msg.client_id].outlet.deliver(message);
+            }
+        }
+
+        // Актор обрабатывает сообщения по одному, поэтому между отправкой
+        // истории и началом живой доставки не может быть новой публикации.
+        // Что не поместилось в буфер, подписчик дочитает после паузы
+        if let Some(from) = replay {
+            let room = self.subscribers[&msg.client_id]
+                .outlet
+                .room(self.config.subscriber_buffer);
+            let backlog = self.backlog(&from);
+            let rest = backlog.get(room).map(|m| m.offset);
+            // История доставляется так же, как живые сообщения: с ожиданием
+            // подтверждения и сдвигом позиции группы или постоянной подписки
+            for message in backlog.iter().take(room) {
+                match &msg.group {
+                    Some(group) => self.offer_to_group(group, message, ctx),
+                    None => self.send_to(&msg.client_id, message, ctx),
+                }
+            }
+            if let Some(offset) = rest {
+                match &msg.group {
+                    Some(group) => {
+                        self.group_offsets.insert(group.clone(), offset);
+                        if let Some(group) = self.groups.get_mut(group) {
+                            group.paused = true;
+                        }
+                    }
+                    None => {
+                        if let Some(subscriber) = self.subscribers.get_mut(&msg.client_id) {
+                            subscriber.paused_at = Some(offset);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
     }
 }
 
@@ -184,9 +1689,11 @@ impl Handler<Subscribe> for Topic {
 impl Handler<Unsubscribe> for Topic {
     type Result = ();
 
-    fn handle(&mut self, msg: Unsubscribe, _ctx: &mut Self::Context) -> Self::Result {
+    fn handle(&mut self, msg: Unsubscribe, ctx: &mut Self::Context) -> Self::Result {
         // Удаляем подписчика
-        self.subscribers.remove(&msg.client_id);
+        self.durables.remove(&msg.client_id);
+        self.remove_subscriber(&msg.client_id, ctx);
+        self.watchers.remove(&msg.client_id);
     }
 }
 
@@ -194,14 +1701,325 @@ impl Handler<Unsubscribe> for Topic {
 impl Handler<Acknowledge> for Topic {
     type Result = ();
 
-    fn handle(&mut self, msg: Acknowledge, _ctx: &mut Self::Context) -> Self::Result {
-        if let Some(client_ids) = self.pending_acks.get_mut(&msg.message_id) {
+    fn handle(&mut self, msg: Acknowledge, ctx: &mut Self::Context) -> Self::Result {
+        if let Some(pending) = self.pending_acks.get_mut(&msg.message_id) {
             // Удаляем клиента из ожидающих
-            client_ids.remove(&msg.client_id);
-            // Если все получили сообщение, удаляем из ожидающих
-            if client_ids.is_empty() {
+            pending.client_ids.remove(&msg.client_id);
+            // Если все получили сообщение, удаляем из ожидающих и отменяем таймер
+            if pending.client_ids.is_empty() {
+                if let Some(timer) = pending.timer.take() {
+                    ctx.cancel_future(timer);
+                }
+                self.pending_acks.remove(&msg.message_id);
+            }
+        }
+    }
+}
+
+// Обработка отрицательного подтверждения
+impl Handler<Nack> for Topic {
+    type Result = ();
+
+    fn handle(&mut self, msg: Nack, ctx: &mut Self::Context) -> Self::Result {
+        let Some(pending) = self.pending_acks.get_mut(&msg.message_id) else {
+            return;
+        };
+        // Клиент может отказаться только от сообщения, которое ему доставлено
+        if !pending.client_ids.contains(&msg.client_id) {
+            return;
+        }
+        pending.last_failure = Some(msg.reason.unwrap_or_else(|| "nack".into()));
+        if let Some(timer) = pending.timer.take() {
+            ctx.cancel_future(timer);
+        }
+
+        // Отказ или исчерпанные попытки - сообщение уходит в dead-letter топик
+        if msg.action == NackAction::Reject || pending.attempts >= self.config.max_delivery_attempts
+        {
+            pending.client_ids.remove(&msg.client_id);
+            let message = pending.message.clone();
+            let attempts = pending.attempts;
+            let reason = pending.last_failure.clone();
+            if pending.client_ids.is_empty() {
                 self.pending_acks.remove(&msg.message_id);
+            } else {
+                // Остальные клиенты продолжают ждать по обычному расписанию
+                self.schedule_ack_check(msg.message_id.clone(), attempts, ctx);
             }
+            self.dead_letter(message, attempts, reason);
+            return;
         }
+
+        match msg.action {
+            NackAction::Delay { delay_ms } => {
+                let handle = ctx.run_later(Duration::from_millis(delay_ms), move |act, ctx| {
+                    act.redeliver(&msg.message_id, &msg.client_id, ctx);
+                });
+                pending.timer = Some(handle);
+            }
+            _ => self.redeliver(&msg.message_id, &msg.client_id, ctx),
+        }
+    }
+}
+
+// Снимок состояния топика: настройки, сообщения, позиции групп и подписок,
+// ожидающие подтверждения
+impl Handler<TakeSnapshot> for Topic {
+    type Result = MessageResult<TakeSnapshot>;
+
+    fn handle(&mut self, _msg: TakeSnapshot, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(TopicSnapshot {
+            name: self.name.clone(),
+            config: self.config.clone(),
+            next_offset: self.storage.next_offset(),
+            messages: self.retained_messages(),
+            group_offsets: self.group_offsets.clone(),
+            durables: self
+                .durables
+                .iter()
+                .map(|(name, durable)| (name.clone(), durable.position))
+                .collect(),
+            pending_acks: self
+                .pending_acks
+                .values()
+                .map(|pending| PendingSnapshot {
+                    message: pending.message.clone(),
+                    client_ids: pending.client_ids.iter().cloned().collect(),
+                    attempts: pending.attempts,
+                    last_failure: pending.last_failure.clone(),
+                    fetch_group: pending.fetch_group.clone(),
+                    refetch: pending.refetch,
+                })
+                .collect(),
+        })
+    }
+}
+
+// Восстановление топика из снимка, offset'ы сообщений сохраняются
+impl Handler<RestoreSnapshot> for Topic {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: RestoreSnapshot, ctx: &mut Self::Context) -> Self::Result {
+        let snapshot = msg.0;
+        if !self.storage.is_empty() || self.storage.next_offset() > 0 {
+            return Err(format!("Топик {} уже содержит сообщения", self.name));
+        }
+        for message in snapshot.messages {
+            self.storage
+                .append(message)
+                .map_err(|e| format!("Ошибка записи сообщения: {}", e))?;
+        }
+        self.storage
+            .skip_to(snapshot.next_offset)
+            .map_err(|e| format!("Ошибка записи сообщения: {}", e))?;
+        self.group_offsets = snapshot.group_offsets;
+        // Постоянные подписки ждут своих клиентов как после отключения
+        let expires_at = now_millis() + self.config.subscription_expiry_ms;
+        for (name, position) in snapshot.durables {
+            self.durables.insert(
+                name,
+                Durable {
+                    position,
+                    expires_at: Some(expires_at),
+                },
+            );
+        }
+
+        // Ожидающие подтверждения сообщения продолжают повторную доставку,
+        // клиенты получат их, если переподключатся с тем же id
+        for pending in snapshot.pending_acks {
+            let message_id = pending.message.id.clone();
+            self.pending_acks.insert(
+                message_id.clone(),
+                PendingAck {
+                    message: pending.message,
+                    client_ids: pending.client_ids.into_iter().collect(),
+                    attempts: pending.attempts,
+                    last_failure: pending.last_failure,
+                    timer: None,
+                    fetch_group: pending.fetch_group,
+                    refetch: pending.refetch,
+                },
+            );
+            self.schedule_ack_check(message_id, pending.attempts, ctx);
+        }
+        self.sync_budget();
+        Ok(())
+    }
+}
+
+// Описание топика
+impl Handler<Describe> for Topic {
+    type Result = MessageResult<Describe>;
+
+    fn handle(&mut self, _msg: Describe, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(TopicDescription {
+            name: self.name.clone(),
+            config: self.config.clone(),
+            message_count: self.storage.len(),
+            size_bytes: self.storage.size_bytes(),
+            next_offset: self.storage.next_offset(),
+            subscriber_count: self.subscribers.len(),
+            watcher_count: self.watchers.len(),
+            groups: self
+                .groups
+                .iter()
+                .map(|(name, group)| (name.clone(), group.members.len()))
+                .collect(),
+            pending_acks: self.pending_acks.len(),
+            blocked_publishes: self.blocked.len(),
+            waiting_fetches: self.fetches.len(),
+            durable_subscriptions: self
+                .durables
+                .iter()
+                .map(|(name, durable)| {
+                    (
+                        name.clone(),
+                        DurableDescription {
+                            position: durable.position,
+                            connected: durable.expires_at.is_none(),
+                            expires_at: durable.expires_at,
+                        },
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
+// Изменение настроек топика
+impl Handler<AlterConfig> for Topic {
+    type Result = ();
+
+    fn handle(&mut self, msg: AlterConfig, _ctx: &mut Self::Context) -> Self::Result {
+        self.config = msg.config;
+        self.dead_letter = msg.dead_letter;
+        // Новая политика хранения сразу применяется к сохраненным сообщениям:
+        // короткий retention удаляет старые, включенная компакция схлопывает ключи.
+        // Новое ожидание подтверждения действует со следующей проверки
+        self.clean_up_messages();
+        println!("Настройки топика изменены - {}", self.name);
+    }
+}
+
+// Удаление топика
+impl Handler<DeleteTopic> for Topic {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DeleteTopic, ctx: &mut Self::Context) -> Self::Result {
+        // Сессии клиентов следят за своим соединением и сами не остановятся,
+        // поэтому потоки подписчиков и наблюдателей завершаются явно:
+        // клиент дочитывает буфер, и ответ закрывается
+        for subscriber in self.subscribers.values() {
+            subscriber.outlet.disconnect();
+        }
+        for watcher in self.watchers.values() {
+            watcher.outlet.disconnect();
+        }
+        self.subscribers.clear();
+        self.watchers.clear();
+        self.groups.clear();
+        self.durables.clear();
+        self.pending_acks.clear();
+        // Ожидающие издатели и fetch получают ошибку, память возвращается в бюджет
+        self.blocked.clear();
+        self.fetches.clear();
+        if let Some(budget) = &self.budget {
+            budget.update(self.accounted, 0);
+            self.accounted = 0;
+        }
+        ctx.stop();
+        println!("Топик удален - {}", self.name);
+    }
+}
+
+// Значение ключа
+impl Handler<GetKey> for Topic {
+    type Result = Result<Option<Message>, String>;
+
+    fn handle(&mut self, msg: GetKey, _ctx: &mut Self::Context) -> Self::Result {
+        self.current_value(&msg.key)
+    }
+}
+
+// Страница ключей
+impl Handler<ListKeys> for Topic {
+    type Result = Result<KeyPage, String>;
+
+    fn handle(&mut self, msg: ListKeys, _ctx: &mut Self::Context) -> Self::Result {
+        let mut keys = Vec::new();
+        let mut next = None;
+        for key in self.sorted_keys(msg.prefix.as_deref(), msg.after.as_deref()) {
+            if self.current_value(&key)?.is_none() {
+                continue;
+            }
+            // Курсор нужен, только если после страницы есть еще живой ключ
+            if keys.len() == msg.limit {
+                next = keys.last().cloned();
+                break;
+            }
+            keys.push(key);
+        }
+        Ok(KeyPage { keys, next })
+    }
+}
+
+// Все текущие значения
+impl Handler<GetTable> for Topic {
+    type Result = Result<TableSnapshot, String>;
+
+    fn handle(&mut self, _msg: GetTable, _ctx: &mut Self::Context) -> Self::Result {
+        let mut entries = BTreeMap::new();
+        for key in self.sorted_keys(None, None) {
+            if let Some(message) = self.current_value(&key)? {
+                entries.insert(key, message.payload.unwrap_or_default());
+            }
+        }
+        Ok(TableSnapshot {
+            next_offset: self.storage.next_offset(),
+            entries,
+        })
+    }
+}
+
+// Подписка на изменения ключей
+impl Handler<Watch> for Topic {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Watch, _ctx: &mut Self::Context) -> Self::Result {
+        if msg.keys.is_empty() && msg.prefix.is_none() {
+            return Err("Нужно указать ключи или префикс".into());
+        }
+        self.check_client_id(&msg.client_id)?;
+
+        // Текущие значения отправляем до регистрации, актор обрабатывает
+        // сообщения по одному, поэтому изменение между ними не потеряется
+        let outlet = Outlet {
+            topic: self.name.clone(),
+            addr: msg.addr,
+            buffer: msg.buffer,
+        };
+        let mut keys: Vec<String> = msg.keys.iter().cloned().collect();
+        if let Some(prefix) = &msg.prefix {
+            keys.extend(self.sorted_keys(Some(prefix), None));
+        }
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            if let Some(message) = self.current_value(&key)? {
+                outlet.deliver(message);
+            }
+        }
+
+        self.watchers.insert(
+            msg.client_id,
+            Watcher {
+                outlet,
+                keys: msg.keys,
+                prefix: msg.prefix,
+                stale: false,
+            },
+        );
+        Ok(())
     }
 }
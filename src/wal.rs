@@ -0,0 +1,430 @@
+use crate::message::{now_millis, Message};
+use crate::topic::TopicConfig;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Файл с описанием топика внутри его каталога
+const TOPIC_FILE: &str = "topic.json";
+const LOG_EXTENSION: &str = "log";
+const INDEX_EXTENSION: &str = "index";
+// Запись индекса: offset (u64) + позиция записи в сегменте (u64)
+const INDEX_ENTRY_SIZE: u64 = 16;
+
+// Когда сбрасывать записанные данные на диск
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // После каждого сообщения
+    Always,
+    // После каждых N сообщений
+    Every(u32),
+    // Оставляем на усмотрение ОС
+    Never,
+}
+
+// Разбор политики из строки: always, never или число сообщений
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => s
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .map(FsyncPolicy::Every)
+                .ok_or_else(|| format!("Некорректная политика fsync: {}", s)),
+        }
+    }
+}
+
+// Настройки хранения журналов на диске
+#[derive(Debug, Clone)]
+pub struct WalOptions {
+    // Каталог, в котором у каждого топика свой подкаталог
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    // Размер сегмента, после которого начинается новый
+    pub segment_bytes: u64,
+}
+
+impl WalOptions {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        WalOptions {
+            dir: dir.into(),
+            fsync: FsyncPolicy::Every(100),
+            segment_bytes: 16 * 1024 * 1024,
+        }
+    }
+
+    // Каталог конкретного топика
+    pub fn topic_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(encode_name(name))
+    }
+}
+
+// Описание топика, которое хранится рядом с его журналом
+#[derive(Serialize, Deserialize)]
+pub struct StoredTopic {
+    pub name: String,
+    pub config: TopicConfig,
+}
+
+// Сохранение описания топика
+pub fn save_topic(dir: &Path, name: &str, config: &TopicConfig) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let stored = StoredTopic {
+        name: name.to_string(),
+        config: config.clone(),
+    };
+    let tmp = dir.join(format!("{}.tmp", TOPIC_FILE));
+    fs::write(&tmp, serde_json::to_vec_pretty(&stored)?)?;
+    // Переименование атомарно, поэтому описание не окажется записанным наполовину
+    fs::rename(tmp, dir.join(TOPIC_FILE))
+}
+
+// Все топики, сохраненные в каталоге данных
+pub fn load_topics(options: &WalOptions) -> io::Result<Vec<StoredTopic>> {
+    let mut topics = Vec::new();
+    if !options.dir.exists() {
+        return Ok(topics);
+    }
+    for entry in fs::read_dir(&options.dir)? {
+        let path = entry?.path().join(TOPIC_FILE);
+        if path.is_file() {
+            topics.push(serde_json::from_slice(&fs::read(path)?)?);
+        }
+    }
+    Ok(topics)
+}
+
+// Имя каталога топика: безопасные символы как есть, остальные байты в виде %XX
+fn encode_name(name: &str) -> String {
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// Сегмент журнала, файлы называются по offset первого сообщения
+struct Segment {
+    base_offset: u64,
+    // Offset, который получит следующее сообщение в этом сегменте
+    next_offset: u64,
+    // Размер файла с сообщениями
+    size: u64,
+    // Индекс сегмента в памяти: offset -> позиция записи в файле
+    entries: Vec<(u64, u64)>,
+}
+
+impl Segment {
+    fn path(dir: &Path, base_offset: u64, extension: &str) -> PathBuf {
+        dir.join(format!("{:020}.{}", base_offset, extension))
+    }
+}
+
+// Журнал топика: append-only сегменты с сообщениями и индекс offset -> позиция
+pub struct TopicLog {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    segment_bytes: u64,
+    // Сегменты по возрастанию offset, последний - активный
+    segments: Vec<Segment>,
+    // Открытые файлы активного сегмента
+    log: File,
+    index: File,
+    // Сколько сообщений записано после последнего fsync
+    unsynced: u32,
+}
+
+impl TopicLog {
+    // Открытие журнала топика, возвращает все сохраненные в нем сообщения.
+    // Недописанная запись в конце (например после падения) отбрасывается
+    pub fn open(dir: PathBuf, options: &WalOptions) -> io::Result<(TopicLog, Vec<Message>)> {
+        fs::create_dir_all(&dir)?;
+
+        let mut base_offsets: Vec<u64> = fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let path = entry.path();
+                if path.extension()? != LOG_EXTENSION {
+                    return None;
+                }
+                path.file_stem()?.to_str()?.parse().ok()
+            })
+            .collect();
+        base_offsets.sort_unstable();
+        if base_offsets.is_empty() {
+            base_offsets.push(0);
+        }
+
+        let mut segments = Vec::new();
+        let mut messages = Vec::new();
+        for base_offset in base_offsets {
+            let (segment, mut segment_messages) = recover_segment(&dir, base_offset)?;
+            segments.push(segment);
+            messages.append(&mut segment_messages);
+        }
+
+        let active = segments.last().expect("есть хотя бы один сегмент");
+        let log = open_append(&Segment::path(&dir, active.base_offset, LOG_EXTENSION))?;
+        let index = open_append(&Segment::path(&dir, active.base_offset, INDEX_EXTENSION))?;
+        Ok((
+            TopicLog {
+                dir,
+                fsync: options.fsync,
+                segment_bytes: options.segment_bytes,
+                segments,
+                log,
+                index,
+                unsynced: 0,
+            },
+            messages,
+        ))
+    }
+
+    // Offset, который получит следующее сообщение
+    pub fn next_offset(&self) -> u64 {
+        self.segments
+            .last()
+            .map_or(0, |segment| segment.next_offset)
+    }
+
+    // Запись сообщения в конец журнала
+    pub fn append(&mut self, message: &Message) -> io::Result<()> {
+        if self.active().size >= self.segment_bytes
+            && self.active().next_offset > self.active().base_offset
+        {
+            self.roll(message.offset)?;
+        }
+
+        let bytes = serde_json::to_vec(message)?;
+        let position = self.active().size;
+        let mut record = Vec::with_capacity(4 + bytes.len());
+        record.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        record.extend_from_slice(&bytes);
+        self.log.write_all(&record)?;
+
+        let mut entry = [0u8; INDEX_ENTRY_SIZE as usize];
+        entry[..8].copy_from_slice(&message.offset.to_le_bytes());
+        entry[8..].copy_from_slice(&position.to_le_bytes());
+        self.index.write_all(&entry)?;
+
+        let segment = self.active_mut();
+        segment.size += record.len() as u64;
+        segment.next_offset = message.offset + 1;
+        segment.entries.push((message.offset, position));
+
+        self.unsynced += 1;
+        match self.fsync {
+            FsyncPolicy::Always => self.sync()?,
+            FsyncPolicy::Every(n) if self.unsynced >= n => self.sync()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Сброс записанных данных на диск
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.log.sync_data()?;
+            self.index.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    // Удаление сегментов, все сообщения которых младше offset.
+    // Активный сегмент не удаляется, по нему восстанавливается следующий offset
+    pub fn remove_before(&mut self, offset: u64) -> io::Result<()> {
+        while self.segments.len() > 1 && self.segments[0].next_offset <= offset {
+            let segment = self.segments.remove(0);
+            fs::remove_file(Segment::path(&self.dir, segment.base_offset, LOG_EXTENSION))?;
+            fs::remove_file(Segment::path(
+                &self.dir,
+                segment.base_offset,
+                INDEX_EXTENSION,
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn active(&self) -> &Segment {
+        self.segments.last().expect("есть хотя бы один сегмент")
+    }
+
+    fn active_mut(&mut self) -> &mut Segment {
+        self.segments.last_mut().expect("есть хотя бы один сегмент")
+    }
+
+    // Новый активный сегмент начиная с base_offset
+    fn roll(&mut self, base_offset: u64) -> io::Result<()> {
+        self.sync()?;
+        self.log = open_append(&Segment::path(&self.dir, base_offset, LOG_EXTENSION))?;
+        self.index = open_append(&Segment::path(&self.dir, base_offset, INDEX_EXTENSION))?;
+        self.segments.push(Segment {
+            base_offset,
+            next_offset: base_offset,
+            size: 0,
+            entries: Vec::new(),
+        });
+        Ok(())
+    }
+
+    // Пропуск offset'ов: новый сегмент начинается с offset, поэтому
+    // следующий offset восстановится и после перезапуска
+    pub fn skip_to(&mut self, offset: u64) -> io::Result<()> {
+        if offset > self.next_offset() {
+            self.roll(offset)?;
+        }
+        Ok(())
+    }
+
+    // Чтение сообщения по offset через индекс
+    pub fn read(&self, offset: u64) -> io::Result<Option<Message>> {
+        let index = self
+            .segments
+            .partition_point(|segment| segment.base_offset <= offset);
+        let Some(segment) = index.checked_sub(1).map(|index| &self.segments[index]) else {
+            return Ok(None);
+        };
+        let Ok(entry) = segment
+            .entries
+            .binary_search_by_key(&offset, |(offset, _)| *offset)
+        else {
+            return Ok(None);
+        };
+
+        let mut file = File::open(Segment::path(&self.dir, segment.base_offset, LOG_EXTENSION))?;
+        file.seek(SeekFrom::Start(segment.entries[entry].1))?;
+        let mut len = [0u8; 4];
+        file.read_exact(&mut len)?;
+        let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+        file.read_exact(&mut bytes)?;
+        let mut message: Message = serde_json::from_slice(&bytes)?;
+        restore_timestamp(&mut message);
+        Ok(Some(message))
+    }
+
+    // Перезапись закрытых сегментов, в них остаются только сообщения,
+    // для которых keep вернул true. Активный сегмент не трогаем
+    pub fn compact(&mut self, keep: impl Fn(&Message) -> bool) -> io::Result<()> {
+        let sealed = self.segments.len() - 1;
+        for segment in &mut self.segments[..sealed] {
+            let log_path = Segment::path(&self.dir, segment.base_offset, LOG_EXTENSION);
+            let index_path = Segment::path(&self.dir, segment.base_offset, INDEX_EXTENSION);
+            let (_, messages) = recover_segment(&self.dir, segment.base_offset)?;
+            if messages.iter().all(&keep) {
+                continue;
+            }
+
+            let mut records = Vec::new();
+            let mut index = Vec::new();
+            let mut entries = Vec::new();
+            for message in messages.iter().filter(|m| keep(m)) {
+                let bytes = serde_json::to_vec(message)?;
+                let position = records.len() as u64;
+                index.extend_from_slice(&message.offset.to_le_bytes());
+                index.extend_from_slice(&position.to_le_bytes());
+                entries.push((message.offset, position));
+                records.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                records.extend_from_slice(&bytes);
+            }
+
+            // Сначала пишем новую версию рядом, затем атомарно подменяем
+            let tmp = log_path.with_extension("compact");
+            fs::write(&tmp, &records)?;
+            File::open(&tmp)?.sync_all()?;
+            fs::rename(&tmp, &log_path)?;
+            fs::write(&index_path, &index)?;
+            segment.size = records.len() as u64;
+            segment.entries = entries;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TopicLog {
+    fn drop(&mut self) {
+        let _ = self.sync();
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Чтение сегмента с обрезкой поврежденного хвоста и перестроением индекса
+fn recover_segment(dir: &Path, base_offset: u64) -> io::Result<(Segment, Vec<Message>)> {
+    let log_path = Segment::path(dir, base_offset, LOG_EXTENSION);
+    let index_path = Segment::path(dir, base_offset, INDEX_EXTENSION);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&log_path)?;
+
+    let mut messages = Vec::new();
+    let mut index = Vec::new();
+    let mut entries = Vec::new();
+    let mut position = 0u64;
+    {
+        let mut reader = BufReader::new(&mut file);
+        loop {
+            let mut len = [0u8; 4];
+            if reader.read_exact(&mut len).is_err() {
+                break;
+            }
+            let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+            if reader.read_exact(&mut bytes).is_err() {
+                break;
+            }
+            let Ok(mut message) = serde_json::from_slice::<Message>(&bytes) else {
+                break;
+            };
+            restore_timestamp(&mut message);
+            index.extend_from_slice(&message.offset.to_le_bytes());
+            index.extend_from_slice(&position.to_le_bytes());
+            entries.push((message.offset, position));
+            position += 4 + bytes.len() as u64;
+            messages.push(message);
+        }
+    }
+
+    // Все, что после последней целой записи, отбрасываем
+    if file.metadata()?.len() != position {
+        println!("Журнал {} обрезан до {} байт", log_path.display(), position);
+        file.set_len(position)?;
+        file.seek(SeekFrom::End(0))?;
+    }
+    // Индекс восстанавливается по сегменту, так что он всегда с ним согласован
+    fs::write(&index_path, &index)?;
+
+    let next_offset = messages.last().map_or(base_offset, |m| m.offset + 1);
+    Ok((
+        Segment {
+            base_offset,
+            next_offset,
+            size: position,
+            entries,
+        },
+        messages,
+    ))
+}
+
+// Instant не сериализуется, восстанавливаем его по времени публикации
+fn restore_timestamp(message: &mut Message) {
+    let age = Duration::from_millis(now_millis().saturating_sub(message.published_at));
+    message.timestamp = Instant::now().checked_sub(age).or(Some(Instant::now()));
+}
@@ -0,0 +1,301 @@
+use crate::{
+    broker::Broker,
+    client::{self, TopicSessions, HEARTBEAT_INTERVAL},
+    message::Message,
+    topic::{DeliverMessage, NackAction, StartPosition},
+};
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::{body::BodyStream, error, web, Error, HttpRequest, HttpResponse};
+use futures::{channel::mpsc, lock::Mutex, stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// Параметры подключения
+#[derive(Deserialize)]
+pub struct ConnectRequest {
+    // Постоянный id клиента, по умолчанию создается новый
+    client_id: Option<String>,
+}
+
+// Кадр от клиента: JSON с полем type, request_id возвращается в ответе
+#[derive(Deserialize)]
+struct ClientFrame {
+    request_id: Option<String>,
+    #[serde(flatten)]
+    request: Request,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Subscribe {
+        topic: String,
+        // Позиция начала чтения, как у /subscribe
+        from: Option<String>,
+        group: Option<String>,
+        // Постоянная подписка с именем, равным id клиента
+        #[serde(default)]
+        durable: bool,
+    },
+    Unsubscribe {
+        topic: String,
+    },
+    Publish {
+        topic: String,
+        key: Option<String>,
+        payload: Option<String>,
+        #[serde(default)]
+        require_ack: bool,
+    },
+    Ack {
+        topic: String,
+        message_id: String,
+    },
+    Nack {
+        topic: String,
+        message_id: String,
+        #[serde(flatten)]
+        action: NackAction,
+        reason: Option<String>,
+    },
+}
+
+// Кадр от сервера
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Event<'a> {
+    Connected {
+        client_id: &'a str,
+    },
+    Message {
+        topic: &'a str,
+        message: &'a Message,
+    },
+    Ok {
+        request_id: Option<String>,
+    },
+    Published {
+        request_id: Option<String>,
+        id: String,
+        offset: u64,
+    },
+    Error {
+        request_id: Option<String>,
+        error: String,
+    },
+}
+
+// Что отправить клиенту помимо сообщений топиков
+enum Outgoing {
+    Delivery(DeliverMessage),
+    Event(String),
+    Pong(web::Bytes),
+    Close(Option<ws::CloseReason>),
+}
+
+// Соединение: один id клиента на все подписки, у каждой подписки своя сессия
+struct Connection {
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    client_id: String,
+    sessions: TopicSessions,
+    tx: mpsc::UnboundedSender<Outgoing>,
+}
+
+fn event_text(event: &Event) -> String {
+    serde_json::to_string(event).unwrap_or_default()
+}
+
+impl Connection {
+    fn send(&self, event: &Event) {
+        let _ = self.tx.unbounded_send(Outgoing::Event(event_text(event)));
+    }
+
+    // Обработка запроса клиента, ответ уходит тем же соединением
+    async fn handle(&mut self, text: &[u8]) {
+        let frame: ClientFrame = match serde_json::from_slice(text) {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.send(&Event::Error {
+                    request_id: None,
+                    error: format!("Неверный кадр: {}", e),
+                });
+                return;
+            }
+        };
+        let request_id = frame.request_id;
+        match self.apply(frame.request).await {
+            Ok(Some((id, offset))) => self.send(&Event::Published {
+                request_id,
+                id,
+                offset,
+            }),
+            Ok(None) => self.send(&Event::Ok { request_id }),
+            Err(error) => self.send(&Event::Error { request_id, error }),
+        }
+    }
+
+    // Для публикации возвращает id и offset сообщения
+    async fn apply(&mut self, request: Request) -> Result<Option<(String, u64)>, String> {
+        match request {
+            Request::Publish {
+                topic,
+                key,
+                payload,
+                require_ack,
+            } => {
+                let message = Message::with_payload(payload, key, require_ack);
+                let id = message.id.clone();
+                // Брокер отпускаем до ожидания публикации
+                let publish = self.broker.lock().await.publish_message(&topic, message);
+                return Ok(Some((id, publish.await?)));
+            }
+            Request::Subscribe {
+                topic,
+                from,
+                group,
+                durable,
+            } => {
+                let from = match from {
+                    Some(from) => from.parse()?,
+                    None => StartPosition::default(),
+                };
+                let client_id = self.client_id.clone();
+                self.sessions
+                    .subscribe(&self.broker, &topic, client_id, from, group, durable)
+                    .await?;
+                return Ok(None);
+            }
+            _ => {}
+        }
+
+        let broker = self.broker.lock().await;
+        match request {
+            Request::Unsubscribe { topic } => {
+                broker.unsubscribe(&topic, self.client_id.clone())?;
+                self.sessions.close(&topic);
+            }
+            Request::Ack { topic, message_id } => {
+                broker.acknowledge(&topic, self.client_id.clone(), message_id)?
+            }
+            Request::Nack {
+                topic,
+                message_id,
+                action,
+                reason,
+            } => broker.nack(&topic, self.client_id.clone(), message_id, action, reason)?,
+            Request::Publish { .. } | Request::Subscribe { .. } => {}
+        }
+        Ok(None)
+    }
+}
+
+// Чтение кадров клиента, пока соединение не закрыто
+async fn read_frames(mut payload: web::Payload, mut connection: Connection) {
+    let mut codec = ws::Codec::new();
+    let mut buf = web::BytesMut::new();
+    loop {
+        let frame = match codec.decode(&mut buf) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => match payload.next().await {
+                Some(Ok(chunk)) => {
+                    buf.extend_from_slice(&chunk);
+                    continue;
+                }
+                // Клиент отключился
+                _ => return,
+            },
+            Err(e) => {
+                connection.send(&Event::Error {
+                    request_id: None,
+                    error: e.to_string(),
+                });
+                let _ = connection.tx.unbounded_send(Outgoing::Close(None));
+                return;
+            }
+        };
+        match frame {
+            ws::Frame::Text(text) => connection.handle(&text).await,
+            ws::Frame::Ping(data) => {
+                let _ = connection.tx.unbounded_send(Outgoing::Pong(data));
+            }
+            ws::Frame::Pong(_) => {}
+            ws::Frame::Close(reason) => {
+                let _ = connection.tx.unbounded_send(Outgoing::Close(reason));
+                return;
+            }
+            ws::Frame::Binary(_) | ws::Frame::Continuation(_) => connection.send(&Event::Error {
+                request_id: None,
+                error: "Поддерживаются только текстовые кадры JSON".into(),
+            }),
+        }
+    }
+}
+
+// WebSocket: в одном соединении можно подписаться на несколько топиков,
+// публиковать и подтверждать сообщения. Сообщения топиков приходят кадрами
+// {"type": "message", "topic": ..., "message": ...}, в паузах сервер шлет ping
+pub async fn connect(
+    broker: web::Data<Arc<Mutex<Broker>>>,
+    req_http: HttpRequest,
+    query: web::Query<ConnectRequest>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let client_id = client::client_id(&req_http, query.client_id.as_deref())?;
+    let mut handshake = ws::handshake(req_http.head())?;
+
+    let (sessions, deliveries) = TopicSessions::new();
+    let (tx, rx) = mpsc::unbounded();
+    let _ = tx.unbounded_send(Outgoing::Event(event_text(&Event::Connected {
+        client_id: &client_id,
+    })));
+    let outgoing = stream::select(deliveries.map(Outgoing::Delivery), rx);
+    actix_web::rt::spawn(read_frames(
+        payload,
+        Connection {
+            broker,
+            client_id: client_id.clone(),
+            sessions,
+            tx,
+        },
+    ));
+    println!("Клиент подключился по WebSocket, ID клиента: {}", client_id);
+
+    // Когда поток ответа удаляется, буферы подписок закрываются и сессии
+    // останавливаются, а топики отписывают клиента
+    let frames = stream::unfold(
+        (outgoing, ws::Codec::new(), false),
+        |(mut outgoing, mut codec, closed)| async move {
+            if closed {
+                return None;
+            }
+            let next = actix_web::rt::time::timeout(HEARTBEAT_INTERVAL, outgoing.next()).await;
+            let (frame, closed) = match next {
+                Ok(Some(Outgoing::Delivery(delivery))) => {
+                    let event = Event::Message {
+                        topic: &delivery.topic,
+                        message: &delivery.message,
+                    };
+                    (ws::Message::Text(event_text(&event).into()), false)
+                }
+                Ok(Some(Outgoing::Event(text))) => (ws::Message::Text(text.into()), false),
+                Err(_) => (ws::Message::Ping(web::Bytes::new()), false),
+                Ok(Some(Outgoing::Pong(data))) => (ws::Message::Pong(data), false),
+                Ok(Some(Outgoing::Close(reason))) => (ws::Message::Close(reason), true),
+                Ok(None) => return None,
+            };
+            let mut buf = web::BytesMut::new();
+            let bytes = codec
+                .encode(frame, &mut buf)
+                .map(|()| buf.freeze())
+                .map_err(error::ErrorInternalServerError);
+            Some((bytes, (outgoing, codec, closed)))
+        },
+    );
+    let response = handshake.message_body(BodyStream::new(frames))?;
+    Ok(HttpResponse::from(response).map_into_boxed_body())
+}
+
+pub fn init_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/ws").route(web::get().to(connect)));
+}
@@ -0,0 +1,227 @@
+use actix_web::{http::StatusCode, test, web, App};
+use futures::lock::Mutex;
+use mem_broker::{broker::Broker, client::init_routes};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+#[actix_web::test]
+async fn topics_are_listed_described_and_deleted() {
+    let broker = web::Data::new(Arc::new(Mutex::new(Broker::new())));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "jobs", "retention": 60, "dead_letter_topic": "jobs_dlq"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/publish")
+        .set_json(json!({"topic": "jobs", "key": null, "payload": "p", "require_ack": false}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let req = test::TestRequest::get().uri("/admin/topics").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!(["jobs", "jobs_dlq"]));
+
+    let req = test::TestRequest::get()
+        .uri("/admin/topics/jobs")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["config"]["retention"], 60);
+    assert_eq!(body["message_count"], 1);
+    assert_eq!(body["next_offset"], 1);
+    assert_eq!(body["subscriber_count"], 0);
+    assert_eq!(body["pending_acks"], 0);
+
+    // Dead-letter топик нельзя удалить, пока на него ссылается другой топик
+    let req = test::TestRequest::delete()
+        .uri("/admin/topics/jobs_dlq")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    for name in ["jobs", "jobs_dlq"] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/admin/topics/{}", name))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/admin/topics/jobs")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+    let req = test::TestRequest::get().uri("/admin/topics").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!([]));
+}
+
+#[actix_web::test]
+async fn topic_config_is_altered_at_runtime() {
+    let broker = web::Data::new(Arc::new(Mutex::new(Broker::new())));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "jobs", "ack_timeout_ms": 1000}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::post()
+        .uri("/publish")
+        .set_json(json!({"topic": "jobs", "key": null, "payload": "p", "require_ack": false}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(5)).await;
+
+    // Нулевой retention сразу удаляет уже сохраненные сообщения
+    let req = test::TestRequest::patch()
+        .uri("/admin/topics/jobs")
+        .set_json(json!({"retention": 0}))
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["retention"], 0);
+    // Поля, которых нет в запросе, не меняются
+    assert_eq!(body["ack_timeout_ms"], 1000);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/topics/jobs")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["message_count"], 0);
+
+    let req = test::TestRequest::patch()
+        .uri("/admin/topics/jobs")
+        .set_json(json!({"storage": "file"}))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn dead_letter_cycles_are_rejected() {
+    let broker = web::Data::new(Arc::new(Mutex::new(Broker::new())));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+
+    // a -> b -> c
+    for (name, dead_letter) in [("c", None), ("b", Some("c")), ("a", Some("b"))] {
+        let req = test::TestRequest::post()
+            .uri("/create_topic")
+            .set_json(json!({"name": name, "dead_letter_topic": dead_letter}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    // Цепочка c -> a -> b -> c замкнулась бы на c
+    let req = test::TestRequest::patch()
+        .uri("/admin/topics/c")
+        .set_json(json!({"dead_letter_topic": "a"}))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let req = test::TestRequest::get().uri("/admin/topics/c").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["config"]["dead_letter_topic"], Value::Null);
+
+    // После смены dead-letter топика у a старая цепочка больше не мешает
+    let req = test::TestRequest::patch()
+        .uri("/admin/topics/a")
+        .set_json(json!({"dead_letter_topic": null}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::patch()
+        .uri("/admin/topics/c")
+        .set_json(json!({"dead_letter_topic": "a"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+}
+
+#[actix_web::test]
+async fn broker_memory_budget_is_shared_by_topics() {
+    let broker = Broker::new();
+    broker.set_memory_limit(Some(4));
+    let broker = web::Data::new(Arc::new(Mutex::new(broker)));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+
+    for (name, overflow) in [("drop", "drop_oldest"), ("reject", "reject")] {
+        let req = test::TestRequest::post()
+            .uri("/create_topic")
+            .set_json(json!({"name": name, "overflow": overflow}))
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+    let publish = |topic: &str| {
+        test::TestRequest::post()
+            .uri("/publish")
+            .set_json(json!({"topic": topic, "key": null, "payload": "pp", "require_ack": false}))
+            .to_request()
+    };
+
+    assert!(test::call_service(&app, publish("reject"))
+        .await
+        .status()
+        .is_success());
+    assert!(test::call_service(&app, publish("drop"))
+        .await
+        .status()
+        .is_success());
+    // Память занята целиком: топик с reject отказывает, топик с drop_oldest
+    // освобождает место за счет своих старых сообщений
+    assert_eq!(
+        test::call_service(&app, publish("reject")).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert!(test::call_service(&app, publish("drop"))
+        .await
+        .status()
+        .is_success());
+
+    let req = test::TestRequest::get().uri("/admin/memory").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({"used": 4, "limit": 4}));
+}
+
+#[actix_web::test]
+async fn snapshot_is_written_only_into_snapshot_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut broker = Broker::new();
+    broker.set_snapshot_dir(Some(dir.path().to_path_buf()));
+    let broker = web::Data::new(Arc::new(Mutex::new(broker)));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+
+    let snapshot = |path: &str| {
+        test::TestRequest::post()
+            .uri("/admin/snapshot")
+            .set_json(json!({ "path": path }))
+            .to_request()
+    };
+    let other = tempfile::tempdir().unwrap();
+    let outside = other.path().join("outside.snapshot");
+    for path in [
+        outside.to_str().unwrap(),
+        "../outside.snapshot",
+        "a/../../b",
+        "",
+    ] {
+        assert_eq!(
+            test::call_service(&app, snapshot(path)).await.status(),
+            StatusCode::BAD_REQUEST,
+            "{}",
+            path
+        );
+    }
+    assert!(!outside.exists());
+
+    let body: Value = test::call_and_read_body_json(&app, snapshot("broker.snapshot")).await;
+    assert_eq!(body["topics"], 0);
+    assert!(dir.path().join("broker.snapshot").exists());
+}
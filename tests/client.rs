@@ -0,0 +1,389 @@
+use actix_web::{http::StatusCode, test, web, App};
+use futures::lock::Mutex;
+use mem_broker::{broker::Broker, client::init_routes};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+fn broker() -> web::Data<Arc<Mutex<Broker>>> {
+    web::Data::new(Arc::new(Mutex::new(Broker::new())))
+}
+
+#[actix_web::test]
+async fn publish_returns_offset() {
+    let app = test::init_service(App::new().app_data(broker()).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "orders", "retention": null, "compaction": false}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    for expected in 0..2 {
+        let req = test::TestRequest::post()
+            .uri("/publish")
+            .set_json(json!({"topic": "orders", "key": null, "payload": "p", "require_ack": false}))
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(body["offset"], expected);
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/message?topic=orders&offset=1")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["offset"], 1);
+    assert_eq!(body["payload"], "p");
+}
+
+// Читает тело потокового ответа, пока не придет count событий
+async fn read_events(body: actix_web::body::BoxBody, count: usize) -> String {
+    use actix_web::body::MessageBody;
+    let mut body = Box::pin(body);
+    let mut text = String::new();
+    while text.matches("\n\n").count() < count {
+        let chunk = std::future::poll_fn(|cx| body.as_mut().poll_next(cx));
+        let chunk = actix_web::rt::time::timeout(std::time::Duration::from_secs(1), chunk)
+            .await
+            .expect("событие не пришло")
+            .expect("поток закрыт")
+            .unwrap();
+        text.push_str(std::str::from_utf8(&chunk).unwrap());
+    }
+    text
+}
+
+#[actix_web::test]
+async fn subscribe_streams_sse_and_resumes_after_last_event_id() {
+    let app = test::init_service(App::new().app_data(broker()).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "orders"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    for payload in ["a", "b", "c"] {
+        let req = test::TestRequest::post()
+            .uri("/publish")
+            .set_json(
+                json!({"topic": "orders", "key": "k", "payload": payload, "require_ack": false}),
+            )
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/subscribe?topic=orders&from=earliest&client_id=reader")
+        .insert_header(("Last-Event-ID", "0"))
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(
+        resp.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+    assert_eq!(resp.headers().get("X-Client-Id").unwrap(), "reader");
+
+    let text = read_events(resp.into_body(), 3).await;
+    let events: Vec<&str> = text.split_terminator("\n\n").collect();
+    assert_eq!(events.len(), 3);
+    // Первым событием клиент узнает свой id
+    assert_eq!(
+        events[0],
+        r#"event: subscribed
+data: {"client_id":"reader","topic":"orders"}"#
+    );
+    for (event, (offset, payload)) in events[1..].iter().zip([(1, "b"), (2, "c")]) {
+        let lines: Vec<&str> = event.lines().collect();
+        assert_eq!(lines[0], format!("id: {}", offset));
+        assert_eq!(lines[1], "event: message");
+        let data: Value = serde_json::from_str(lines[2].strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(data["offset"], offset);
+        assert_eq!(data["key"], "k");
+        assert_eq!(data["payload"], payload);
+    }
+
+    // Id уже подключенного клиента занять нельзя
+    let req = test::TestRequest::get()
+        .uri("/subscribe?topic=orders")
+        .insert_header(("X-Client-Id", "reader"))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn dropped_sse_client_is_unsubscribed() {
+    let app = test::init_service(App::new().app_data(broker()).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "orders"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/subscribe?topic=orders&client_id=reader")
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    read_events(resp.into_body(), 1).await;
+
+    // Поток ответа удален: сессия останавливается, топик забывает подписчика
+    let mut subscribers = Value::Null;
+    for _ in 0..30 {
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(100)).await;
+        let req = test::TestRequest::get()
+            .uri("/admin/topics/orders")
+            .to_request();
+        let body: Value = test::call_and_read_body_json(&app, req).await;
+        subscribers = body["subscriber_count"].clone();
+        if subscribers == 0 {
+            break;
+        }
+    }
+    assert_eq!(subscribers, 0);
+
+    // Id освободился
+    let req = test::TestRequest::get()
+        .uri("/subscribe?topic=orders&client_id=reader")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+}
+
+#[actix_web::test]
+async fn deleted_topic_closes_sse_stream() {
+    use actix_web::body::MessageBody;
+    let app = test::init_service(App::new().app_data(broker()).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "orders"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let req = test::TestRequest::get()
+        .uri("/subscribe?topic=orders")
+        .to_request();
+    let mut body = Box::pin(test::call_service(&app, req).await.into_body());
+    let subscribed = std::future::poll_fn(|cx| body.as_mut().poll_next(cx)).await;
+    assert!(subscribed.is_some());
+
+    let req = test::TestRequest::delete()
+        .uri("/admin/topics/orders")
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    // Поток завершается, а не ждет heartbeat
+    let end = std::future::poll_fn(|cx| body.as_mut().poll_next(cx));
+    let end = actix_web::rt::time::timeout(std::time::Duration::from_secs(1), end)
+        .await
+        .expect("поток не закрылся");
+    assert!(end.is_none());
+}
+
+#[actix_web::test]
+async fn fetch_returns_batches_and_long_polls() {
+    let app = test::init_service(App::new().app_data(broker()).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "jobs"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    let publish = |payload: &str| {
+        test::TestRequest::post()
+            .uri("/publish")
+            .set_json(
+                json!({"topic": "jobs", "key": null, "payload": payload, "require_ack": false}),
+            )
+            .to_request()
+    };
+    for payload in ["a", "b", "c"] {
+        assert!(test::call_service(&app, publish(payload))
+            .await
+            .status()
+            .is_success());
+    }
+
+    let fetch = |query: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/fetch?topic=jobs&{}", query))
+            .to_request()
+    };
+    let body: Value = test::call_and_read_body_json(&app, fetch("group=batch&max=2")).await;
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert_eq!(body[0]["payload"], "a");
+    assert_eq!(body[1]["offset"], 1);
+    let body: Value = test::call_and_read_body_json(&app, fetch("group=batch")).await;
+    assert_eq!(body[0]["payload"], "c");
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    let body: Value = test::call_and_read_body_json(&app, fetch("offset=1&max=1")).await;
+    assert_eq!(body[0]["payload"], "b");
+
+    // Новых сообщений нет - пустой массив по истечении ожидания
+    let body: Value =
+        test::call_and_read_body_json(&app, fetch("group=batch&timeout_ms=100")).await;
+    assert_eq!(body, json!([]));
+
+    // Ожидающий запрос получает сообщение, как только оно опубликовано
+    let (body, _) = futures::join!(
+        test::call_and_read_body_json::<_, _, Value>(&app, fetch("group=batch&timeout_ms=5000")),
+        async {
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(100)).await;
+            test::call_service(&app, publish("d")).await
+        }
+    );
+    assert_eq!(body[0]["payload"], "d");
+
+    let resp = test::call_service(&app, fetch("max=10")).await;
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn topic_sessions_apply_slow_consumer_policy_per_topic() {
+    use futures::StreamExt;
+    use mem_broker::client::TopicSessions;
+    use mem_broker::message::Message;
+    use mem_broker::topic::{SlowConsumerPolicy, StartPosition, TopicConfig};
+
+    let mut broker = Broker::new();
+    let slow = TopicConfig {
+        subscriber_buffer: 2,
+        slow_consumer: SlowConsumerPolicy::DropOldest,
+        ..Default::default()
+    };
+    broker.create_topic("slow".into(), slow).unwrap();
+    broker
+        .create_topic("other".into(), TopicConfig::default())
+        .unwrap();
+    let broker = Mutex::new(broker);
+
+    // Одно соединение подписано на оба топика и пока ничего не читает
+    let (mut sessions, mut deliveries) = TopicSessions::new();
+    for topic in ["other", "slow"] {
+        sessions
+            .subscribe(
+                &broker,
+                topic,
+                "c1".into(),
+                StartPosition::Latest,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+    }
+    for topic in ["other", "slow"] {
+        for payload in ["1", "2", "3"] {
+            let message = Message::new(payload.into(), None, false);
+            let publish = broker.lock().await.publish_message(topic, message);
+            publish.await.unwrap();
+        }
+    }
+    actix_web::rt::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    // Сообщения другого топика не вытесняются и не вытесняют
+    let mut received: Vec<(String, String)> = Vec::new();
+    while let Ok(Some(delivery)) =
+        actix_web::rt::time::timeout(std::time::Duration::from_millis(300), deliveries.next()).await
+    {
+        received.push((delivery.topic, delivery.message.payload.unwrap()));
+    }
+    received.sort();
+    let expected: Vec<(String, String)> = [
+        ("other", "1"),
+        ("other", "2"),
+        ("other", "3"),
+        ("slow", "2"),
+        ("slow", "3"),
+    ]
+    .iter()
+    .map(|(topic, payload)| (topic.to_string(), payload.to_string()))
+    .collect();
+    assert_eq!(received, expected);
+}
+
+#[actix_web::test]
+async fn topic_sessions_reject_second_subscription_to_a_topic() {
+    use futures::StreamExt;
+    use mem_broker::client::TopicSessions;
+    use mem_broker::message::Message;
+    use mem_broker::topic::{StartPosition, TopicConfig};
+
+    let mut broker = Broker::new();
+    broker
+        .create_topic("orders".into(), TopicConfig::default())
+        .unwrap();
+    let broker = Mutex::new(broker);
+
+    let (mut sessions, mut deliveries) = TopicSessions::new();
+    for expected_ok in [true, false] {
+        let subscribed = sessions
+            .subscribe(
+                &broker,
+                "orders",
+                "c1".into(),
+                StartPosition::Latest,
+                None,
+                false,
+            )
+            .await;
+        assert_eq!(subscribed.is_ok(), expected_ok);
+    }
+
+    // Первая подписка продолжает получать сообщения
+    let message = Message::new("p".into(), None, false);
+    let publish = broker.lock().await.publish_message("orders", message);
+    publish.await.unwrap();
+    let delivery =
+        actix_web::rt::time::timeout(std::time::Duration::from_secs(1), deliveries.next())
+            .await
+            .unwrap()
+            .unwrap();
+    assert_eq!(delivery.message.payload.as_deref(), Some("p"));
+}
+
+#[actix_web::test]
+async fn topic_sessions_sync_with_broker_topics() {
+    use mem_broker::client::TopicSessions;
+    use mem_broker::topic::{StartPosition, TopicConfig};
+
+    let mut broker = Broker::new();
+    for topic in ["news.a", "news.b", "other"] {
+        broker
+            .create_topic(topic.into(), TopicConfig::default())
+            .unwrap();
+    }
+    let broker = Mutex::new(broker);
+    let wanted = |topic: &str| topic.starts_with("news.");
+
+    // Топик news.b пока занят другим соединением с тем же id
+    let (mut busy, _busy_deliveries) = TopicSessions::new();
+    busy.subscribe(
+        &broker,
+        "news.b",
+        "c1".into(),
+        StartPosition::Latest,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+
+    let (mut sessions, _deliveries) = TopicSessions::new();
+    let changes = sessions
+        .sync(&broker, "c1", wanted, StartPosition::Latest, false)
+        .await;
+    assert_eq!(changes.added, ["news.a"]);
+    assert!(changes.removed.is_empty());
+
+    // Неудавшаяся подписка повторяется при следующей сверке
+    busy.close_all().await;
+    broker.lock().await.delete_topic("news.a").await.unwrap();
+    let changes = sessions
+        .sync(&broker, "c1", wanted, StartPosition::Latest, false)
+        .await;
+    assert_eq!(changes.added, ["news.b"]);
+    assert_eq!(changes.removed, ["news.a"]);
+    let mut topics: Vec<&String> = sessions.topics().collect();
+    topics.sort();
+    assert_eq!(topics, ["news.b"]);
+}
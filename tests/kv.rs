@@ -0,0 +1,67 @@
+use actix_web::{http::StatusCode, test, web, App};
+use futures::lock::Mutex;
+use mem_broker::{broker::Broker, client::init_routes};
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+#[actix_web::test]
+async fn compacted_topic_is_readable_as_table() {
+    let broker = web::Data::new(Arc::new(Mutex::new(Broker::new())));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "config", "compaction": true}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+    for (key, payload) in [
+        ("app/a", Some("1")),
+        ("app/b", Some("2")),
+        ("app/c", Some("3")),
+        ("db/url", Some("old")),
+        ("db/url", Some("new")),
+        ("app/b", None),
+        ("app/d", None),
+    ] {
+        let req = test::TestRequest::post()
+            .uri("/publish")
+            .set_json(
+                json!({"topic": "config", "key": key, "payload": payload, "require_ack": false}),
+            )
+            .to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/kv/config/values/db/url")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body["payload"], "new");
+    // Удаленный tombstone ключ не найден
+    let req = test::TestRequest::get()
+        .uri("/kv/config/values/app/b")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/kv/config/keys?prefix=app/&limit=1")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(body, json!({"keys": ["app/a"], "next": "app/a"}));
+    let req = test::TestRequest::get()
+        .uri("/kv/config/keys?prefix=app/&limit=1&after=app/a")
+        .to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    // После app/c остался только удаленный ключ, курсора нет
+    assert_eq!(body, json!({"keys": ["app/c"], "next": null}));
+
+    let req = test::TestRequest::get().uri("/kv/config").to_request();
+    let body: Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(
+        body,
+        json!({"next_offset": 7, "entries": {"app/a": "1", "app/c": "3", "db/url": "new"}})
+    );
+}
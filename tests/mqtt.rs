@@ -0,0 +1,289 @@
+use actix_codec::Framed;
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::{sleep, timeout};
+use actix_web::web::Bytes;
+use futures::{lock::Mutex, SinkExt, StreamExt};
+use mem_broker::broker::Broker;
+use mem_broker::mqtt::{self, connack, Connect, MqttCodec, Packet, Publish, SUBSCRIBE_FAILURE};
+use mem_broker::topic::TopicConfig;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+type Client = Framed<TcpStream, MqttCodec>;
+
+async fn start() -> (Arc<Mutex<Broker>>, SocketAddr) {
+    let mut broker = Broker::new();
+    broker
+        .create_topic("alerts".into(), TopicConfig::default())
+        .unwrap();
+    let broker = Arc::new(Mutex::new(broker));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    actix_web::rt::spawn(mqtt::serve(listener, broker.clone()));
+    (broker, addr)
+}
+
+async fn next_packet(client: &mut Client) -> Packet {
+    timeout(Duration::from_secs(3), client.next())
+        .await
+        .expect("пакет не пришел")
+        .expect("соединение закрыто")
+        .unwrap()
+}
+
+async fn connect_with(addr: SocketAddr, connect: Connect) -> (Client, bool) {
+    let mut client = Framed::new(TcpStream::connect(addr).await.unwrap(), MqttCodec);
+    client.send(Packet::Connect(connect)).await.unwrap();
+    match next_packet(&mut client).await {
+        Packet::ConnAck {
+            session_present,
+            code: connack::ACCEPTED,
+        } => (client, session_present),
+        other => panic!("неожиданный ответ: {:?}", other),
+    }
+}
+
+async fn connect(addr: SocketAddr, client_id: &str, clean_session: bool) -> (Client, bool) {
+    connect_with(addr, Connect::new(client_id, clean_session)).await
+}
+
+fn publish(topic: &str, payload: &str, qos: u8, retain: bool, packet_id: Option<u16>) -> Packet {
+    Packet::Publish(Publish {
+        topic: topic.into(),
+        payload: Bytes::from(payload.to_string()),
+        qos,
+        retain,
+        dup: false,
+        packet_id,
+    })
+}
+
+async fn subscribe(client: &mut Client, filters: &[(&str, u8)]) -> Vec<u8> {
+    let filters = filters
+        .iter()
+        .map(|(f, qos)| (f.to_string(), *qos))
+        .collect();
+    client
+        .send(Packet::Subscribe {
+            packet_id: 1,
+            filters,
+        })
+        .await
+        .unwrap();
+    match next_packet(client).await {
+        Packet::SubAck {
+            packet_id: 1,
+            codes,
+        } => codes,
+        other => panic!("неожиданный ответ: {:?}", other),
+    }
+}
+
+async fn next_publish(client: &mut Client) -> Publish {
+    match next_packet(client).await {
+        Packet::Publish(publish) => publish,
+        other => panic!("неожиданный пакет: {:?}", other),
+    }
+}
+
+#[test]
+fn matches_topic_filters() {
+    assert!(mqtt::filter_matches("sensors/+/temp", "sensors/a/temp"));
+    assert!(!mqtt::filter_matches("sensors/+/temp", "sensors/a/b/temp"));
+    assert!(mqtt::filter_matches("sensors/#", "sensors"));
+    assert!(mqtt::filter_matches("sensors/#", "sensors/a/b"));
+    assert!(!mqtt::filter_matches("#", "$SYS/uptime"));
+    assert!(mqtt::valid_filter("+/x/#"));
+    assert!(!mqtt::valid_filter("a/#/b"));
+    assert!(!mqtt::valid_filter("a/b+"));
+}
+
+#[actix_web::test]
+async fn rejects_unsupported_protocol_level() {
+    let (_, addr) = start().await;
+    let mut client = Framed::new(TcpStream::connect(addr).await.unwrap(), MqttCodec);
+    let mut request = Connect::new("device-1", true);
+    request.protocol_level = 3;
+    client.send(Packet::Connect(request)).await.unwrap();
+    assert_eq!(
+        next_packet(&mut client).await,
+        Packet::ConnAck {
+            session_present: false,
+            code: connack::UNACCEPTABLE_PROTOCOL
+        }
+    );
+    assert!(client.next().await.is_none());
+}
+
+#[actix_web::test]
+async fn delivers_qos1_through_wildcards_and_acks() {
+    let (broker, addr) = start().await;
+    let (mut subscriber, _) = connect(addr, "display", true).await;
+    let codes = subscribe(
+        &mut subscriber,
+        &[("sensors/+/temp", 2), ("sensors/#/x", 0)],
+    )
+    .await;
+    assert_eq!(codes, vec![1, SUBSCRIBE_FAILURE]);
+
+    // Топик создается публикацией, подписчик подключается к нему сам
+    let (mut publisher, _) = connect(addr, "sensor", true).await;
+    publisher
+        .send(publish("sensors/a/temp", "21.5", 1, false, Some(7)))
+        .await
+        .unwrap();
+    assert_eq!(next_packet(&mut publisher).await, Packet::PubAck(7));
+    publisher
+        .send(publish("sensors/a/humidity", "40", 0, false, None))
+        .await
+        .unwrap();
+
+    let delivered = next_publish(&mut subscriber).await;
+    assert_eq!(delivered.topic, "sensors/a/temp");
+    assert_eq!(delivered.payload, Bytes::from("21.5"));
+    assert_eq!(delivered.qos, 1);
+    subscriber
+        .send(Packet::PubAck(delivered.packet_id.unwrap()))
+        .await
+        .unwrap();
+
+    // Подписчик уже подключен к топику, сообщение ждет его PUBACK
+    publisher
+        .send(publish("sensors/a/temp", "22.0", 1, false, Some(8)))
+        .await
+        .unwrap();
+    assert_eq!(next_packet(&mut publisher).await, Packet::PubAck(8));
+    let delivered = next_publish(&mut subscriber).await;
+    assert_eq!(delivered.payload, Bytes::from("22.0"));
+    let packet_id = delivered.packet_id.unwrap();
+
+    let topic = "sensors/a/temp";
+    let pending = broker.lock().await.describe_topic(topic).await.unwrap();
+    assert_eq!(pending.pending_acks, 1);
+    subscriber.send(Packet::PubAck(packet_id)).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let acked = broker.lock().await.describe_topic(topic).await.unwrap();
+    assert_eq!(acked.pending_acks, 0);
+
+    // Сообщения топика, не подходящего под фильтр, не доставляются
+    subscriber.send(Packet::PingReq).await.unwrap();
+    assert_eq!(next_packet(&mut subscriber).await, Packet::PingResp);
+}
+
+#[actix_web::test]
+async fn publishes_will_as_retained_message() {
+    let (_, addr) = start().await;
+    let mut request = Connect::new("lamp", true);
+    request.will = Some(Publish {
+        topic: "status/lamp".into(),
+        payload: Bytes::from("offline"),
+        qos: 0,
+        retain: true,
+        dup: false,
+        packet_id: None,
+    });
+    let (lamp, _) = connect_with(addr, request).await;
+    // Соединение обрывается без DISCONNECT
+    drop(lamp);
+    sleep(Duration::from_millis(200)).await;
+
+    let (mut watcher, _) = connect(addr, "panel", true).await;
+    assert_eq!(subscribe(&mut watcher, &[("status/+", 1)]).await, vec![1]);
+    let retained = next_publish(&mut watcher).await;
+    assert_eq!(retained.topic, "status/lamp");
+    assert_eq!(retained.payload, Bytes::from("offline"));
+    assert!(retained.retain);
+}
+
+#[actix_web::test]
+async fn persistent_session_receives_missed_messages() {
+    let (_, addr) = start().await;
+    let (mut device, session_present) = connect(addr, "device-1", false).await;
+    assert!(!session_present);
+    assert_eq!(subscribe(&mut device, &[("alerts", 1)]).await, vec![1]);
+    device.send(Packet::Disconnect).await.unwrap();
+    assert!(device.next().await.is_none());
+
+    let (mut publisher, _) = connect(addr, "server", true).await;
+    publisher
+        .send(publish("alerts", "fire", 1, false, Some(1)))
+        .await
+        .unwrap();
+    assert_eq!(next_packet(&mut publisher).await, Packet::PubAck(1));
+
+    // Пропущенное сообщение приходит после переподключения
+    let (mut device, session_present) = connect(addr, "device-1", false).await;
+    assert!(session_present);
+    let missed = next_publish(&mut device).await;
+    assert_eq!(missed.payload, Bytes::from("fire"));
+    device
+        .send(Packet::PubAck(missed.packet_id.unwrap()))
+        .await
+        .unwrap();
+    device.send(Packet::Disconnect).await.unwrap();
+    assert!(device.next().await.is_none());
+
+    // Чистая сессия удаляет сохраненную
+    let (_device, session_present) = connect(addr, "device-1", true).await;
+    assert!(!session_present);
+}
+
+#[actix_web::test]
+async fn empty_retained_message_clears_retained() {
+    let (_, addr) = start().await;
+    let (mut lamp, _) = connect(addr, "lamp", true).await;
+    lamp.send(publish("status/lamp", "on", 1, true, Some(1)))
+        .await
+        .unwrap();
+    assert_eq!(next_packet(&mut lamp).await, Packet::PubAck(1));
+
+    let (mut panel, _) = connect(addr, "panel", true).await;
+    assert_eq!(subscribe(&mut panel, &[("status/+", 1)]).await, vec![1]);
+    assert_eq!(next_publish(&mut panel).await.payload, Bytes::from("on"));
+
+    // Пустое сохраненное сообщение доставляется подписчикам, соединение остается
+    lamp.send(publish("status/lamp", "", 1, true, Some(2)))
+        .await
+        .unwrap();
+    assert_eq!(next_packet(&mut lamp).await, Packet::PubAck(2));
+    let cleared = next_publish(&mut panel).await;
+    assert_eq!(cleared.topic, "status/lamp");
+    assert!(cleared.payload.is_empty());
+    lamp.send(Packet::PingReq).await.unwrap();
+    assert_eq!(next_packet(&mut lamp).await, Packet::PingResp);
+
+    // Новый подписчик сохраненного сообщения не получает
+    let (mut watcher, _) = connect(addr, "watcher", true).await;
+    assert_eq!(subscribe(&mut watcher, &[("status/+", 1)]).await, vec![1]);
+    watcher.send(Packet::PingReq).await.unwrap();
+    assert_eq!(next_packet(&mut watcher).await, Packet::PingResp);
+}
+
+#[actix_web::test]
+async fn rejected_qos1_publish_is_not_acknowledged() {
+    let (_, addr) = start().await;
+    let (mut client, _) = connect(addr, "sensor", true).await;
+    let invalid = |qos: u8, packet_id: Option<u16>| {
+        Packet::Publish(Publish {
+            topic: "alerts".into(),
+            payload: Bytes::from_static(&[0xFF, 0xFE]),
+            qos,
+            retain: false,
+            dup: false,
+            packet_id,
+        })
+    };
+
+    // Отказ брокера для QoS 0 только логируется
+    client.send(invalid(0, None)).await.unwrap();
+    client.send(Packet::PingReq).await.unwrap();
+    assert_eq!(next_packet(&mut client).await, Packet::PingResp);
+
+    // Для QoS 1 PUBACK не отправляется, соединение закрывается
+    client.send(invalid(1, Some(7))).await.unwrap();
+    let closed = timeout(Duration::from_secs(3), client.next())
+        .await
+        .expect("соединение не закрыто");
+    assert!(!matches!(closed, Some(Ok(_))));
+}
@@ -0,0 +1,203 @@
+use actix_codec::{Decoder, Framed};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::timeout;
+use actix_web::web::BytesMut;
+use futures::{lock::Mutex, SinkExt, StreamExt};
+use mem_broker::broker::Broker;
+use mem_broker::resp::{self, glob_matches, RespCodec, RespOptions, Value};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+type Client = Framed<TcpStream, RespCodec>;
+
+async fn start(options: RespOptions) -> (Arc<Mutex<Broker>>, SocketAddr) {
+    let broker = Arc::new(Mutex::new(Broker::new()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    actix_web::rt::spawn(resp::serve(listener, broker.clone(), options));
+    (broker, addr)
+}
+
+async fn connect(addr: SocketAddr) -> Client {
+    Framed::new(
+        TcpStream::connect(addr).await.unwrap(),
+        RespCodec::default(),
+    )
+}
+
+async fn next_value(client: &mut Client) -> Value {
+    timeout(Duration::from_secs(3), client.next())
+        .await
+        .expect("ответ не пришел")
+        .expect("соединение закрыто")
+        .unwrap()
+}
+
+async fn command(client: &mut Client, args: &[&str]) -> Value {
+    client.send(Value::command(args)).await.unwrap();
+    next_value(client).await
+}
+
+fn array(items: &[&str]) -> Value {
+    Value::Array(
+        items
+            .iter()
+            .map(|item| Value::bulk(item.to_string()))
+            .collect(),
+    )
+}
+
+fn confirm(kind: &str, name: &str, count: i64) -> Value {
+    Value::Array(vec![
+        Value::bulk(kind.to_string()),
+        Value::bulk(name.to_string()),
+        Value::Integer(count),
+    ])
+}
+
+#[test]
+fn matches_glob_patterns() {
+    assert!(glob_matches(b"news.*", b"news.sport"));
+    assert!(glob_matches(b"h?llo", b"hello"));
+    assert!(glob_matches(b"h[ae]llo", b"hallo"));
+    assert!(!glob_matches(b"h[^e]llo", b"hello"));
+    assert!(glob_matches(b"h[a-c]llo", b"hbllo"));
+    assert!(glob_matches(b"a\\*", b"a*"));
+    assert!(!glob_matches(b"a\\*", b"ab"));
+    assert!(glob_matches(b"*.[a-z]*.?", b"news.sport.x.y"));
+    assert!(glob_matches(b"**", b""));
+    assert!(!glob_matches(b"*x", b"abc"));
+    assert!(!glob_matches(b"a[bc", b"ab"));
+
+    // Много * подряд с неудачным концом не перебирают все разбиения строки
+    let pattern = "a*".repeat(30) + "b";
+    let text = "a".repeat(5000);
+    let started = std::time::Instant::now();
+    assert!(!glob_matches(pattern.as_bytes(), text.as_bytes()));
+    assert!(started.elapsed() < std::time::Duration::from_secs(1));
+}
+
+#[test]
+fn decodes_commands_in_parts_and_rejects_nested_arrays() {
+    let mut codec = RespCodec::default();
+    let mut buf = BytesMut::from(&b"*2\r\n$4\r\nPING\r\n$2"[..]);
+    assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    // Разобранный элемент уже удален из буфера
+    assert_eq!(&buf[..], b"$2");
+    buf.extend_from_slice(b"\r\nhi\r\n");
+    assert_eq!(
+        codec.decode(&mut buf).unwrap(),
+        Some(Value::command(&["PING", "hi"]))
+    );
+    assert!(buf.is_empty());
+
+    let mut buf = BytesMut::from(&b"*1\r\n*1\r\n*1\r\n"[..]);
+    assert!(RespCodec::default().decode(&mut buf).is_err());
+    let mut buf = BytesMut::from(&b"*99999999\r\n"[..]);
+    assert!(RespCodec::default().decode(&mut buf).is_err());
+}
+
+#[actix_web::test]
+async fn answers_ping_and_quit() {
+    let (_, addr) = start(RespOptions::default()).await;
+    let mut client = connect(addr).await;
+    assert_eq!(
+        command(&mut client, &["PING"]).await,
+        Value::Simple("PONG".into())
+    );
+    assert_eq!(
+        command(&mut client, &["ping", "hi"]).await,
+        Value::bulk("hi")
+    );
+    assert!(matches!(
+        command(&mut client, &["GET", "key"]).await,
+        Value::Error(_)
+    ));
+
+    // Inline-команда, как из telnet
+    client.io_mut().try_write(b"PING\r\n").unwrap();
+    assert_eq!(next_value(&mut client).await, Value::Simple("PONG".into()));
+
+    assert_eq!(
+        command(&mut client, &["QUIT"]).await,
+        Value::Simple("OK".into())
+    );
+    assert!(client.next().await.is_none());
+}
+
+#[actix_web::test]
+async fn publishes_to_channels_and_patterns() {
+    let (_, addr) = start(RespOptions::default()).await;
+    let mut subscriber = connect(addr).await;
+    let mut publisher = connect(addr).await;
+
+    assert_eq!(
+        command(&mut subscriber, &["SUBSCRIBE", "news"]).await,
+        confirm("subscribe", "news", 1)
+    );
+    assert_eq!(
+        command(&mut subscriber, &["PSUBSCRIBE", "ne*"]).await,
+        confirm("psubscribe", "ne*", 2)
+    );
+    assert_eq!(
+        command(&mut publisher, &["PUBLISH", "news", "hello"]).await,
+        Value::Integer(1)
+    );
+    assert_eq!(
+        next_value(&mut subscriber).await,
+        array(&["message", "news", "hello"])
+    );
+    assert_eq!(
+        next_value(&mut subscriber).await,
+        array(&["pmessage", "ne*", "news", "hello"])
+    );
+
+    // В режиме подписки доступны только команды подписки
+    assert!(matches!(
+        command(&mut subscriber, &["PUBLISH", "news", "x"]).await,
+        Value::Error(_)
+    ));
+    assert_eq!(
+        command(&mut subscriber, &["PING"]).await,
+        array(&["pong", ""])
+    );
+
+    // Канал, созданный после подписки на шаблон, тоже доставляется
+    assert_eq!(
+        command(&mut publisher, &["PUBLISH", "nest", "eggs"]).await,
+        Value::Integer(0)
+    );
+    assert_eq!(
+        next_value(&mut subscriber).await,
+        array(&["pmessage", "ne*", "nest", "eggs"])
+    );
+
+    assert_eq!(
+        command(&mut subscriber, &["UNSUBSCRIBE"]).await,
+        confirm("unsubscribe", "news", 1)
+    );
+    assert_eq!(
+        command(&mut subscriber, &["PUNSUBSCRIBE", "ne*"]).await,
+        confirm("punsubscribe", "ne*", 0)
+    );
+    assert_eq!(
+        command(&mut subscriber, &["PING"]).await,
+        Value::Simple("PONG".into())
+    );
+}
+
+#[actix_web::test]
+async fn does_not_create_topics_when_disabled() {
+    let (broker, addr) = start(RespOptions { auto_create: false }).await;
+    let mut client = connect(addr).await;
+    assert_eq!(
+        command(&mut client, &["PUBLISH", "missing", "x"]).await,
+        Value::Integer(0)
+    );
+    assert_eq!(
+        command(&mut client, &["SUBSCRIBE", "missing"]).await,
+        confirm("subscribe", "missing", 1)
+    );
+    assert!(!broker.lock().await.has_topic("missing"));
+}
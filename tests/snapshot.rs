@@ -0,0 +1,118 @@
+use mem_broker::broker::Broker;
+use mem_broker::message::Message;
+use mem_broker::snapshot::BrokerSnapshot;
+use mem_broker::topic::{Fetch, FetchFrom, NackAction, TopicConfig};
+use std::time::Duration;
+
+#[actix_web::test]
+async fn broker_is_restored_from_snapshot_file() {
+    let mut source = Broker::new();
+    source
+        .create_topic(
+            "jobs".into(),
+            TopicConfig {
+                dead_letter_topic: Some("jobs_dlq".into()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    source
+        .create_topic(
+            "table".into(),
+            TopicConfig {
+                compaction: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    for payload in ["a", "b", "c"] {
+        source
+            .publish_message("jobs", Message::new(payload.into(), None, false))
+            .await
+            .unwrap();
+    }
+    for payload in ["v1", "v2"] {
+        source
+            .publish_message(
+                "table",
+                Message::new(payload.into(), Some("k".into()), false),
+            )
+            .await
+            .unwrap();
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("broker.snapshot");
+    let snapshot = source.snapshot().await.unwrap();
+    assert_eq!(snapshot.topics.len(), 3);
+    snapshot.save(&path).unwrap();
+
+    let mut restored = Broker::new();
+    restored
+        .restore_snapshot(BrokerSnapshot::load(&path).unwrap())
+        .await
+        .unwrap();
+
+    let message = restored.get_message("jobs", 2).await.unwrap().unwrap();
+    assert_eq!(message.payload.as_deref(), Some("c"));
+    let offset = restored
+        .publish_message("jobs", Message::new("d".into(), None, false))
+        .await
+        .unwrap();
+    assert_eq!(offset, 3);
+
+    // В компактном топике восстановлено только последнее значение ключа
+    assert!(restored.get_message("table", 0).await.unwrap().is_none());
+    let latest = restored.get_message("table", 1).await.unwrap().unwrap();
+    assert_eq!(latest.payload.as_deref(), Some("v2"));
+    assert!(restored.get_message("jobs_dlq", 0).await.is_ok());
+
+    // Повторное восстановление в тот же брокер запрещено
+    let again = restored
+        .restore_snapshot(BrokerSnapshot::load(&path).unwrap())
+        .await;
+    assert!(again.is_err());
+}
+
+#[actix_web::test]
+async fn pending_fetch_state_survives_snapshot() {
+    let mut source = Broker::new();
+    source
+        .create_topic("jobs".into(), TopicConfig::default())
+        .unwrap();
+    let message = Message::new("job".into(), None, true);
+    let id = message.id.clone();
+    source.publish_message("jobs", message).await.unwrap();
+    let fetch = Fetch {
+        client_id: "worker".into(),
+        from: FetchFrom::Group("workers".into()),
+        max: 10,
+        wait: Duration::ZERO,
+    };
+    let fetched = source.fetch("jobs", fetch).await.unwrap();
+    assert_eq!(fetched.len(), 1);
+    source
+        .nack(
+            "jobs",
+            "worker".into(),
+            id,
+            NackAction::Delay { delay_ms: 60_000 },
+            Some("timeout upstream".into()),
+        )
+        .unwrap();
+
+    // Группа fetch и причина отказа сохраняются в снимке и восстанавливаются из него
+    for broker in [None, Some(Broker::new())] {
+        let snapshot = source.snapshot().await.unwrap();
+        let snapshot = match broker {
+            None => snapshot,
+            Some(mut restored) => {
+                restored.restore_snapshot(snapshot).await.unwrap();
+                restored.snapshot().await.unwrap()
+            }
+        };
+        let pending = &snapshot.topics[0].pending_acks[0];
+        assert_eq!(pending.fetch_group.as_deref(), Some("workers"));
+        assert_eq!(pending.last_failure.as_deref(), Some("timeout upstream"));
+    }
+}
@@ -0,0 +1,181 @@
+use actix_codec::{Decoder, Encoder, Framed};
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::rt::time::{sleep, timeout};
+use actix_web::web::BytesMut;
+use futures::{lock::Mutex, SinkExt, StreamExt};
+use mem_broker::broker::Broker;
+use mem_broker::stomp::{self, Frame, StompCodec};
+use mem_broker::topic::TopicConfig;
+use std::sync::Arc;
+use std::time::Duration;
+
+type Client = Framed<TcpStream, StompCodec>;
+
+async fn start() -> (Arc<Mutex<Broker>>, Client) {
+    let mut broker = Broker::new();
+    broker
+        .create_topic("orders".into(), TopicConfig::default())
+        .unwrap();
+    let broker = Arc::new(Mutex::new(broker));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    actix_web::rt::spawn(stomp::serve(listener, broker.clone()));
+    let client = Framed::new(TcpStream::connect(addr).await.unwrap(), StompCodec);
+    (broker, client)
+}
+
+async fn next_frame(client: &mut Client) -> Frame {
+    timeout(Duration::from_secs(3), client.next())
+        .await
+        .expect("кадр не пришел")
+        .expect("соединение закрыто")
+        .unwrap()
+}
+
+async fn connect(client: &mut Client) {
+    let connect = Frame::new("CONNECT")
+        .header("accept-version", "1.1,1.2")
+        .header("host", "localhost");
+    client.send(connect).await.unwrap();
+    let connected = next_frame(client).await;
+    assert_eq!(connected.command, "CONNECTED");
+    assert_eq!(connected.get("version"), Some("1.2"));
+}
+
+// Кадр с receipt и ожидание RECEIPT
+async fn request(client: &mut Client, frame: Frame, receipt: &str) {
+    client.send(frame.header("receipt", receipt)).await.unwrap();
+    let reply = next_frame(client).await;
+    assert_eq!(reply.command, "RECEIPT", "{:?}", reply);
+    assert_eq!(reply.get("receipt-id"), Some(receipt));
+}
+
+async fn subscribe(client: &mut Client, id: &str, ack: &str) {
+    let frame = Frame::new("SUBSCRIBE")
+        .header("id", id)
+        .header("destination", "orders")
+        .header("ack", ack);
+    request(client, frame, &format!("sub-{}", id)).await;
+}
+
+async fn send(client: &mut Client, body: &str) {
+    let frame = Frame::new("SEND")
+        .header("destination", "orders")
+        .header("require-ack", "true")
+        .body(body.to_string());
+    // Без receipt: подписчик на том же соединении может получить MESSAGE раньше RECEIPT
+    client.send(frame).await.unwrap();
+}
+
+async fn pending_acks(broker: &Arc<Mutex<Broker>>) -> usize {
+    sleep(Duration::from_millis(100)).await;
+    let broker = broker.lock().await;
+    broker.describe_topic("orders").await.unwrap().pending_acks
+}
+
+#[test]
+fn encodes_and_decodes_frames() {
+    let frame = Frame::new("SEND")
+        .header("destination", "a:b\nc")
+        .body("with\0nul");
+    let mut buf = BytesMut::new();
+    StompCodec.encode(frame.clone(), &mut buf).unwrap();
+    let decoded = StompCodec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(decoded.get("destination"), Some("a:b\nc"));
+    assert_eq!(decoded.body, frame.body);
+    assert!(buf.is_empty());
+
+    // Кадр без content-length, строки с CRLF и heartbeat перед ним
+    let mut buf = BytesMut::from(&b"\r\nACK\r\nid:1\r\n\r\n\0"[..]);
+    assert_eq!(StompCodec.decode(&mut buf).unwrap(), Some(Frame::default()));
+    let ack = StompCodec.decode(&mut buf).unwrap().unwrap();
+    assert_eq!(ack.command, "ACK");
+    assert_eq!(ack.get("id"), Some("1"));
+}
+
+#[actix_web::test]
+async fn rejects_unsupported_version() {
+    let (_, mut client) = start().await;
+    let connect = Frame::new("CONNECT").header("accept-version", "1.0,1.1");
+    client.send(connect).await.unwrap();
+    assert_eq!(next_frame(&mut client).await.command, "ERROR");
+    assert!(client.next().await.is_none());
+}
+
+#[actix_web::test]
+async fn acknowledges_in_client_individual_mode() {
+    let (broker, mut client) = start().await;
+    connect(&mut client).await;
+    subscribe(&mut client, "0", "client-individual").await;
+    send(&mut client, "a").await;
+    send(&mut client, "b").await;
+
+    let first = next_frame(&mut client).await;
+    assert_eq!(first.command, "MESSAGE");
+    assert_eq!(first.get("subscription"), Some("0"));
+    assert_eq!(first.get("destination"), Some("orders"));
+    assert_eq!(&first.body[..], b"a");
+    let second = next_frame(&mut client).await;
+    assert_eq!(&second.body[..], b"b");
+    assert_eq!(pending_acks(&broker).await, 2);
+
+    // Подтверждается только указанное сообщение
+    let ack = Frame::new("ACK").header("id", second.get("ack").unwrap());
+    client.send(ack).await.unwrap();
+    assert_eq!(pending_acks(&broker).await, 1);
+
+    // NACK возвращает сообщение на повторную доставку
+    let nack = Frame::new("NACK").header("id", first.get("ack").unwrap());
+    client.send(nack).await.unwrap();
+    let redelivered = next_frame(&mut client).await;
+    assert_eq!(redelivered.get("message-id"), first.get("message-id"));
+    assert_ne!(redelivered.get("ack"), first.get("ack"));
+}
+
+#[actix_web::test]
+async fn acknowledges_cumulatively_in_client_mode() {
+    let (broker, mut client) = start().await;
+    connect(&mut client).await;
+    subscribe(&mut client, "0", "client").await;
+    // Подписка в режиме auto подтверждает сама
+    subscribe(&mut client, "1", "auto").await;
+    send(&mut client, "a").await;
+    send(&mut client, "b").await;
+
+    let mut last_ack = None;
+    for _ in 0..4 {
+        let message = next_frame(&mut client).await;
+        match message.get("subscription") {
+            Some("0") => last_ack = message.get("ack").map(str::to_string),
+            _ => assert_eq!(message.get("ack"), None),
+        }
+    }
+    assert_eq!(pending_acks(&broker).await, 2);
+    let ack = Frame::new("ACK").header("id", last_ack.unwrap());
+    client.send(ack).await.unwrap();
+    assert_eq!(pending_acks(&broker).await, 0);
+
+    request(
+        &mut client,
+        Frame::new("UNSUBSCRIBE").header("id", "0"),
+        "unsub",
+    )
+    .await;
+    request(&mut client, Frame::new("DISCONNECT"), "bye").await;
+    assert!(client.next().await.is_none());
+}
+
+#[actix_web::test]
+async fn reports_errors_with_receipt() {
+    let (_, mut client) = start().await;
+    connect(&mut client).await;
+    let frame = Frame::new("SEND")
+        .header("destination", "missing")
+        .header("receipt", "r1")
+        .body("x");
+    client.send(frame).await.unwrap();
+    let error = next_frame(&mut client).await;
+    assert_eq!(error.command, "ERROR");
+    assert_eq!(error.get("receipt-id"), Some("r1"));
+    assert!(client.next().await.is_none());
+}
@@ -0,0 +1,136 @@
+use mem_broker::message::Message;
+use mem_broker::storage::{CompactOptions, FileStorage, MemoryBudget, MemoryStorage, TopicStorage};
+use mem_broker::wal::{TopicLog, WalOptions};
+
+fn message(offset: u64, key: &str, payload: &str, published_at: u64) -> Message {
+    let mut message = Message::new(payload.into(), Some(key.into()), false);
+    message.offset = offset;
+    message.published_at = published_at;
+    message
+}
+
+fn payloads(messages: Vec<Message>) -> Vec<String> {
+    messages.into_iter().filter_map(|m| m.payload).collect()
+}
+
+// Одинаковые проверки для любого хранилища
+fn check_storage(storage: &mut dyn TopicStorage) {
+    storage.append(message(0, "a", "a1", 100)).unwrap();
+    storage.append(message(1, "b", "b1", 200)).unwrap();
+    storage.append(message(2, "a", "a2", 300)).unwrap();
+    storage.append(message(3, "c", "c1", 400)).unwrap();
+    assert_eq!(storage.next_offset(), 4);
+    assert_eq!(storage.len(), 4);
+    assert_eq!(storage.size_bytes(), 4 * 3);
+
+    assert_eq!(payloads(storage.read_range(1, 2).unwrap()), ["b1", "a2"]);
+    assert_eq!(
+        storage.read_key("a").unwrap().unwrap().payload.unwrap(),
+        "a2"
+    );
+
+    storage.compact(&CompactOptions::default()).unwrap();
+    assert_eq!(
+        payloads(storage.read_range(0, usize::MAX).unwrap()),
+        ["b1", "a2", "c1"]
+    );
+
+    storage.truncate_before_time(250).unwrap();
+    assert_eq!(
+        payloads(storage.read_range(0, usize::MAX).unwrap()),
+        ["a2", "c1"]
+    );
+    assert!(storage.read_key("b").unwrap().is_none());
+
+    storage.truncate_to_len(1).unwrap();
+    assert_eq!(payloads(storage.read_range(0, usize::MAX).unwrap()), ["c1"]);
+    storage.truncate_to_size(2).unwrap();
+    assert!(storage.is_empty());
+    assert_eq!(storage.next_offset(), 4);
+}
+
+// Tombstone удаляет ключ после своего срока, сообщения без ключа сохраняются по желанию
+fn check_tombstones(storage: &mut dyn TopicStorage) {
+    storage.append(message(0, "a", "a1", 100)).unwrap();
+    let mut tombstone = Message::with_payload(None, Some("a".into()), false);
+    tombstone.offset = 1;
+    tombstone.published_at = 200;
+    storage.append(tombstone).unwrap();
+    let mut keyless = Message::new("x".into(), None, false);
+    keyless.offset = 2;
+    storage.append(keyless).unwrap();
+
+    let mut options = CompactOptions {
+        keep_keyless: true,
+        tombstones_before: 150,
+    };
+    storage.compact(&options).unwrap();
+    assert!(storage.read_key("a").unwrap().unwrap().is_tombstone());
+    assert_eq!(storage.len(), 2);
+
+    options.tombstones_before = 250;
+    storage.compact(&options).unwrap();
+    assert!(storage.read_key("a").unwrap().is_none());
+    assert_eq!(payloads(storage.read_range(0, usize::MAX).unwrap()), ["x"]);
+
+    options.keep_keyless = false;
+    storage.compact(&options).unwrap();
+    assert!(storage.is_empty());
+}
+
+#[test]
+fn memory_storage() {
+    check_storage(&mut MemoryStorage::new());
+    check_tombstones(&mut MemoryStorage::new());
+}
+
+#[test]
+fn file_storage() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = WalOptions::new(dir.path());
+    let (log, messages) = TopicLog::open(options.topic_dir("t"), &options).unwrap();
+    check_storage(&mut FileStorage::open(log, messages));
+    let (log, messages) = TopicLog::open(options.topic_dir("tombstones"), &options).unwrap();
+    check_tombstones(&mut FileStorage::open(log, messages));
+}
+
+#[test]
+fn file_storage_compaction_survives_reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut options = WalOptions::new(dir.path());
+    // Каждое сообщение в своем сегменте, чтобы компакция могла их переписать
+    options.segment_bytes = 1;
+
+    {
+        let (log, messages) = TopicLog::open(options.topic_dir("t"), &options).unwrap();
+        let mut storage = FileStorage::open(log, messages);
+        for (offset, payload) in ["v1", "v2", "v3"].into_iter().enumerate() {
+            storage
+                .append(message(offset as u64, "k", payload, 0))
+                .unwrap();
+        }
+        storage.compact(&CompactOptions::default()).unwrap();
+    }
+
+    let (log, messages) = TopicLog::open(options.topic_dir("t"), &options).unwrap();
+    assert_eq!(payloads(messages.clone()), ["v3"]);
+    let storage = FileStorage::open(log, messages);
+    assert_eq!(
+        storage.read_key("k").unwrap().unwrap().payload.unwrap(),
+        "v3"
+    );
+    assert_eq!(storage.next_offset(), 3);
+}
+
+#[test]
+fn memory_budget_tracks_usage() {
+    let budget = MemoryBudget::new(Some(10));
+    budget.update(0, 6);
+    assert!(budget.fits(4));
+    assert!(!budget.fits(5));
+    budget.update(6, 2);
+    assert_eq!(budget.used(), 2);
+    budget.set_limit(None);
+    assert!(budget.fits(u64::MAX - 2));
+    assert_eq!(budget.limit(), None);
+}
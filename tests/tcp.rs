@@ -0,0 +1,138 @@
+use actix_codec::Framed;
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::web::Bytes;
+use futures::{lock::Mutex, SinkExt, StreamExt};
+use mem_broker::broker::Broker;
+use mem_broker::tcp::{self, opcode, BodyReader, BodyWriter, Frame, FrameCodec, PROTOCOL_VERSION};
+use mem_broker::topic::TopicConfig;
+use std::sync::Arc;
+use std::time::Duration;
+
+type Client = Framed<TcpStream, FrameCodec>;
+
+async fn start() -> Client {
+    let mut broker = Broker::new();
+    broker
+        .create_topic("orders".into(), TopicConfig::default())
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    actix_web::rt::spawn(tcp::serve(listener, Arc::new(Mutex::new(broker))));
+    Framed::new(TcpStream::connect(addr).await.unwrap(), FrameCodec)
+}
+
+async fn request(client: &mut Client, opcode: u8, request_id: u32, body: BodyWriter) {
+    client
+        .send(Frame::new(opcode, request_id, body.finish()))
+        .await
+        .unwrap();
+}
+
+async fn next_frame(client: &mut Client) -> Frame {
+    actix_web::rt::time::timeout(Duration::from_secs(1), client.next())
+        .await
+        .expect("кадр не пришел")
+        .expect("соединение закрыто")
+        .unwrap()
+}
+
+fn hello(version: u16) -> BodyWriter {
+    BodyWriter::default()
+        .u16(version)
+        .opt_string(Some("service-1"))
+}
+
+#[actix_web::test]
+async fn rejects_unsupported_version() {
+    let mut client = start().await;
+    request(&mut client, opcode::HELLO, 1, hello(PROTOCOL_VERSION + 1)).await;
+    let reply = next_frame(&mut client).await;
+    assert_eq!(reply.opcode, opcode::ERROR);
+    assert!(client.next().await.is_none());
+}
+
+#[actix_web::test]
+async fn publishes_subscribes_fetches_and_acks() {
+    let mut client = start().await;
+    request(&mut client, opcode::HELLO, 1, hello(PROTOCOL_VERSION)).await;
+    let reply = next_frame(&mut client).await;
+    assert_eq!(reply.opcode, opcode::HELLO_OK);
+    let mut body = BodyReader::new(reply.body);
+    assert_eq!(body.u16().unwrap(), PROTOCOL_VERSION);
+    assert_eq!(body.string().unwrap(), "service-1");
+
+    let subscribe = BodyWriter::default()
+        .string("orders")
+        .opt_string(None)
+        .opt_string(None)
+        .u8(0);
+    request(&mut client, opcode::SUBSCRIBE, 2, subscribe).await;
+    assert_eq!(
+        next_frame(&mut client).await,
+        Frame::new(opcode::OK, 2, Bytes::new())
+    );
+
+    let batch = BodyWriter::default()
+        .string("orders")
+        .u32(2)
+        .opt_string(Some("k"))
+        .opt_string(Some("a"))
+        .u8(1)
+        .opt_string(None)
+        .opt_string(Some("b"))
+        .u8(0);
+    request(&mut client, opcode::PUBLISH_BATCH, 3, batch).await;
+
+    // Ответ на публикацию и доставка подписки приходят в любом порядке
+    let mut delivered = Vec::new();
+    let mut published = None;
+    while delivered.len() < 2 || published.is_none() {
+        let frame = next_frame(&mut client).await;
+        let mut body = BodyReader::new(frame.body);
+        match frame.opcode {
+            opcode::DELIVER => {
+                assert_eq!(body.string().unwrap(), "orders");
+                delivered.push(body.message().unwrap());
+            }
+            opcode::PUBLISHED_BATCH => {
+                assert_eq!(frame.request_id, 3);
+                assert_eq!(body.u32().unwrap(), 2);
+                let id = body.string().unwrap();
+                assert_eq!(body.u64().unwrap(), 0);
+                published = Some(id);
+            }
+            other => panic!("неожиданный кадр {:#04x}", other),
+        }
+    }
+    assert_eq!(delivered[0].payload.as_deref(), Some("a"));
+    assert_eq!(delivered[0].key.as_deref(), Some("k"));
+    assert_eq!(delivered[1].offset, 1);
+    assert_eq!(Some(delivered[0].id.clone()), published);
+
+    let ack = BodyWriter::default()
+        .string("orders")
+        .string(&delivered[0].id);
+    request(&mut client, opcode::ACK, 4, ack).await;
+    assert_eq!(next_frame(&mut client).await.opcode, opcode::OK);
+
+    let fetch = BodyWriter::default()
+        .string("orders")
+        .u8(0)
+        .u64(1)
+        .u32(10)
+        .u32(0);
+    request(&mut client, opcode::FETCH, 5, fetch).await;
+    let reply = next_frame(&mut client).await;
+    assert_eq!(reply.opcode, opcode::MESSAGES);
+    let mut body = BodyReader::new(reply.body);
+    assert_eq!(body.u32().unwrap(), 1);
+    assert_eq!(body.message().unwrap().payload.as_deref(), Some("b"));
+
+    request(&mut client, opcode::HEARTBEAT, 6, BodyWriter::default()).await;
+    assert_eq!(
+        next_frame(&mut client).await,
+        Frame::new(opcode::HEARTBEAT, 6, Bytes::new())
+    );
+    request(&mut client, 0x42, 7, BodyWriter::default()).await;
+    assert_eq!(next_frame(&mut client).await.opcode, opcode::ERROR);
+}
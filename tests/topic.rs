@@ -0,0 +1,956 @@
+use actix::prelude::*;
+use futures::{channel::mpsc, StreamExt};
+use mem_broker::buffer::{BufferStream, SubscriberBuffer};
+use mem_broker::message::Message;
+use mem_broker::storage::MemoryBudget;
+use mem_broker::topic::{
+    Acknowledge, AlterConfig, DeleteTopic, DeliverMessage, Describe, Fetch, FetchFrom, GetMessage,
+    KeylessPolicy, Nack, NackAction, OverflowPolicy, PublishMessage, SlowConsumerPolicy,
+    StartPosition, Subscribe, Topic, TopicConfig, Unsubscribe, Watch, DISCONNECT_CHECK_INTERVAL,
+    HEADER_DELIVERY_ATTEMPTS, HEADER_FAILURE_REASON, HEADER_ORIGINAL_TOPIC,
+};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Подписчик для тестов, пересылает полученные сообщения в канал
+struct Collector {
+    tx: mpsc::UnboundedSender<Message>,
+}
+
+impl Actor for Collector {
+    type Context = Context<Self>;
+}
+
+impl Handler<DeliverMessage> for Collector {
+    type Result = ();
+
+    // Как и сессия клиента, останавливается, когда приемник закрыт
+    fn handle(&mut self, msg: DeliverMessage, ctx: &mut Context<Self>) -> Self::Result {
+        if self.tx.unbounded_send(msg.message).is_err() {
+            ctx.stop();
+        }
+    }
+}
+
+async fn subscribe(
+    topic: &Addr<Topic>,
+    client_id: &str,
+    from: StartPosition,
+    group: Option<&str>,
+) -> mpsc::UnboundedReceiver<Message> {
+    let (tx, rx) = mpsc::unbounded();
+    topic
+        .send(Subscribe {
+            client_id: client_id.into(),
+            addr: Collector { tx }.start().recipient(),
+            from,
+            group: group.map(String::from),
+            buffer: None,
+            durable: false,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    rx
+}
+
+// Остановка подписчика, как при отключении клиента
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Stop;
+
+impl Handler<Stop> for Collector {
+    type Result = ();
+
+    fn handle(&mut self, _msg: Stop, ctx: &mut Context<Self>) -> Self::Result {
+        ctx.stop();
+    }
+}
+
+async fn subscribe_durable(
+    topic: &Addr<Topic>,
+    name: &str,
+) -> (Addr<Collector>, mpsc::UnboundedReceiver<Message>) {
+    let (tx, rx) = mpsc::unbounded();
+    let session = Collector { tx }.start();
+    topic
+        .send(Subscribe {
+            client_id: name.into(),
+            addr: session.clone().recipient(),
+            from: StartPosition::Latest,
+            group: None,
+            buffer: None,
+            durable: true,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    (session, rx)
+}
+
+// Отключение клиента: сессия останавливается, топик замечает это при следующей доставке
+async fn disconnect(session: Addr<Collector>) {
+    session.send(Stop).await.unwrap();
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+}
+
+async fn next_message(rx: &mut mpsc::UnboundedReceiver<Message>) -> Message {
+    actix_web::rt::time::timeout(Duration::from_secs(1), rx.next())
+        .await
+        .expect("сообщение не пришло")
+        .expect("канал закрыт")
+}
+
+async fn next_payload(rx: &mut mpsc::UnboundedReceiver<Message>) -> String {
+    next_message(rx).await.payload.unwrap_or_default()
+}
+
+// Проверка, что за короткое время подписчик ничего не получил
+async fn assert_empty(rx: &mut mpsc::UnboundedReceiver<Message>) {
+    let received = actix_web::rt::time::timeout(Duration::from_millis(100), rx.next()).await;
+    assert!(received.is_err(), "получено лишнее сообщение");
+}
+
+async fn publish_message(topic: &Addr<Topic>, mut message: Message) -> Message {
+    message.offset = topic
+        .send(PublishMessage(message.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    message
+}
+
+async fn publish(topic: &Addr<Topic>, payload: &str, key: Option<&str>) -> Message {
+    publish_message(
+        topic,
+        Message::new(payload.into(), key.map(String::from), false),
+    )
+    .await
+}
+
+#[actix_web::test]
+async fn replays_retained_messages_before_live_delivery() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    publish(&topic, "first", None).await;
+    let second = publish(&topic, "second", None).await;
+    publish(&topic, "third", None).await;
+
+    let mut earliest = subscribe(&topic, "earliest", StartPosition::Earliest, None).await;
+    let mut after = subscribe(
+        &topic,
+        "after",
+        StartPosition::AfterMessage(second.id.clone()),
+        None,
+    )
+    .await;
+
+    publish(&topic, "live", None).await;
+
+    for expected in ["first", "second", "third", "live"] {
+        assert_eq!(next_payload(&mut earliest).await, expected);
+    }
+    for expected in ["third", "live"] {
+        assert_eq!(next_payload(&mut after).await, expected);
+    }
+}
+
+#[actix_web::test]
+async fn replays_compacted_topic_by_key() {
+    let topic = Topic::new(
+        "topic".into(),
+        TopicConfig {
+            compaction: true,
+            ..Default::default()
+        },
+    )
+    .start();
+    publish(&topic, "a1", Some("a")).await;
+    publish(&topic, "b1", Some("b")).await;
+    publish(&topic, "a2", Some("a")).await;
+
+    let mut rx = subscribe(&topic, "client", StartPosition::Earliest, None).await;
+
+    assert_eq!(next_payload(&mut rx).await, "b1");
+    assert_eq!(next_payload(&mut rx).await, "a2");
+}
+
+#[actix_web::test]
+async fn compacted_topic_handles_tombstones_and_keyless_messages() {
+    let config = TopicConfig {
+        compaction: true,
+        ..Default::default()
+    };
+    let topic = Topic::new("topic".into(), config.clone()).start();
+    let keyless = topic
+        .send(PublishMessage(Message::new("x".into(), None, false)))
+        .await
+        .unwrap();
+    assert!(keyless.is_err());
+
+    publish(&topic, "a1", Some("a")).await;
+    publish(&topic, "b1", Some("b")).await;
+    publish_message(&topic, Message::with_payload(None, Some("a".into()), false)).await;
+
+    // Подписчик видит tombstone, чтобы удалить ключ у себя
+    let mut rx = subscribe(&topic, "c1", StartPosition::Earliest, None).await;
+    assert_eq!(next_payload(&mut rx).await, "b1");
+    let tombstone = next_message(&mut rx).await;
+    assert!(tombstone.is_tombstone());
+    assert_empty(&mut rx).await;
+
+    // Сообщения без ключа можно разрешить
+    let keep_keyless = TopicConfig {
+        keyless: KeylessPolicy::Keep,
+        ..config
+    };
+    topic
+        .send(AlterConfig {
+            config: keep_keyless.clone(),
+            dead_letter: None,
+        })
+        .await
+        .unwrap();
+    publish(&topic, "x", None).await;
+
+    // С нулевым сроком tombstone удаляется первой же компакцией
+    actix_web::rt::time::sleep(Duration::from_millis(5)).await;
+    topic
+        .send(AlterConfig {
+            config: TopicConfig {
+                tombstone_retention_ms: 0,
+                ..keep_keyless
+            },
+            dead_letter: None,
+        })
+        .await
+        .unwrap();
+    let mut rx = subscribe(&topic, "c2", StartPosition::Earliest, None).await;
+    assert_eq!(next_payload(&mut rx).await, "b1");
+    assert_eq!(next_payload(&mut rx).await, "x");
+    assert_empty(&mut rx).await;
+}
+
+#[actix_web::test]
+async fn watch_streams_current_values_then_changes() {
+    let topic = Topic::new(
+        "topic".into(),
+        TopicConfig {
+            compaction: true,
+            ..Default::default()
+        },
+    )
+    .start();
+    publish(&topic, "1", Some("app/a")).await;
+    publish(&topic, "2", Some("db/url")).await;
+    publish(&topic, "3", Some("other")).await;
+
+    let (tx, mut rx) = mpsc::unbounded();
+    topic
+        .send(Watch {
+            client_id: "w".into(),
+            addr: Collector { tx }.start().recipient(),
+            keys: ["db/url".to_string()].into(),
+            prefix: Some("app/".into()),
+            buffer: None,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(next_payload(&mut rx).await, "1");
+    assert_eq!(next_payload(&mut rx).await, "2");
+
+    publish(&topic, "4", Some("other")).await;
+    publish(&topic, "5", Some("app/b")).await;
+    publish_message(
+        &topic,
+        Message::with_payload(None, Some("db/url".into()), false),
+    )
+    .await;
+    assert_eq!(next_payload(&mut rx).await, "5");
+    let deleted = next_message(&mut rx).await;
+    assert_eq!(deleted.key.as_deref(), Some("db/url"));
+    assert!(deleted.is_tombstone());
+    assert_empty(&mut rx).await;
+}
+
+#[actix_web::test]
+async fn assigns_increasing_offsets_and_seeks_by_them() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    for (expected, payload) in ["a", "b", "c"].into_iter().enumerate() {
+        assert_eq!(publish(&topic, payload, None).await.offset, expected as u64);
+    }
+
+    let found = topic.send(GetMessage { offset: 1 }).await.unwrap();
+    assert_eq!(found.and_then(|m| m.payload), Some("b".to_string()));
+    assert!(topic
+        .send(GetMessage { offset: 7 })
+        .await
+        .unwrap()
+        .is_none());
+
+    let mut rx = subscribe(&topic, "client", StartPosition::Offset(2), None).await;
+    assert_eq!(next_payload(&mut rx).await, "c");
+}
+
+#[actix_web::test]
+async fn consumer_group_members_share_the_stream() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    let mut first = subscribe(&topic, "w1", StartPosition::Latest, Some("workers")).await;
+    let mut second = subscribe(&topic, "w2", StartPosition::Latest, Some("workers")).await;
+    let mut audit = subscribe(&topic, "audit", StartPosition::Latest, Some("audit")).await;
+    let mut plain = subscribe(&topic, "plain", StartPosition::Latest, None).await;
+
+    for payload in ["1", "2", "3", "4"] {
+        publish(&topic, payload, None).await;
+    }
+
+    // Участники группы получают сообщения по кругу
+    for expected in ["1", "3"] {
+        assert_eq!(next_payload(&mut first).await, expected);
+    }
+    for expected in ["2", "4"] {
+        assert_eq!(next_payload(&mut second).await, expected);
+    }
+    assert_empty(&mut first).await;
+    assert_empty(&mut second).await;
+
+    // Другая группа и подписчик без группы получают весь поток
+    for expected in ["1", "2", "3", "4"] {
+        assert_eq!(next_payload(&mut audit).await, expected);
+        assert_eq!(next_payload(&mut plain).await, expected);
+    }
+}
+
+#[actix_web::test]
+async fn group_resumes_from_its_position_after_reconnect() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    let mut first = subscribe(&topic, "w1", StartPosition::Latest, Some("workers")).await;
+    publish(&topic, "1", None).await;
+    assert_eq!(next_payload(&mut first).await, "1");
+
+    topic
+        .send(Unsubscribe {
+            client_id: "w1".into(),
+        })
+        .await
+        .unwrap();
+    publish(&topic, "2", None).await;
+    publish(&topic, "3", None).await;
+
+    // Группа продолжает с того места, где остановилась
+    let mut second = subscribe(&topic, "w2", StartPosition::Latest, Some("workers")).await;
+    assert_eq!(next_payload(&mut second).await, "2");
+    assert_eq!(next_payload(&mut second).await, "3");
+}
+
+#[actix_web::test]
+async fn group_replay_from_saved_position_waits_for_acknowledgement() {
+    let config = TopicConfig {
+        ack_timeout_ms: 50,
+        ..Default::default()
+    };
+    let topic = Topic::new("topic".into(), config).start();
+    let mut first = subscribe(&topic, "w1", StartPosition::Latest, Some("workers")).await;
+    publish(&topic, "1", None).await;
+    assert_eq!(next_payload(&mut first).await, "1");
+    topic
+        .send(Unsubscribe {
+            client_id: "w1".into(),
+        })
+        .await
+        .unwrap();
+    let message = publish_message(&topic, Message::new("2".into(), None, true)).await;
+
+    // Сообщение, дочитанное с позиции группы, без подтверждения доставляется повторно
+    let mut second = subscribe(&topic, "w2", StartPosition::Latest, Some("workers")).await;
+    assert_eq!(next_message(&mut second).await.id, message.id);
+    assert_eq!(next_message(&mut second).await.id, message.id);
+    topic
+        .send(Acknowledge {
+            client_id: "w2".into(),
+            message_id: message.id,
+        })
+        .await
+        .unwrap();
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.pending_acks, 0);
+}
+
+#[actix_web::test]
+async fn leaving_member_hands_over_unacked_messages() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    let mut first = subscribe(&topic, "w1", StartPosition::Latest, Some("workers")).await;
+    let mut second = subscribe(&topic, "w2", StartPosition::Latest, Some("workers")).await;
+
+    let message = publish_message(&topic, Message::new("job".into(), None, true)).await;
+    assert_eq!(next_payload(&mut first).await, "job");
+
+    topic
+        .send(Unsubscribe {
+            client_id: "w1".into(),
+        })
+        .await
+        .unwrap();
+
+    // Неподтвержденное сообщение переходит к оставшемуся участнику
+    let redelivered = next_message(&mut second).await;
+    assert_eq!(redelivered.id, message.id);
+    topic
+        .send(Acknowledge {
+            client_id: "w2".into(),
+            message_id: message.id,
+        })
+        .await
+        .unwrap();
+}
+
+#[actix_web::test]
+async fn redelivers_with_backoff_then_dead_letters() {
+    let dead_letter = Topic::new("dlq".into(), TopicConfig::default()).start();
+    let mut dead = subscribe(&dead_letter, "dlq", StartPosition::Latest, None).await;
+    let config = TopicConfig {
+        ack_timeout_ms: 50,
+        max_delivery_attempts: 3,
+        backoff_multiplier: 2.0,
+        ..Default::default()
+    };
+    let topic = Topic::new("jobs".into(), config)
+        .with_dead_letter(dead_letter)
+        .start();
+    let mut rx = subscribe(&topic, "client", StartPosition::Latest, None).await;
+
+    let message = publish_message(&topic, Message::new("job".into(), None, true)).await;
+    // Первая доставка и две повторные
+    for _ in 0..3 {
+        assert_eq!(next_message(&mut rx).await.id, message.id);
+    }
+
+    let dead_message = next_message(&mut dead).await;
+    assert_eq!(dead_message.payload.as_deref(), Some("job"));
+    assert_eq!(dead_message.headers[HEADER_ORIGINAL_TOPIC], "jobs");
+    assert_eq!(dead_message.headers[HEADER_DELIVERY_ATTEMPTS], "3");
+    assert_eq!(dead_message.headers[HEADER_FAILURE_REASON], "ack timeout");
+    assert_empty(&mut rx).await;
+}
+
+#[actix_web::test]
+async fn acknowledged_message_is_not_redelivered() {
+    let config = TopicConfig {
+        ack_timeout_ms: 50,
+        ..Default::default()
+    };
+    let topic = Topic::new("jobs".into(), config).start();
+    let mut rx = subscribe(&topic, "client", StartPosition::Latest, None).await;
+
+    let message = publish_message(&topic, Message::new("job".into(), None, true)).await;
+    assert_eq!(next_message(&mut rx).await.id, message.id);
+    topic
+        .send(Acknowledge {
+            client_id: "client".into(),
+            message_id: message.id,
+        })
+        .await
+        .unwrap();
+    assert_empty(&mut rx).await;
+}
+
+async fn nack(topic: &Addr<Topic>, message_id: &str, action: NackAction) {
+    topic
+        .send(Nack {
+            client_id: "client".into(),
+            message_id: message_id.into(),
+            action,
+            reason: Some("broken".into()),
+        })
+        .await
+        .unwrap();
+}
+
+#[actix_web::test]
+async fn nack_requeues_delays_and_rejects() {
+    let dead_letter = Topic::new("dlq".into(), TopicConfig::default()).start();
+    let mut dead = subscribe(&dead_letter, "dlq", StartPosition::Latest, None).await;
+    let topic = Topic::new("jobs".into(), TopicConfig::default())
+        .with_dead_letter(dead_letter)
+        .start();
+    let mut rx = subscribe(&topic, "client", StartPosition::Latest, None).await;
+
+    let message = publish_message(&topic, Message::new("job".into(), None, true)).await;
+    assert_eq!(next_message(&mut rx).await.id, message.id);
+
+    // Немедленная повторная доставка, без ожидания таймаута подтверждения
+    nack(&topic, &message.id, NackAction::Requeue).await;
+    assert_eq!(next_message(&mut rx).await.id, message.id);
+
+    // Отложенная повторная доставка
+    nack(&topic, &message.id, NackAction::Delay { delay_ms: 200 }).await;
+    assert_empty(&mut rx).await;
+    assert_eq!(next_message(&mut rx).await.id, message.id);
+
+    // Отказ - сообщение сразу уходит в dead-letter топик
+    nack(&topic, &message.id, NackAction::Reject).await;
+    let dead_message = next_message(&mut dead).await;
+    assert_eq!(dead_message.id, message.id);
+    assert_eq!(dead_message.headers[HEADER_DELIVERY_ATTEMPTS], "3");
+    assert_eq!(dead_message.headers[HEADER_FAILURE_REASON], "broken");
+}
+
+#[actix_web::test]
+async fn delete_disconnects_subscribers() {
+    let topic = Topic::new("t".into(), TopicConfig::default()).start();
+    let mut rx = subscribe(&topic, "c1", StartPosition::Latest, Some("g")).await;
+    publish(&topic, "a", None).await;
+    assert_eq!(next_payload(&mut rx).await, "a");
+
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.subscriber_count, 1);
+    assert_eq!(description.groups["g"], 1);
+
+    topic.send(DeleteTopic).await.unwrap();
+    // Сессия подписчика останавливается, и его поток закрывается
+    let closed = actix_web::rt::time::timeout(Duration::from_secs(1), rx.next()).await;
+    assert!(closed.expect("поток не закрылся").is_none());
+    assert!(topic.send(Describe).await.is_err());
+}
+
+#[actix_web::test]
+async fn enabling_compaction_collapses_existing_messages() {
+    let topic = Topic::new("t".into(), TopicConfig::default()).start();
+    for (payload, key) in [("a1", "a"), ("b1", "b"), ("a2", "a")] {
+        publish(&topic, payload, Some(key)).await;
+    }
+
+    topic
+        .send(AlterConfig {
+            config: TopicConfig {
+                compaction: true,
+                ..Default::default()
+            },
+            dead_letter: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(topic.send(Describe).await.unwrap().message_count, 2);
+    let mut rx = subscribe(&topic, "c1", StartPosition::Earliest, None).await;
+    assert_eq!(next_payload(&mut rx).await, "b1");
+    assert_eq!(next_payload(&mut rx).await, "a2");
+    assert_empty(&mut rx).await;
+}
+
+fn limited(overflow: OverflowPolicy) -> TopicConfig {
+    TopicConfig {
+        max_messages: Some(2),
+        overflow,
+        block_timeout_ms: 300,
+        ..Default::default()
+    }
+}
+
+#[actix_web::test]
+async fn full_topic_drops_oldest_or_rejects() {
+    let topic = Topic::new("t".into(), limited(OverflowPolicy::DropOldest)).start();
+    for payload in ["a", "b", "c"] {
+        publish(&topic, payload, None).await;
+    }
+    let mut rx = subscribe(&topic, "c1", StartPosition::Earliest, None).await;
+    assert_eq!(next_payload(&mut rx).await, "b");
+    assert_eq!(next_payload(&mut rx).await, "c");
+
+    let topic = Topic::new("t".into(), limited(OverflowPolicy::Reject)).start();
+    for payload in ["a", "b"] {
+        publish(&topic, payload, None).await;
+    }
+    let rejected = topic
+        .send(PublishMessage(Message::new("c".into(), None, false)))
+        .await
+        .unwrap();
+    assert!(rejected.is_err());
+}
+
+#[actix_web::test]
+async fn drop_oldest_keeps_messages_when_budget_is_used_by_others() {
+    let budget = Arc::new(MemoryBudget::new(Some(4)));
+    let drop_oldest = TopicConfig {
+        overflow: OverflowPolicy::DropOldest,
+        ..Default::default()
+    };
+    let own = Topic::new("own".into(), drop_oldest.clone())
+        .with_budget(budget.clone())
+        .start();
+    let other = Topic::new("other".into(), TopicConfig::default())
+        .with_budget(budget.clone())
+        .start();
+    publish(&own, "a", None).await;
+    publish(&other, "bcd", None).await;
+
+    // Удаление своего сообщения не освободит места под два байта
+    let rejected = own
+        .send(PublishMessage(Message::new("ef".into(), None, false)))
+        .await
+        .unwrap();
+    assert!(rejected.is_err());
+    assert_eq!(own.send(Describe).await.unwrap().message_count, 1);
+
+    // А под один байт - освободит
+    publish(&own, "e", None).await;
+    let mut rx = subscribe(&own, "c1", StartPosition::Earliest, None).await;
+    assert_eq!(next_payload(&mut rx).await, "e");
+    assert_empty(&mut rx).await;
+}
+
+#[actix_web::test]
+async fn full_topic_blocks_producer_until_space_frees() {
+    let topic = Topic::new("t".into(), limited(OverflowPolicy::Block)).start();
+    for payload in ["a", "b"] {
+        publish(&topic, payload, None).await;
+    }
+
+    // Без свободного места публикация ждет и по таймауту получает ошибку
+    let timed_out = topic
+        .send(PublishMessage(Message::new("c".into(), None, false)))
+        .await
+        .unwrap();
+    assert!(timed_out.is_err());
+
+    let blocked = topic.send(PublishMessage(Message::new("d".into(), None, false)));
+    let raise_limit = async {
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(topic.send(Describe).await.unwrap().blocked_publishes, 1);
+        topic
+            .send(AlterConfig {
+                config: TopicConfig {
+                    max_messages: Some(3),
+                    ..limited(OverflowPolicy::Block)
+                },
+                dead_letter: None,
+            })
+            .await
+            .unwrap();
+    };
+    let (offset, _) = futures::join!(blocked, raise_limit);
+    assert_eq!(offset.unwrap(), Ok(2));
+}
+
+#[actix_web::test]
+async fn disconnected_member_releases_pending_acks() {
+    let topic = Topic::new("t".into(), TopicConfig::default()).start();
+    let first = subscribe(&topic, "c1", StartPosition::Latest, Some("g")).await;
+    let mut second = subscribe(&topic, "c2", StartPosition::Latest, Some("g")).await;
+
+    // Первый участник отключается, не подтвердив сообщение
+    drop(first);
+    let message = publish_message(&topic, Message::new("job".into(), None, true)).await;
+
+    let received = actix_web::rt::time::timeout(Duration::from_secs(3), second.next())
+        .await
+        .expect("сообщение не передано")
+        .unwrap();
+    assert_eq!(received.id, message.id);
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.subscriber_count, 1);
+    assert_eq!(description.groups["g"], 1);
+}
+
+// Подписчик с ограниченным буфером, как у сессии клиента
+struct Buffered {
+    buffer: Arc<SubscriberBuffer>,
+}
+
+impl Actor for Buffered {
+    type Context = Context<Self>;
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.buffer.finish();
+    }
+}
+
+impl Handler<DeliverMessage> for Buffered {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeliverMessage, _ctx: &mut Context<Self>) -> Self::Result {
+        self.buffer.push(msg);
+    }
+}
+
+// Топик с буфером на два сообщения, подписчик пока ничего не читает
+async fn slow_subscriber(policy: SlowConsumerPolicy) -> (Addr<Topic>, BufferStream) {
+    let topic = Topic::new(
+        "topic".into(),
+        TopicConfig {
+            subscriber_buffer: 2,
+            slow_consumer: policy,
+            ..Default::default()
+        },
+    )
+    .start();
+    let buffer = SubscriberBuffer::new();
+    let stream = buffer.stream();
+    topic
+        .send(Subscribe {
+            client_id: "slow".into(),
+            addr: Buffered {
+                buffer: buffer.clone(),
+            }
+            .start()
+            .recipient(),
+            from: StartPosition::Latest,
+            group: None,
+            buffer: Some(buffer),
+            durable: false,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    for payload in ["1", "2", "3", "4", "5"] {
+        publish(&topic, payload, None).await;
+    }
+    // Даем сессии разложить доставленное по буферу
+    actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    (topic, stream)
+}
+
+async fn read_payloads(stream: &mut BufferStream) -> Vec<String> {
+    let mut payloads = Vec::new();
+    while let Ok(Some(delivery)) =
+        actix_web::rt::time::timeout(Duration::from_millis(300), stream.next()).await
+    {
+        payloads.push(delivery.message.payload.unwrap_or_default());
+    }
+    payloads
+}
+
+#[actix_web::test]
+async fn paused_subscriber_resumes_from_its_position() {
+    let (topic, mut stream) = slow_subscriber(SlowConsumerPolicy::Pause).await;
+    assert_eq!(read_payloads(&mut stream).await, ["1", "2", "3", "4", "5"]);
+
+    publish(&topic, "6", None).await;
+    assert_eq!(read_payloads(&mut stream).await, ["6"]);
+}
+
+#[actix_web::test]
+async fn slow_subscriber_drops_oldest_or_newest() {
+    let (_topic, mut stream) = slow_subscriber(SlowConsumerPolicy::DropOldest).await;
+    assert_eq!(read_payloads(&mut stream).await, ["4", "5"]);
+
+    let (topic, mut stream) = slow_subscriber(SlowConsumerPolicy::DropNewest).await;
+    assert_eq!(read_payloads(&mut stream).await, ["1", "2"]);
+    publish(&topic, "6", None).await;
+    assert_eq!(read_payloads(&mut stream).await, ["6"]);
+}
+
+#[actix_web::test]
+async fn slow_subscriber_is_disconnected() {
+    let (topic, mut stream) = slow_subscriber(SlowConsumerPolicy::Disconnect).await;
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.subscriber_count, 0);
+    // Уже доставленное клиент дочитывает, затем поток завершается
+    assert_eq!(
+        stream.next().await.unwrap().message.payload.as_deref(),
+        Some("1")
+    );
+    assert_eq!(
+        stream.next().await.unwrap().message.payload.as_deref(),
+        Some("2")
+    );
+    assert!(stream.next().await.is_none());
+}
+
+#[actix_web::test]
+async fn redelivery_respects_full_subscriber_buffer() {
+    let topic = Topic::new(
+        "topic".into(),
+        TopicConfig {
+            subscriber_buffer: 2,
+            slow_consumer: SlowConsumerPolicy::DropNewest,
+            ack_timeout_ms: 50,
+            backoff_multiplier: 1.0,
+            max_delivery_attempts: 3,
+            ..Default::default()
+        },
+    )
+    .start();
+    let buffer = SubscriberBuffer::new();
+    let mut stream = buffer.stream();
+    topic
+        .send(Subscribe {
+            client_id: "slow".into(),
+            addr: Buffered {
+                buffer: buffer.clone(),
+            }
+            .start()
+            .recipient(),
+            from: StartPosition::Latest,
+            group: None,
+            buffer: Some(buffer),
+            durable: false,
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    for payload in ["a", "b"] {
+        publish_message(&topic, Message::new(payload.into(), None, true)).await;
+    }
+
+    // Повторные отправки в заполненный буфер не попадают
+    actix_web::rt::time::sleep(Duration::from_millis(250)).await;
+    assert_eq!(read_payloads(&mut stream).await, ["a", "b"]);
+}
+
+#[actix_web::test]
+async fn durable_subscription_resumes_after_reconnect() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    publish(&topic, "before", None).await;
+    let (session, mut rx) = subscribe_durable(&topic, "orders").await;
+    publish_message(&topic, Message::new("unacked".into(), None, true)).await;
+    assert_eq!(next_payload(&mut rx).await, "unacked");
+
+    disconnect(session).await;
+    publish(&topic, "offline", None).await;
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.subscriber_count, 0);
+    assert_eq!(description.pending_acks, 1);
+    let durable = &description.durable_subscriptions["orders"];
+    assert!(!durable.connected);
+    assert_eq!(durable.position, 2);
+
+    // Сначала неподтвержденное, затем пропущенное, позиция from не важна
+    let (_session, mut rx) = subscribe_durable(&topic, "orders").await;
+    assert_eq!(next_payload(&mut rx).await, "unacked");
+    assert_eq!(next_payload(&mut rx).await, "offline");
+    assert_empty(&mut rx).await;
+
+    // Отписка удаляет постоянную подписку вместе с неподтвержденными сообщениями
+    topic
+        .send(Unsubscribe {
+            client_id: "orders".into(),
+        })
+        .await
+        .unwrap();
+    let description = topic.send(Describe).await.unwrap();
+    assert!(description.durable_subscriptions.is_empty());
+    assert_eq!(description.pending_acks, 0);
+}
+
+#[actix_web::test]
+async fn replayed_messages_wait_for_acknowledgement() {
+    let topic = Topic::new("topic".into(), TopicConfig::default()).start();
+    let (session, _rx) = subscribe_durable(&topic, "orders").await;
+    disconnect(session).await;
+    publish_message(&topic, Message::new("missed".into(), None, true)).await;
+
+    // Пропущенное постоянной подпиской ждет подтверждения, как и при живой доставке
+    let (_session, mut rx) = subscribe_durable(&topic, "orders").await;
+    let message = next_message(&mut rx).await;
+    assert_eq!(message.payload.as_deref(), Some("missed"));
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.pending_acks, 1);
+    topic
+        .send(Acknowledge {
+            client_id: "orders".into(),
+            message_id: message.id,
+        })
+        .await
+        .unwrap();
+    let description = topic.send(Describe).await.unwrap();
+    assert_eq!(description.pending_acks, 0);
+}
+
+#[actix_web::test]
+async fn expired_durable_subscription_is_forgotten() {
+    let topic = Topic::new(
+        "topic".into(),
+        TopicConfig {
+            subscription_expiry_ms: 0,
+            ..Default::default()
+        },
+    )
+    .start();
+    let (session, mut rx) = subscribe_durable(&topic, "orders").await;
+    publish_message(&topic, Message::new("unacked".into(), None, true)).await;
+    assert_eq!(next_payload(&mut rx).await, "unacked");
+    disconnect(session).await;
+
+    actix_web::rt::time::sleep(DISCONNECT_CHECK_INTERVAL * 2).await;
+    let description = topic.send(Describe).await.unwrap();
+    assert!(description.durable_subscriptions.is_empty());
+    assert_eq!(description.pending_acks, 0);
+
+    let (_session, mut rx) = subscribe_durable(&topic, "orders").await;
+    assert_empty(&mut rx).await;
+}
+
+fn fetch_group(client_id: &str, wait: Duration) -> Fetch {
+    Fetch {
+        client_id: client_id.into(),
+        from: FetchFrom::Group("batch".into()),
+        max: 10,
+        wait,
+    }
+}
+
+#[actix_web::test]
+async fn unacked_fetch_is_returned_to_the_group() {
+    let topic = Topic::new(
+        "topic".into(),
+        TopicConfig {
+            ack_timeout_ms: 50,
+            ..Default::default()
+        },
+    )
+    .start();
+    let message = publish_message(&topic, Message::new("job".into(), None, true)).await;
+
+    let fetched = topic
+        .send(fetch_group("a", Duration::ZERO))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(fetched.len(), 1);
+    let again = topic
+        .send(fetch_group("b", Duration::ZERO))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(again.is_empty());
+
+    // Клиент a не подтвердил, сообщение достается следующему запросу группы
+    let refetched = topic
+        .send(fetch_group("b", Duration::from_secs(1)))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(refetched[0].id, message.id);
+    topic
+        .send(Acknowledge {
+            client_id: "b".into(),
+            message_id: message.id,
+        })
+        .await
+        .unwrap();
+    assert_eq!(topic.send(Describe).await.unwrap().pending_acks, 0);
+}
+
+#[test]
+fn redelivery_delay_grows_up_to_the_limit() {
+    let config = TopicConfig {
+        ack_timeout_ms: 100,
+        backoff_multiplier: 3.0,
+        max_backoff_ms: 1_000,
+        ..Default::default()
+    };
+    assert_eq!(config.redelivery_delay(1), Duration::from_millis(100));
+    assert_eq!(config.redelivery_delay(2), Duration::from_millis(300));
+    assert_eq!(config.redelivery_delay(3), Duration::from_millis(900));
+    assert_eq!(config.redelivery_delay(4), Duration::from_millis(1_000));
+}
+
+#[test]
+fn parses_start_position() {
+    assert_eq!("earliest".parse(), Ok(StartPosition::Earliest));
+    assert_eq!("latest".parse(), Ok(StartPosition::Latest));
+    assert_eq!("time:42".parse(), Ok(StartPosition::Timestamp(42)));
+    assert_eq!("offset:3".parse(), Ok(StartPosition::Offset(3)));
+    assert_eq!("abc".parse(), Ok(StartPosition::AfterMessage("abc".into())));
+    assert!("time:x".parse::<StartPosition>().is_err());
+}
@@ -0,0 +1,153 @@
+use mem_broker::broker::Broker;
+use mem_broker::message::Message;
+use mem_broker::topic::TopicConfig;
+use mem_broker::wal::{save_topic, FsyncPolicy, TopicLog, WalOptions};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+fn message(offset: u64, payload: &str) -> Message {
+    let mut message = Message::new(payload.into(), None, false);
+    message.offset = offset;
+    message
+}
+
+#[test]
+fn log_survives_reopen_and_drops_torn_tail() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut options = WalOptions::new(dir.path());
+    options.fsync = FsyncPolicy::Always;
+    // Маленький сегмент, чтобы сообщения разошлись по нескольким файлам
+    options.segment_bytes = 64;
+    let topic_dir = options.topic_dir("orders");
+
+    {
+        let (mut log, messages) = TopicLog::open(topic_dir.clone(), &options).unwrap();
+        assert!(messages.is_empty());
+        for offset in 0..5 {
+            log.append(&message(offset, "payload")).unwrap();
+        }
+    }
+
+    // Имитируем запись, оборванную на середине
+    let last_segment = std::fs::read_dir(&topic_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .max()
+        .unwrap();
+    OpenOptions::new()
+        .append(true)
+        .open(last_segment)
+        .unwrap()
+        .write_all(&[42, 0, 0, 0, b'{'])
+        .unwrap();
+
+    let (mut log, messages) = TopicLog::open(topic_dir.clone(), &options).unwrap();
+    let offsets: Vec<u64> = messages.iter().map(|m| m.offset).collect();
+    assert_eq!(offsets, vec![0, 1, 2, 3, 4]);
+    assert_eq!(log.next_offset(), 5);
+
+    // Старые сегменты удаляются целиком, активный остается
+    log.remove_before(5).unwrap();
+    drop(log);
+    let (log, messages) = TopicLog::open(topic_dir, &options).unwrap();
+    assert!(messages.iter().all(|m| m.offset >= 4));
+    assert_eq!(log.next_offset(), 5);
+}
+
+#[actix_web::test]
+async fn broker_restores_topics_from_disk() {
+    let dir = tempfile::tempdir().unwrap();
+
+    {
+        let mut broker = Broker::open(WalOptions::new(dir.path())).unwrap();
+        broker
+            .create_topic("plain".into(), TopicConfig::default())
+            .unwrap();
+        broker
+            .create_topic(
+                "table".into(),
+                TopicConfig {
+                    compaction: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        for payload in ["a", "b"] {
+            broker
+                .publish_message("plain", Message::new(payload.into(), None, false))
+                .await
+                .unwrap();
+        }
+        for (key, payload) in [("k", "v1"), ("k", "v2")] {
+            broker
+                .publish_message(
+                    "table",
+                    Message::new(payload.into(), Some(key.into()), false),
+                )
+                .await
+                .unwrap();
+        }
+    }
+
+    let broker = Broker::open(WalOptions::new(dir.path())).unwrap();
+    let restored = broker.get_message("plain", 1).await.unwrap().unwrap();
+    assert_eq!(restored.payload.as_deref(), Some("b"));
+    // Offset'ы продолжаются с того места, где остановились
+    let offset = broker
+        .publish_message("plain", Message::new("c".into(), None, false))
+        .await
+        .unwrap();
+    assert_eq!(offset, 2);
+
+    // В компактном топике осталось только последнее значение ключа
+    assert!(broker.get_message("table", 0).await.unwrap().is_none());
+    let latest = broker.get_message("table", 1).await.unwrap().unwrap();
+    assert_eq!(latest.payload.as_deref(), Some("v2"));
+}
+
+#[actix_web::test]
+async fn dead_letter_cycle_survives_restart_and_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = WalOptions::new(dir.path());
+    // Топики, которые ссылаются друг на друга, могли остаться на диске
+    // от версий без проверки цепочек dead-letter топиков
+    for (name, dead_letter) in [("a", "b"), ("b", "a")] {
+        let config = TopicConfig {
+            dead_letter_topic: Some(dead_letter.into()),
+            ..Default::default()
+        };
+        save_topic(&options.topic_dir(name), name, &config).unwrap();
+    }
+
+    {
+        let broker = Broker::open(WalOptions::new(dir.path())).unwrap();
+        for name in ["a", "b"] {
+            broker
+                .publish_message(name, Message::new(name.into(), None, false))
+                .await
+                .unwrap();
+        }
+    }
+
+    let broker = Broker::open(WalOptions::new(dir.path())).unwrap();
+    for (name, dead_letter) in [("a", "b"), ("b", "a")] {
+        let description = broker.describe_topic(name).await.unwrap();
+        assert_eq!(
+            description.config.dead_letter_topic.as_deref(),
+            Some(dead_letter)
+        );
+        let message = broker.get_message(name, 0).await.unwrap().unwrap();
+        assert_eq!(message.payload.as_deref(), Some(name));
+    }
+
+    let mut restored = Broker::new();
+    restored
+        .restore_snapshot(broker.snapshot().await.unwrap())
+        .await
+        .unwrap();
+    for name in ["a", "b"] {
+        let message = restored.get_message(name, 0).await.unwrap().unwrap();
+        assert_eq!(message.payload.as_deref(), Some(name));
+    }
+}
@@ -0,0 +1,99 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws;
+use actix_web::{body::MessageBody, http::StatusCode, test, web, App};
+use futures::lock::Mutex;
+use mem_broker::{broker::Broker, client::init_routes};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Кадры клиента, как их отправил бы браузер
+fn client_frames(frames: &[Value]) -> web::Bytes {
+    let mut codec = ws::Codec::new().client_mode();
+    let mut buf = web::BytesMut::new();
+    for frame in frames {
+        codec
+            .encode(ws::Message::Text(frame.to_string().into()), &mut buf)
+            .unwrap();
+    }
+    buf.freeze()
+}
+
+// Читает кадры сервера, пока не придет count текстовых кадров
+async fn read_events(body: actix_web::body::BoxBody, count: usize) -> Vec<Value> {
+    let mut body = Box::pin(body);
+    let mut codec = ws::Codec::new().client_mode();
+    let mut buf = web::BytesMut::new();
+    let mut events = Vec::new();
+    while events.len() < count {
+        if let Some(frame) = codec.decode(&mut buf).unwrap() {
+            if let ws::Frame::Text(text) = frame {
+                events.push(serde_json::from_slice(&text).unwrap());
+            }
+            continue;
+        }
+        let chunk = std::future::poll_fn(|cx| body.as_mut().poll_next(cx));
+        let chunk = actix_web::rt::time::timeout(Duration::from_secs(1), chunk)
+            .await
+            .expect("кадр не пришел")
+            .expect("соединение закрыто")
+            .unwrap();
+        buf.extend_from_slice(&chunk);
+    }
+    events
+}
+
+#[actix_web::test]
+async fn websocket_handles_requests_and_delivers_messages() {
+    let broker = web::Data::new(Arc::new(Mutex::new(Broker::new())));
+    let app = test::init_service(App::new().app_data(broker).configure(init_routes)).await;
+    let req = test::TestRequest::post()
+        .uri("/create_topic")
+        .set_json(json!({"name": "orders"}))
+        .to_request();
+    assert!(test::call_service(&app, req).await.status().is_success());
+
+    let payload = client_frames(&[
+        json!({"type": "subscribe", "request_id": "1", "topic": "orders"}),
+        json!({"type": "publish", "request_id": "2", "topic": "orders", "payload": "hi"}),
+        json!({"type": "nack", "request_id": "3", "topic": "orders", "message_id": "none",
+               "action": "delay", "delay_ms": 10}),
+        json!({"type": "subscribe", "request_id": "4", "topic": "missing"}),
+        json!({"type": "ack", "request_id": "5", "topic": "orders", "message_id": "none"}),
+        json!("не кадр"),
+    ]);
+    let req = test::TestRequest::get()
+        .uri("/ws?client_id=socket")
+        .insert_header(("upgrade", "websocket"))
+        .insert_header(("connection", "upgrade"))
+        .insert_header(("sec-websocket-version", "13"))
+        .insert_header(("sec-websocket-key", "dGhlIHNhbXBsZSBub25jZQ=="))
+        .set_payload(payload)
+        .to_request();
+    let resp = test::call_service(&app, req).await;
+    assert_eq!(resp.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    let events = read_events(resp.into_body(), 8).await;
+    let find = |kind: &str, request_id: Option<&str>| {
+        events
+            .iter()
+            .find(|e| e["type"] == kind && e["request_id"].as_str() == request_id)
+            .unwrap_or_else(|| panic!("нет кадра {} {:?}: {:?}", kind, request_id, events))
+    };
+    assert_eq!(
+        events[0],
+        json!({"type": "connected", "client_id": "socket"})
+    );
+    find("ok", Some("1"));
+    let published = find("published", Some("2"));
+    assert_eq!(published["offset"], 0);
+    find("ok", Some("3"));
+    find("error", Some("4"));
+    find("ok", Some("5"));
+    find("error", None);
+
+    let message = events.iter().find(|e| e["type"] == "message").unwrap();
+    assert_eq!(message["topic"], "orders");
+    assert_eq!(message["message"]["payload"], "hi");
+    assert_eq!(message["message"]["id"], published["id"]);
+}